@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS book (
+    id UUID PRIMARY KEY,
+    name TEXT,
+    author TEXT,
+    year INTEGER
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS review (
+    id UUID PRIMARY KEY,
+    book_id UUID NOT NULL REFERENCES book (id) ON DELETE CASCADE,
+    rating INTEGER NOT NULL CHECK (rating BETWEEN 1 AND 5),
+    text TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS review_book_id_idx ON review (book_id);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS idempotency_key (
+    scope TEXT NOT NULL,
+    key TEXT NOT NULL,
+    book_id UUID NOT NULL REFERENCES book (id) ON DELETE CASCADE,
+    created_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (scope, key)
+);
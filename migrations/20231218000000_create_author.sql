@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS author (
+    id UUID PRIMARY KEY,
+    name TEXT NOT NULL
+);
+
+ALTER TABLE book ADD COLUMN author_id UUID REFERENCES author (id) ON DELETE SET NULL;
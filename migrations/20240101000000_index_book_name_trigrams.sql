@@ -0,0 +1,23 @@
+-- Lets `name_contains` (an ILIKE '%term%') use an index instead of scanning
+-- every book. The extension lives in `public` so schemas that only reach it
+-- through their search_path share one copy.
+--
+-- Creating pg_trgm takes CREATE on the database or a superuser. Without
+-- either, it and its indexes are skipped, `name_contains` scans, and fuzzy
+-- search answers 503 until someone who may runs
+-- `CREATE EXTENSION pg_trgm SCHEMA public`.
+DO $$
+BEGIN
+    CREATE EXTENSION IF NOT EXISTS pg_trgm SCHEMA public;
+EXCEPTION WHEN insufficient_privilege THEN
+    RAISE WARNING 'skipping pg_trgm: %', SQLERRM;
+END
+$$;
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'pg_trgm') THEN
+        CREATE INDEX IF NOT EXISTS book_name_trgm ON book USING gin (name public.gin_trgm_ops);
+    END IF;
+END
+$$;
@@ -0,0 +1,10 @@
+-- Fuzzy search compares authors as well as names; `name` got its trigram
+-- index with `name_contains`. Like that one, this is skipped without
+-- pg_trgm.
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'pg_trgm') THEN
+        CREATE INDEX IF NOT EXISTS book_author_trgm ON book USING gin (author public.gin_trgm_ops);
+    END IF;
+END
+$$;
@@ -0,0 +1,2 @@
+-- Unconstrained NUMERIC keeps every digit a client sends.
+ALTER TABLE book ADD COLUMN price NUMERIC;
@@ -0,0 +1,4 @@
+-- Creates look a book up by its name and author, ignoring case, to refuse
+-- a duplicate. Updates may still give two books the same pair, so this
+-- isn't a unique index.
+CREATE INDEX IF NOT EXISTS book_name_author_idx ON book (lower(name), lower(author));
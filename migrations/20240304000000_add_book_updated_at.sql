@@ -0,0 +1,4 @@
+-- Set by every write, so a book's Last-Modified is read with it. Books
+-- written before are dated by their last audit log entry.
+ALTER TABLE book ADD COLUMN updated_at TIMESTAMPTZ;
+UPDATE book SET updated_at = (SELECT MAX(created_at) FROM audit_log WHERE audit_log.book_id = book.id);
@@ -0,0 +1,14 @@
+-- Reviews, idempotency keys and author links belong to a book in whichever
+-- table TABLE_NAME names, so none of them can reference `book`; the
+-- repository checks the book exists and deletes them with it instead.
+ALTER TABLE review DROP CONSTRAINT IF EXISTS review_book_id_fkey;
+ALTER TABLE idempotency_key DROP CONSTRAINT IF EXISTS idempotency_key_book_id_fkey;
+
+CREATE TABLE IF NOT EXISTS book_author (
+    book_id UUID PRIMARY KEY,
+    author_id UUID NOT NULL REFERENCES author (id) ON DELETE CASCADE
+);
+
+INSERT INTO book_author (book_id, author_id)
+SELECT id, author_id FROM book WHERE author_id IS NOT NULL;
+ALTER TABLE book DROP COLUMN author_id;
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS book (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    name TEXT,
+    author TEXT,
+    year INTEGER
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS review (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    book_id CHAR(36) NOT NULL,
+    rating INTEGER NOT NULL CHECK (rating BETWEEN 1 AND 5),
+    text TEXT,
+    created_at TIMESTAMP(6) NOT NULL,
+    INDEX review_book_id_idx (book_id),
+    FOREIGN KEY (book_id) REFERENCES book (id) ON DELETE CASCADE
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS idempotency_key (
+    scope VARCHAR(64) NOT NULL,
+    `key` VARCHAR(255) NOT NULL,
+    book_id CHAR(36) NOT NULL,
+    created_at TIMESTAMP(6) NOT NULL,
+    PRIMARY KEY (scope, `key`),
+    FOREIGN KEY (book_id) REFERENCES book (id) ON DELETE CASCADE
+);
@@ -0,0 +1,7 @@
+-- Keys recorded before this only pointed at a book, which can't be replayed
+-- faithfully, so they are dropped rather than backfilled.
+DELETE FROM idempotency_key;
+ALTER TABLE idempotency_key
+    ADD COLUMN request_hash VARCHAR(64) NOT NULL DEFAULT '',
+    ADD COLUMN response_body TEXT NOT NULL;
+CREATE INDEX idempotency_key_created_at ON idempotency_key (created_at);
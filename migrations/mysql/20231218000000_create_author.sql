@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS author (
+    id CHAR(36) PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL
+);
+
+ALTER TABLE book
+    ADD COLUMN author_id CHAR(36),
+    ADD FOREIGN KEY (author_id) REFERENCES author (id) ON DELETE SET NULL;
@@ -0,0 +1,2 @@
+-- DECIMAL needs a scale; four places cover every currency's minor unit.
+ALTER TABLE book ADD COLUMN price DECIMAL(19, 4);
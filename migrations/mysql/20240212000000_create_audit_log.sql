@@ -0,0 +1,10 @@
+-- No foreign key to book: the log outlives the books it describes.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id BIGINT AUTO_INCREMENT PRIMARY KEY,
+    book_id CHAR(36) NOT NULL,
+    action VARCHAR(16) NOT NULL CHECK (action IN ('create', 'update', 'delete')),
+    old_value TEXT,
+    new_value TEXT,
+    created_at TIMESTAMP(6) NOT NULL,
+    INDEX audit_log_book_id_idx (book_id, id)
+);
@@ -0,0 +1,8 @@
+-- `events` is a comma-separated list, such as `book.created,book.deleted`.
+CREATE TABLE IF NOT EXISTS webhook_subscription (
+    id CHAR(36) PRIMARY KEY,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    events TEXT NOT NULL,
+    created_at TIMESTAMP(6) NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Set by every write, so a book's Last-Modified is read with it. Books
+-- written before are dated by their last audit log entry. NULL is spelled
+-- out, or an older server would stamp the first TIMESTAMP column itself.
+ALTER TABLE book ADD COLUMN updated_at TIMESTAMP(6) NULL;
+UPDATE book SET updated_at = (SELECT MAX(created_at) FROM audit_log WHERE audit_log.book_id = book.id);
@@ -0,0 +1,16 @@
+-- Reviews, idempotency keys and author links belong to a book in whichever
+-- table TABLE_NAME names, so none of them can reference `book`; the
+-- repository checks the book exists and deletes them with it instead. The
+-- foreign keys were unnamed, so these are the names MySQL gave them.
+ALTER TABLE review DROP FOREIGN KEY review_ibfk_1;
+ALTER TABLE idempotency_key DROP FOREIGN KEY idempotency_key_ibfk_1;
+
+CREATE TABLE IF NOT EXISTS book_author (
+    book_id CHAR(36) PRIMARY KEY NOT NULL,
+    author_id CHAR(36) NOT NULL,
+    FOREIGN KEY (author_id) REFERENCES author (id) ON DELETE CASCADE
+);
+
+INSERT INTO book_author (book_id, author_id)
+SELECT id, author_id FROM book WHERE author_id IS NOT NULL;
+ALTER TABLE book DROP FOREIGN KEY book_ibfk_1, DROP COLUMN author_id;
@@ -0,0 +1,10 @@
+-- Creates look a book up by its name and author, ignoring case, to refuse
+-- a duplicate. MySQL can't index LOWER() of a TEXT column directly, so the
+-- lower-cased pair is stored alongside and indexed by prefix, long enough
+-- to tell apart all but the longest names. Updates may still give two
+-- books the same pair, so this isn't a unique index. A table named by
+-- TABLE_NAME needs these columns too.
+ALTER TABLE book
+    ADD COLUMN name_lower TEXT AS (LOWER(name)) STORED,
+    ADD COLUMN author_lower TEXT AS (LOWER(author)) STORED,
+    ADD INDEX book_name_author_idx (name_lower(191), author_lower(191));
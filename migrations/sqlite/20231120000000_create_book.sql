@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS book (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT,
+    author TEXT,
+    year INTEGER
+);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS author (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT NOT NULL
+);
+
+ALTER TABLE book ADD COLUMN author_id TEXT REFERENCES author (id) ON DELETE SET NULL;
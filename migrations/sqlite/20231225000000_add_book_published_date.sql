@@ -0,0 +1,3 @@
+-- SQLite has no date type; dates are stored as ISO 8601 text, which
+-- compares in date order.
+ALTER TABLE book ADD COLUMN published_date TEXT;
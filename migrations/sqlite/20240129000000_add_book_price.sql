@@ -0,0 +1,3 @@
+-- SQLite has no decimal type; prices are stored as their exact decimal
+-- text and only cast to REAL for comparisons.
+ALTER TABLE book ADD COLUMN price TEXT;
@@ -0,0 +1,11 @@
+-- No foreign key to book: the log outlives the books it describes.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    book_id TEXT NOT NULL,
+    action TEXT NOT NULL CHECK (action IN ('create', 'update', 'delete')),
+    old_value TEXT,
+    new_value TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_book_id_idx ON audit_log (book_id, id);
@@ -0,0 +1,56 @@
+-- Reviews, idempotency keys and author links belong to a book in whichever
+-- table TABLE_NAME names, so none of them can reference `book`; the
+-- repository checks the book exists and deletes them with it instead.
+-- SQLite can't drop a foreign key or a column one uses, so the tables are
+-- rebuilt without them.
+CREATE TABLE review_new (
+    id TEXT PRIMARY KEY NOT NULL,
+    book_id TEXT NOT NULL,
+    rating INTEGER NOT NULL CHECK (rating BETWEEN 1 AND 5),
+    text TEXT,
+    created_at TEXT NOT NULL
+);
+INSERT INTO review_new SELECT id, book_id, rating, text, created_at FROM review;
+DROP TABLE review;
+ALTER TABLE review_new RENAME TO review;
+CREATE INDEX IF NOT EXISTS review_book_id_idx ON review (book_id);
+
+CREATE TABLE idempotency_key_new (
+    scope TEXT NOT NULL,
+    key TEXT NOT NULL,
+    book_id TEXT NOT NULL,
+    created_at TEXT NOT NULL,
+    request_hash TEXT NOT NULL DEFAULT '',
+    response_body TEXT NOT NULL DEFAULT '',
+    PRIMARY KEY (scope, key)
+);
+INSERT INTO idempotency_key_new
+SELECT scope, key, book_id, created_at, request_hash, response_body FROM idempotency_key;
+DROP TABLE idempotency_key;
+ALTER TABLE idempotency_key_new RENAME TO idempotency_key;
+CREATE INDEX IF NOT EXISTS idempotency_key_created_at ON idempotency_key (created_at);
+
+CREATE TABLE IF NOT EXISTS book_author (
+    book_id TEXT PRIMARY KEY NOT NULL,
+    author_id TEXT NOT NULL REFERENCES author (id) ON DELETE CASCADE
+);
+INSERT INTO book_author (book_id, author_id)
+SELECT id, author_id FROM book WHERE author_id IS NOT NULL;
+
+CREATE TABLE book_new (
+    id TEXT PRIMARY KEY NOT NULL,
+    name TEXT,
+    author TEXT,
+    year INTEGER,
+    published_date TEXT,
+    publisher TEXT,
+    language TEXT,
+    price TEXT,
+    stock INTEGER,
+    updated_at TEXT
+);
+INSERT INTO book_new
+SELECT id, name, author, year, published_date, publisher, language, price, stock, updated_at FROM book;
+DROP TABLE book;
+ALTER TABLE book_new RENAME TO book;
+CREATE INDEX IF NOT EXISTS book_name_author_idx ON book (lower(name), lower(author));
@@ -0,0 +1,68 @@
+//! The guard on the `/admin` routes: the bearer token in `admin_token`.
+
+use tide::{Middleware, Next, Request};
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// Lets a request through only with `Authorization: Bearer <token>`. With
+/// no token configured the routes it guards are off altogether, rather
+/// than open.
+#[derive(Clone, Debug)]
+pub struct AdminToken {
+    token: Option<String>
+}
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        AdminToken { token: token.filter(|token| !token.is_empty()) }
+    }
+
+    /// The token in `admin_token`, if it's set.
+    pub fn from_config(config: &Config) -> Self {
+        AdminToken::new(config.admin_token.clone())
+    }
+
+    fn allows(&self, authorization: Option<&str>) -> Result<(), AppError> {
+        let token = self.token.as_deref().ok_or_else(|| {
+            AppError::Forbidden(String::from("the admin routes are off; set ADMIN_TOKEN to turn them on"))
+        })?;
+        let given = authorization
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, given)| given.trim());
+        match given {
+            Some(given) if same(given.as_bytes(), token.as_bytes()) => Ok(()),
+            _ => Err(AppError::Unauthorized(String::from("an admin bearer token is required"))),
+        }
+    }
+}
+
+/// Compares `a` and `b` in a time that doesn't depend on where they differ,
+/// so the token can't be guessed a byte at a time.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for AdminToken {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let authorization = req.header("Authorization").map(|values| values.last().as_str().to_owned());
+        match self.allows(authorization.as_deref()) {
+            Ok(()) => Ok(next.run(req).await),
+            Err(e) => Ok(e.into_response()),
+        }
+    }
+}
+
+#[test]
+fn only_the_configured_token_is_let_through() {
+    let guard = AdminToken::new(Some(String::from("s3cret")));
+    assert!(guard.allows(Some("Bearer s3cret")).is_ok());
+    assert!(guard.allows(Some("bearer s3cret")).is_ok());
+    for authorization in [None, Some("Bearer"), Some("Bearer s3cre"), Some("Bearer s3cret!"), Some("Basic s3cret")] {
+        assert!(matches!(guard.allows(authorization), Err(AppError::Unauthorized(_))), "{:?}", authorization);
+    }
+    let off = AdminToken::new(Some(String::new()));
+    assert!(matches!(off.allows(Some("Bearer ")), Err(AppError::Forbidden(_))));
+}
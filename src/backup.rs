@@ -0,0 +1,759 @@
+//! `GET /books/export` and `POST /books/restore`: the book table, or one
+//! author's part of it, as one JSON file, and back. `GET /admin/export` and `POST /admin/import`
+//! are the same for moving the books between environments, with the file
+//! saying what it is.
+//! `POST /books/import` loads books from a CSV file instead, as
+//! spreadsheets write them, and like `/admin/import` takes the admin token.
+
+use std::fmt;
+use std::io;
+
+use async_std::channel::{self, Sender};
+use chrono::{DateTime, Utc};
+use serde::de::{self, DeserializeSeed, Deserializer, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tide::http::mime;
+use tide::{Body, Request, Response};
+use uuid::Uuid;
+
+use crate::{AuditAction, Book, State, fields, streaming, validate_book};
+use crate::config::Config;
+use crate::body::{BodyLimit, BodyReader, REQUEST_MEDIA_TYPES, body_reader_in, read_bytes_in, read_json, unknown_keys};
+use crate::error::{AppError, FieldError};
+use crate::openapi::{book_schema, json_response, problem_response};
+use crate::repository::{BookFilter, IMPORT_BATCH, ImportMode, RepositoryError, import_batches};
+
+/// Used when `restore_max_body_bytes` isn't set: 64 MiB, since a backup
+/// is far bigger than any other request body.
+pub const DEFAULT_RESTORE_MAX_BODY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The body limit of `POST /books/restore`, from `restore_max_body_bytes`,
+/// overriding `max_body_bytes`.
+pub fn restore_limit(config: &Config) -> BodyLimit {
+    BodyLimit::new(config.restore_max_body_bytes)
+}
+
+pub fn export_books_doc() -> Value {
+    json!({
+        "operationId": "export_books",
+        "parameters": [{
+            "name": "author",
+            "in": "query",
+            "required": false,
+            "description": "Only books whose author contains this, ignoring case, as for `GET /books`",
+            "schema": {"type": "string"}
+        }],
+        "responses": {
+            "200": {
+                "description": "Every book, or every one by `author`, ordered by id, as a file for `POST /books/restore`; an empty array when there are none",
+                "headers": {
+                    "Content-Disposition": {
+                        "description": "`attachment; filename=\"books-backup.json\"`",
+                        "schema": {"type": "string"}
+                    }
+                },
+                "content": {"application/json": {"schema": {"type": "array", "items": book_schema()}}}
+            }
+        }
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    author: Option<String>
+}
+
+/// Streams every book, or `?author=`'s, out as a JSON array, written as
+/// the rows are read so the table is never held in memory. A failure
+/// part-way leaves the array unterminated, so a broken backup can't pass
+/// for a whole one.
+pub async fn export_books(req: Request<State>) -> Result<Response, AppError> {
+    let query: ExportQuery = req.query()?;
+    let filter = BookFilter { author: query.author, ..BookFilter::default() };
+    let mut res = Response::new(200);
+    res.insert_header("Content-Disposition", "attachment; filename=\"books-backup.json\"");
+    res.set_body(stream_books(&req, filter, |_| String::new(), "\n"));
+    Ok(res)
+}
+
+/// The version of the `GET /admin/export` document, for an import to
+/// check it can read.
+pub const EXPORT_VERSION: u32 = 1;
+
+pub fn admin_export_doc() -> Value {
+    json!({
+        "operationId": "admin_export",
+        "security": [{"adminToken": []}],
+        "responses": {
+            "200": {
+                "description": "Every book, ordered by id, and what the export was, as a file for `POST /books/restore`",
+                "headers": {
+                    "Content-Disposition": {
+                        "description": "`attachment; filename=\"books-export-YYYY-MM-DD.json\"`, dated as `exported_at`",
+                        "schema": {"type": "string"}
+                    }
+                },
+                "content": {"application/json": {"schema": export_schema()}}
+            },
+            "401": problem_response("No `Authorization: Bearer` token, or not `ADMIN_TOKEN`"),
+            "403": problem_response("`ADMIN_TOKEN` isn't set, so the admin routes are off")
+        }
+    })
+}
+
+fn export_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["export", "books"],
+        "properties": {
+            "books": {"type": "array", "items": book_schema()},
+            "export": {
+                "type": "object",
+                "description": "Written before `books`, and read first by `POST /admin/import`",
+                "required": ["version", "exported_at", "row_count"],
+                "properties": {
+                    "version": {"type": "integer", "enum": [EXPORT_VERSION]},
+                    "exported_at": {"type": "string", "format": "date-time"},
+                    "row_count": {"type": "integer", "format": "int64"}
+                }
+            }
+        }
+    })
+}
+
+/// What `GET /admin/export` says about itself in its `export` object.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExportInfo {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    pub row_count: u64
+}
+
+/// `GET /books/export` as a document that describes itself:
+/// `{"export": {...}, "books": [...]}`. The `export` object comes first,
+/// so an import can check it before writing anything; its `row_count` is
+/// counted in the snapshot the books are read from, so it's what the file
+/// holds even with writes going on.
+pub async fn admin_export(req: Request<State>) -> Result<Response, AppError> {
+    let exported_at = Utc::now();
+    let mut res = Response::new(200);
+    let filename = format!("books-export-{}.json", exported_at.format("%Y-%m-%d"));
+    res.insert_header("Content-Disposition", format!("attachment; filename=\"{}\"", filename));
+    res.set_body(stream_books(&req, BookFilter::default(), move |row_count| {
+        let info = ExportInfo { version: EXPORT_VERSION, exported_at, row_count };
+        format!("{{\"export\": {}, \"books\": ", json!(info))
+    }, "}\n"));
+    Ok(res)
+}
+
+/// A body of the books `filter` lets through as a JSON array between what
+/// `head` makes of how many there are and `tail`, written as the rows are
+/// read. A failure part-way, or a count the books don't agree with,
+/// leaves the array, and so the document, unterminated.
+fn stream_books(req: &Request<State>, filter: BookFilter, head: impl FnOnce(u64) -> String + Send + 'static, tail: &'static str) -> Body {
+    let repo = req.state().repo.clone();
+    let (chunks, receiver) = channel::bounded(1);
+    async_std::task::spawn(async move {
+        let (counted, count) = channel::bounded(1);
+        let (books, exported) = channel::bounded::<Book>(64);
+        let export = async_std::task::spawn(async move { repo.export_books(&filter, counted, books).await });
+        let Ok(count) = count.recv().await else {
+            if let Err(e) = export.await {
+                tracing::error!("export failed before its first book: {}", e);
+            }
+            return;
+        };
+        let mut head = head(count);
+        let mut separator = "[\n";
+        while let Ok(book) = exported.recv().await {
+            let json = match serde_json::to_string(&book) {
+                Ok(json) => json,
+                Err(e) => return tracing::error!("could not serialize book {} for the export: {}", book.id, e),
+            };
+            // The client went away; dropping `exported` stops the export.
+            if chunks.send(format!("{}{}{}", std::mem::take(&mut head), separator, json).into_bytes()).await.is_err() {
+                return;
+            }
+            separator = ",\n";
+        }
+        match export.await {
+            Ok(exported) if exported == count => {
+                let end = if separator == "[\n" { "[]" } else { "\n]" };
+                let _ = chunks.send(format!("{}{}{}", head, end, tail).into_bytes()).await;
+            }
+            Ok(exported) => tracing::error!("export sent {} books of the {} counted, leaving the file unterminated", exported, count),
+            Err(e) => tracing::error!("export stopped part-way, leaving the file unterminated: {}", e),
+        }
+    });
+
+    let mut body = streaming::body(receiver);
+    body.set_mime(mime::JSON);
+    body
+}
+
+pub fn restore_books_doc() -> Value {
+    json!({
+        "operationId": "restore_books",
+        "requestBody": {
+            "required": true,
+            "content": {"application/json": {"schema": {
+                "oneOf": [{"type": "array", "items": book_schema()}, export_schema()]
+            }}}
+        },
+        "responses": {
+            "200": json_response("How many books were restored", json!({
+                "type": "object",
+                "required": ["restored"],
+                "properties": {"restored": {"type": "integer", "format": "int64"}}
+            })),
+            "400": problem_response("Malformed body"),
+            "409": problem_response("A book in the file already exists, by id or by name and author; nothing was restored"),
+            "413": problem_response("The file is over `RESTORE_MAX_BODY_BYTES`"),
+            "422": problem_response("An export whose `version` isn't this server's or whose `row_count` isn't how many books it holds, or a book that fails the checks of a create; `errors` names it by index, as in `[3].price`")
+        }
+    })
+}
+
+/// Creates every book of a file `GET /books/export` or `GET /admin/export`
+/// wrote, checked as a create would check them, all in one transaction.
+/// It's meant for an empty table: a book that's already there fails the
+/// whole restore.
+pub async fn restore_books(mut req: Request<State>) -> Result<Response, AppError> {
+    let books = exported_books(read_json(&mut req).await?)?;
+    let books = books.into_iter().enumerate()
+        .map(|(index, book)| validate_book(&format!("[{}].", index), book))
+        .collect::<Result<Vec<_>, _>>()?;
+    let restored = req.state().repo.restore_books(books).await?;
+    req.state().cache.clear();
+    crate::books_changed(&req, restored.iter().map(|book| (AuditAction::Create, book)));
+
+    let mut res = Response::new(200);
+    res.set_body(Body::from_json(&json!({"restored": restored.len()}))?);
+    Ok(res)
+}
+
+/// An `/admin/export` document.
+#[derive(Deserialize)]
+struct Export {
+    books: Vec<Book>,
+    export: ExportInfo
+}
+
+/// The books of an export, either a bare array or an `/admin/export`
+/// document, whose `export` object has to agree with them as for
+/// `admin_import`.
+fn exported_books(body: Value) -> Result<Vec<Book>, AppError> {
+    if body.is_array() {
+        return Ok(serde_json::from_value(body)?);
+    }
+    let Export { books, export } = serde_json::from_value(body)?;
+    check_export(&export, books.len())?;
+    Ok(books)
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportQuery {
+    #[serde(default)]
+    mode: ImportMode,
+    #[serde(default)]
+    dry_run: bool
+}
+
+/// An `/admin/export` document whose books haven't been read yet, so a
+/// dry run can say which of them don't parse.
+#[derive(Debug, Deserialize)]
+struct UncheckedExport {
+    books: Vec<Value>,
+    export: ExportInfo
+}
+
+/// How one book of a dry run fared. `row` is its index in `books`, or
+/// its line in a CSV file.
+#[derive(Debug, Serialize)]
+struct DryRunRow {
+    row: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Uuid>,
+    valid: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>
+}
+
+pub fn admin_import_doc() -> Value {
+    json!({
+        "operationId": "admin_import",
+        "security": [{"adminToken": []}],
+        "parameters": [{
+            "name": "mode",
+            "in": "query",
+            "description": "`merge` upserts the file's books by id and leaves the others; `replace` deletes every book first",
+            "schema": {"type": "string", "enum": ["merge", "replace"], "default": "merge"}
+        }, {
+            "name": "dry_run",
+            "in": "query",
+            "description": "Check every book and say how each fared, writing nothing",
+            "schema": {"type": "boolean", "default": false}
+        }],
+        "requestBody": {
+            "required": true,
+            "content": {"application/json": {"schema": export_schema()}}
+        },
+        "responses": {
+            "200": json_response("What the import did, in one transaction, or with `dry_run` what each book would make of the checks", json!({
+                "oneOf": [{
+                    "type": "object",
+                    "required": ["inserted", "updated", "skipped", "deleted"],
+                    "properties": {
+                        "inserted": {"type": "integer", "format": "int64"},
+                        "updated": {"type": "integer", "format": "int64"},
+                        "skipped": {"type": "integer", "format": "int64", "description": "Books already stored as the file has them"},
+                        "deleted": {"type": "integer", "format": "int64", "description": "Books `replace` deleted first"}
+                    }
+                }, {
+                    "type": "object",
+                    "required": ["dry_run", "valid", "invalid", "rows"],
+                    "properties": {
+                        "dry_run": {"type": "boolean", "enum": [true]},
+                        "valid": {"type": "integer", "format": "int64"},
+                        "invalid": {"type": "integer", "format": "int64"},
+                        "rows": {"type": "array", "items": {
+                            "type": "object",
+                            "required": ["row", "valid"],
+                            "properties": {
+                                "row": {"type": "integer", "description": "The book's index in `books`"},
+                                "id": {"type": "string", "format": "uuid"},
+                                "valid": {"type": "boolean"},
+                                "errors": {"type": "array", "items": {
+                                    "type": "object",
+                                    "properties": {"field": {"type": "string"}, "message": {"type": "string"}}
+                                }}
+                            }
+                        }}
+                    }
+                }]
+            })),
+            "400": problem_response("Malformed body or `mode`"),
+            "401": problem_response("No `Authorization: Bearer` token, or not `ADMIN_TOKEN`"),
+            "403": problem_response("`ADMIN_TOKEN` isn't set, so the admin routes are off"),
+            "409": problem_response("A new book's name and author are another book's; nothing was imported"),
+            "413": problem_response("The file is over `RESTORE_MAX_BODY_BYTES`"),
+            "422": problem_response("The export's `export` object doesn't come before `books`, its `version` isn't this server's or its `row_count` isn't how many books it holds, or a book fails the checks of a create; `errors` names it, as in `books[3].price`")
+        }
+    })
+}
+
+/// Loads a `GET /admin/export` document into the table, checked as it's
+/// read, its `export` object and every book, so that nothing is written
+/// unless all of it can be. The books are parsed as the body arrives and
+/// written `IMPORT_BATCH` at a time, all in one transaction in the store.
+pub async fn admin_import(mut req: Request<State>) -> Result<Response, AppError> {
+    let ImportQuery { mode, dry_run } = req.query()?;
+    if dry_run {
+        return dry_run_import(read_json(&mut req).await?);
+    }
+    let reader = body_reader_in(&mut req, REQUEST_MEDIA_TYPES)?;
+    let (out, books) = channel::bounded(1);
+    let reading = async_std::task::spawn_blocking(move || read_export(reader, out));
+    let imported = req.state().repo.import_books(books, mode).await;
+    reading.await;
+    let counts = imported?;
+    req.state().cache.clear();
+    crate::books_imported(&req, &counts);
+
+    let mut res = Response::new(200);
+    res.set_body(Body::from_json(&counts)?);
+    Ok(res)
+}
+
+/// Parses an `/admin/export` document from `reader` as it arrives, checking
+/// each book as a create would and sending them on to `out` `IMPORT_BATCH`
+/// at a time, then the empty batch that ends them, or else the error that
+/// abandons the import. The `export` object has to come before `books`,
+/// as `admin_export` writes it, and is checked as it's read, before any
+/// book is sent; `row_count` as the books are counted.
+fn read_export(reader: BodyReader, out: Sender<Result<Vec<Book>, RepositoryError>>) {
+    let mut reader = io::BufReader::new(reader);
+    let mut refused = None;
+    let parsed = {
+        let mut document = serde_json::Deserializer::from_reader(&mut reader);
+        ExportSeed { out: &out, refused: &mut refused }.deserialize(&mut document)
+            .and_then(|()| document.end())
+    };
+    let read = reader.get_ref().check_limit()
+        .and_then(|()| refused.map_or(Ok(()), Err))
+        .and_then(|()| Ok(parsed?));
+    // A failed import has stopped listening.
+    let _ = out.send_blocking(read.map(|()| Vec::new()).map_err(|e| RepositoryError::Rejected(Box::new(e))));
+}
+
+/// Reads an `/admin/export` document for `read_export`. What refuses the
+/// document, a book that fails the checks or an `export` object that
+/// doesn't agree with the books, is left in `refused`, and fails the parse.
+struct ExportSeed<'a> {
+    out: &'a Sender<Result<Vec<Book>, RepositoryError>>,
+    refused: &'a mut Option<AppError>
+}
+
+impl<'de> DeserializeSeed<'de> for ExportSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ExportSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an export document")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let ExportSeed { out, refused } = self;
+        let mut export = None;
+        let mut books = None;
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "export" if export.is_none() => {
+                    let info = map.next_value::<ExportInfo>()?;
+                    check_version(&info).map_err(|e| refuse(refused, e))?;
+                    export = Some(info);
+                }
+                "books" if books.is_none() => {
+                    let Some(info) = &export else {
+                        return Err(refuse(refused, AppError::invalid_field("export", "must come before `books`, as `GET /admin/export` writes it")));
+                    };
+                    books = Some(map.next_value_seed(BooksSeed { out, row_count: info.row_count, refused: &mut *refused })?);
+                }
+                "books" | "export" => return Err(de::Error::custom(format_args!("duplicate field `{}`", key))),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        export.ok_or_else(|| de::Error::missing_field("export"))?;
+        books.ok_or_else(|| de::Error::missing_field("books"))?;
+        Ok(())
+    }
+}
+
+/// Reads the `books` of an export for `ExportSeed` one at a time, sending
+/// them on in batches; its value is how many there were. A book past
+/// `row_count` is refused as it's read, and too few before the last
+/// batch is sent.
+struct BooksSeed<'a> {
+    out: &'a Sender<Result<Vec<Book>, RepositoryError>>,
+    row_count: u64,
+    refused: &'a mut Option<AppError>
+}
+
+impl<'de> DeserializeSeed<'de> for BooksSeed<'_> {
+    type Value = usize;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<usize, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for BooksSeed<'_> {
+    type Value = usize;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an array of books")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<usize, A::Error> {
+        let send = |batch| self.out.send_blocking(Ok(batch)).map_err(|_| de::Error::custom("the import stopped"));
+        let mut batch = Vec::with_capacity(IMPORT_BATCH as usize);
+        let mut count = 0;
+        while let Some(book) = seq.next_element::<Book>()? {
+            if count as u64 == self.row_count {
+                let e = AppError::invalid_field("export.row_count", &format!("must be how many books the file holds, more than {}", self.row_count));
+                return Err(refuse(&mut *self.refused, e));
+            }
+            let book = validate_book(&format!("books[{}].", count), book).map_err(|e| refuse(&mut *self.refused, e))?;
+            batch.push(book);
+            count += 1;
+            if batch.len() == IMPORT_BATCH as usize {
+                send(std::mem::replace(&mut batch, Vec::with_capacity(IMPORT_BATCH as usize)))?;
+            }
+        }
+        check_row_count(self.row_count, count).map_err(|e| refuse(&mut *self.refused, e))?;
+        if !batch.is_empty() {
+            send(batch)?;
+        }
+        Ok(count)
+    }
+}
+
+/// Leaves `e` in `refused` for `read_export`, and fails the parse.
+fn refuse<E: de::Error>(refused: &mut Option<AppError>, e: AppError) -> E {
+    let error = E::custom(e.message());
+    *refused = Some(e);
+    error
+}
+
+/// That `export` is of this server's version and counts the `books` the
+/// file holds.
+fn check_export(export: &ExportInfo, books: usize) -> Result<(), AppError> {
+    check_version(export)?;
+    check_row_count(export.row_count, books)
+}
+
+/// That `export` is of this server's version.
+fn check_version(export: &ExportInfo) -> Result<(), AppError> {
+    if export.version != EXPORT_VERSION {
+        return Err(AppError::invalid_field("export.version", &format!("must be {}, the version this server reads", EXPORT_VERSION)));
+    }
+    Ok(())
+}
+
+/// That `row_count` counts the `books` the file holds.
+fn check_row_count(row_count: u64, books: usize) -> Result<(), AppError> {
+    if row_count != books as u64 {
+        return Err(AppError::invalid_field("export.row_count", &format!("must be {}, how many books the file holds", books)));
+    }
+    Ok(())
+}
+
+/// `POST /admin/import?dry_run=true`: puts each book through what the
+/// import would, parsing and then the checks of a create, and reports on
+/// every one rather than stopping at the first that fails. The document
+/// as a whole still has to be one `admin_import` would read.
+fn dry_run_import(body: Value) -> Result<Response, AppError> {
+    let UncheckedExport { books, export } = serde_json::from_value(body)?;
+    check_export(&export, books.len())?;
+    let rows: Vec<DryRunRow> = books.into_iter().enumerate()
+        .map(|(row, book)| {
+            let id = book.get("id").and_then(|id| serde_json::from_value(id.clone()).ok());
+            let errors = match serde_json::from_value::<Book>(book) {
+                Ok(book) => match validate_book(&format!("books[{}].", row), book) {
+                    Ok(_) => Vec::new(),
+                    Err(AppError::Validation(errors)) => errors,
+                    Err(e) => vec![FieldError { field: format!("books[{}]", row), message: e.message() }],
+                },
+                Err(e) => vec![FieldError { field: format!("books[{}]", row), message: e.to_string() }],
+            };
+            DryRunRow { row, id, valid: errors.is_empty(), errors }
+        })
+        .collect();
+    let valid = rows.iter().filter(|row| row.valid).count();
+
+    let mut res = Response::new(200);
+    res.set_body(json!({"dry_run": true, "valid": valid, "invalid": rows.len() - valid, "rows": rows}));
+    Ok(res)
+}
+
+/// The media type `POST /books/import` takes.
+pub const CSV_MEDIA_TYPES: &[&str] = &["text/csv"];
+
+#[derive(Debug, Deserialize)]
+struct CsvImportQuery {
+    #[serde(default)]
+    dry_run: bool
+}
+
+/// A line of a CSV import: a book's fields under its key names, any of
+/// them left out or empty. A book without an `id` gets a new one.
+#[derive(Debug, Deserialize)]
+struct CsvBook {
+    id: Option<Uuid>,
+    name: Option<String>,
+    author: Option<String>,
+    year: Option<i32>,
+    published_date: Option<chrono::NaiveDate>,
+    publisher: Option<String>,
+    language: Option<String>,
+    price: Option<rust_decimal::Decimal>,
+    stock: Option<i32>
+}
+
+impl From<CsvBook> for Book {
+    fn from(row: CsvBook) -> Self {
+        Book {
+            id: row.id.unwrap_or_else(Uuid::new_v4),
+            name: row.name,
+            author: row.author,
+            year: row.year,
+            published_date: row.published_date,
+            publisher: row.publisher,
+            language: row.language,
+            price: row.price,
+            stock: row.stock,
+            updated_at: None
+        }
+    }
+}
+
+pub fn import_books_doc() -> Value {
+    json!({
+        "operationId": "import_books",
+        "security": [{"adminToken": []}],
+        "parameters": [{
+            "name": "dry_run",
+            "in": "query",
+            "description": "Check every line and say how each fared, writing nothing",
+            "schema": {"type": "boolean", "default": false}
+        }],
+        "requestBody": {
+            "required": true,
+            "description": "A header line naming book keys, such as `name,author,year`, then a book per line. Empty cells are `null`; a line without an `id` gets a new one",
+            "content": {"text/csv": {"schema": {"type": "string"}}}
+        },
+        "responses": {
+            "200": json_response("What the import did, in one transaction, upserting by id, or with `dry_run` what each line would make of the checks", json!({
+                "oneOf": [{
+                    "type": "object",
+                    "required": ["inserted", "updated", "skipped", "deleted"],
+                    "properties": {
+                        "inserted": {"type": "integer", "format": "int64"},
+                        "updated": {"type": "integer", "format": "int64"},
+                        "skipped": {"type": "integer", "format": "int64", "description": "Books already stored as the file has them"},
+                        "deleted": {"type": "integer", "format": "int64", "enum": [0]}
+                    }
+                }, {
+                    "type": "object",
+                    "required": ["dry_run", "valid", "invalid", "rows"],
+                    "properties": {
+                        "dry_run": {"type": "boolean", "enum": [true]},
+                        "valid": {"type": "integer", "format": "int64"},
+                        "invalid": {"type": "integer", "format": "int64"},
+                        "rows": {"type": "array", "items": {
+                            "type": "object",
+                            "required": ["row", "valid"],
+                            "properties": {
+                                "row": {"type": "integer", "description": "The book's line in the file, the header being line 1"},
+                                "id": {"type": "string", "format": "uuid"},
+                                "valid": {"type": "boolean"},
+                                "errors": {"type": "array", "items": {
+                                    "type": "object",
+                                    "properties": {"field": {"type": "string"}, "message": {"type": "string"}}
+                                }}
+                            }
+                        }}
+                    }
+                }]
+            })),
+            "400": problem_response("The file isn't CSV, or `dry_run` isn't a boolean"),
+            "401": problem_response("No `Authorization: Bearer` token, or not `ADMIN_TOKEN`"),
+            "403": problem_response("`ADMIN_TOKEN` isn't set, so the import is off"),
+            "409": problem_response("A new book's name and author are another book's; nothing was imported"),
+            "413": problem_response("The file is over `RESTORE_MAX_BODY_BYTES`"),
+            "415": problem_response("The body isn't `text/csv`"),
+            "422": problem_response("The header names a key books don't have, or a line fails to parse or the checks of a create; `errors` names it by line, as in `[3].price`")
+        }
+    })
+}
+
+/// Loads the books of a CSV file into the table, upserting them by id.
+/// Every line is parsed and checked first, so nothing is written unless
+/// all of them can be; with `?dry_run=true` nothing is written at all,
+/// and the answer says how each line fared.
+pub async fn import_books(mut req: Request<State>) -> Result<Response, AppError> {
+    let CsvImportQuery { dry_run } = req.query()?;
+    let body = read_bytes_in(&mut req, CSV_MEDIA_TYPES).await?;
+    let lines = csv_books(&body)?;
+    if dry_run {
+        let rows: Vec<DryRunRow> = lines.into_iter()
+            .map(|CsvLine { line, id, book }| {
+                let errors = book.err().unwrap_or_default();
+                DryRunRow { row: line, id, valid: errors.is_empty(), errors }
+            })
+            .collect();
+        let valid = rows.iter().filter(|row| row.valid).count();
+        let mut res = Response::new(200);
+        res.set_body(json!({"dry_run": true, "valid": valid, "invalid": rows.len() - valid, "rows": rows}));
+        return Ok(res);
+    }
+
+    let mut books = Vec::new();
+    let mut errors = Vec::new();
+    for CsvLine { book, .. } in lines {
+        match book {
+            Ok(book) => books.push(book),
+            Err(line_errors) => errors.extend(line_errors),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(AppError::Validation(errors));
+    }
+    let counts = req.state().repo.import_books(import_batches(books), ImportMode::Merge).await?;
+    req.state().cache.clear();
+    crate::books_imported(&req, &counts);
+
+    let mut res = Response::new(200);
+    res.set_body(Body::from_json(&counts)?);
+    Ok(res)
+}
+
+/// A line of a CSV import after the header, as `csv_books` read it.
+struct CsvLine {
+    line: usize,
+    /// The `id` the line gives, if it parsed and gave one.
+    id: Option<Uuid>,
+    /// The book once through the checks of a create, or what's wrong with it.
+    book: Result<Book, Vec<FieldError>>
+}
+
+/// Every line of `body` after the header. A header naming a key books
+/// don't have fails the whole file.
+fn csv_books(body: &[u8]) -> Result<Vec<CsvLine>, AppError> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(body);
+    let headers = reader.headers()
+        .map_err(|e| AppError::BadRequest(format!("could not read the CSV header: {}", e)))?
+        .clone();
+    let columns: serde_json::Map<String, Value> = headers.iter().map(|column| (column.to_owned(), Value::Null)).collect();
+    let unknown = unknown_keys(&Value::Object(columns), fields::BOOK_FIELDS, "");
+    if !unknown.is_empty() {
+        return Err(AppError::Validation(unknown));
+    }
+
+    let mut lines = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                let line = e.position().map_or(0, |position| position.line() as usize);
+                let errors = vec![FieldError { field: format!("[{}]", line), message: csv_message(&e) }];
+                lines.push(CsvLine { line, id: None, book: Err(errors) });
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |position| position.line() as usize);
+        let parsed = match record.deserialize::<CsvBook>(Some(&headers)) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let column = match e.kind() {
+                    csv::ErrorKind::Deserialize { err, .. } => err.field().and_then(|field| headers.get(field as usize)),
+                    _ => None,
+                };
+                let field = match column {
+                    Some(column) => format!("[{}].{}", line, column),
+                    None => format!("[{}]", line),
+                };
+                lines.push(CsvLine { line, id: None, book: Err(vec![FieldError { field, message: csv_message(&e) }]) });
+                continue;
+            }
+        };
+        let id = parsed.id;
+        let book = match validate_book(&format!("[{}].", line), Book::from(parsed)) {
+            Ok(book) => Ok(book),
+            Err(AppError::Validation(errors)) => Err(errors),
+            Err(e) => Err(vec![FieldError { field: format!("[{}]", line), message: e.message() }]),
+        };
+        lines.push(CsvLine { line, id, book });
+    }
+    Ok(lines)
+}
+
+/// What's wrong with a line, without the position `csv` puts in front,
+/// since the field already says which line it is.
+fn csv_message(e: &csv::Error) -> String {
+    match e.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => err.kind().to_string(),
+        csv::ErrorKind::UnequalLengths { expected_len, len, .. } => format!("has {} fields where the header has {}", len, expected_len),
+        _ => e.to_string(),
+    }
+}
@@ -0,0 +1,329 @@
+use std::io;
+use std::str::FromStr;
+
+use async_std::io::ReadExt;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tide::{Body, Middleware, Next, Request};
+
+use crate::config::Config;
+use crate::error::{AppError, FieldError};
+use crate::jsonapi;
+
+/// Used when nothing configures a limit: 1 MiB.
+pub const DEFAULT_MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// Caps the size of request bodies read through `read_json`.
+///
+/// Registered on the app it sets the default; registered on a route with
+/// `.with(...)` it overrides that default for the route, since route
+/// middleware runs after the app's.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimit {
+    max_bytes: u64
+}
+
+impl BodyLimit {
+    pub fn new(max_bytes: u64) -> Self {
+        BodyLimit { max_bytes }
+    }
+
+    /// The limit from `max_body_bytes`.
+    pub fn from_config(config: &Config) -> Self {
+        BodyLimit::new(config.max_body_bytes)
+    }
+
+    fn too_large(&self) -> AppError {
+        AppError::PayloadTooLarge(format!("request body exceeds {} bytes", self.max_bytes))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BodyLimit {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(*self);
+        Ok(next.run(req).await)
+    }
+}
+
+/// The media types `read_json` takes request bodies in, charset aside. A
+/// new body format is added here so the `415` for others names it too.
+pub const REQUEST_MEDIA_TYPES: &[&str] = &["application/json", jsonapi::MEDIA_TYPE];
+
+/// What `read_json` makes of a body sent without a `Content-Type`.
+/// Registered on the app like `BodyLimit`, and set by `content_type_mode`:
+/// `lenient` or `strict`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentTypeMode {
+    /// Reads it as JSON, for clients that never set the header.
+    Lenient,
+    /// Refuses it with `415`.
+    Strict
+}
+
+impl FromStr for ContentTypeMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode {
+            "lenient" => Ok(ContentTypeMode::Lenient),
+            "strict" => Ok(ContentTypeMode::Strict),
+            _ => Err(String::from("expected lenient or strict")),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ContentTypeMode {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(*self);
+        Ok(next.run(req).await)
+    }
+}
+
+/// Fails with `415` unless the body is one of `accepted`.
+fn check_content_type<State>(req: &Request<State>, accepted: &'static [&'static str]) -> Result<(), AppError> {
+    let mode = req.ext::<ContentTypeMode>().copied().unwrap_or(ContentTypeMode::Lenient);
+    let detail = match req.content_type() {
+        Some(mime) if accepted.contains(&mime.essence()) => return Ok(()),
+        Some(mime) => format!("request bodies of type {} are not supported", mime.essence()),
+        None if mode == ContentTypeMode::Lenient => return Ok(()),
+        None => String::from("request bodies need a Content-Type"),
+    };
+    Err(AppError::UnsupportedMediaType {
+        detail: format!("{}; send {}", detail, accepted.join(" or ")),
+        accepted
+    })
+}
+
+/// Deserializes the JSON body, rejecting it with `415` when its
+/// `Content-Type` isn't JSON, and with `413` once it passes the request's
+/// `BodyLimit`: up front when `Content-Length` is too big, and otherwise
+/// as soon as a chunked body has streamed one byte too many.
+pub async fn read_json<T, State>(req: &mut Request<State>) -> Result<T, AppError>
+where
+    T: DeserializeOwned,
+    State: Clone + Send + Sync + 'static,
+{
+    read_json_in(req, REQUEST_MEDIA_TYPES).await
+}
+
+/// Like `read_json`, for a route that takes bodies in `accepted` instead
+/// of `REQUEST_MEDIA_TYPES`.
+pub async fn read_json_in<T, State>(req: &mut Request<State>, accepted: &'static [&'static str]) -> Result<T, AppError>
+where
+    T: DeserializeOwned,
+    State: Clone + Send + Sync + 'static,
+{
+    Ok(serde_json::from_slice(&read_bytes_in(req, accepted).await?)?)
+}
+
+/// The body as `read_json_in` checks it, left unparsed, for a route that
+/// takes something other than JSON.
+pub async fn read_bytes_in<State>(req: &mut Request<State>, accepted: &'static [&'static str]) -> Result<Vec<u8>, AppError>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let limit = checked_limit(req, accepted)?;
+    let mut bytes = Vec::new();
+    req.take_body()
+        .take(limit.max_bytes + 1)
+        .read_to_end(&mut bytes).await
+        .map_err(|e| AppError::BadRequest(format!("could not read request body: {}", e)))?;
+    if bytes.len() as u64 > limit.max_bytes {
+        return Err(limit.too_large());
+    }
+    Ok(bytes)
+}
+
+/// The body as `read_bytes_in` checks it, left unread, for a route that
+/// parses it as it arrives instead of holding it all.
+pub fn body_reader_in<State>(req: &mut Request<State>, accepted: &'static [&'static str]) -> Result<BodyReader, AppError>
+where
+    State: Clone + Send + Sync + 'static,
+{
+    let limit = checked_limit(req, accepted)?;
+    Ok(BodyReader { body: req.take_body(), limit, read: 0 })
+}
+
+/// The route's `BodyLimit`, once the body's type is one of `accepted` and
+/// its length, if given, is within the limit.
+fn checked_limit<State>(req: &Request<State>, accepted: &'static [&'static str]) -> Result<BodyLimit, AppError> {
+    check_content_type(req, accepted)?;
+    let limit = req.ext::<BodyLimit>().copied().unwrap_or(BodyLimit::new(DEFAULT_MAX_BODY_BYTES));
+    if req.len().is_some_and(|len| len as u64 > limit.max_bytes) {
+        return Err(limit.too_large());
+    }
+    Ok(limit)
+}
+
+/// A request body read through the blocking `Read`, for a parser to run
+/// on a `spawn_blocking` thread. Reading past the route's `BodyLimit`
+/// fails; `check_limit` then says so.
+pub struct BodyReader {
+    body: Body,
+    limit: BodyLimit,
+    read: u64
+}
+
+impl BodyReader {
+    /// Fails with `413` if the body ran past the limit, so a parse that
+    /// failed for it isn't reported as malformed.
+    pub fn check_limit(&self) -> Result<(), AppError> {
+        if self.read > self.limit.max_bytes {
+            return Err(self.limit.too_large());
+        }
+        Ok(())
+    }
+}
+
+impl io::Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read > self.limit.max_bytes {
+            return Err(io::Error::other(self.limit.too_large().message()));
+        }
+        let allowed = (self.limit.max_bytes + 1 - self.read).min(buf.len() as u64) as usize;
+        let read = async_std::task::block_on(self.body.read(&mut buf[..allowed]))?;
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+/// What `read_body` does with keys the body's type doesn't have.
+/// Registered on the app like `BodyLimit`, and set by `unknown_fields`:
+/// `reject` or `ignore`.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownFields {
+    /// Refuses the body with a `422` naming each unknown key.
+    Reject,
+    /// Drops them, as every body did before they were checked.
+    Ignore
+}
+
+impl FromStr for UnknownFields {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode {
+            "reject" => Ok(UnknownFields::Reject),
+            "ignore" => Ok(UnknownFields::Ignore),
+            _ => Err(String::from("expected reject or ignore")),
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for UnknownFields {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(*self);
+        Ok(next.run(req).await)
+    }
+}
+
+/// A request body whose keys are checked before it's deserialized, so a
+/// typo like `yaer` is reported instead of silently dropped.
+pub trait RequestBody: DeserializeOwned {
+    /// The keys of `body` this type doesn't have, as paths like
+    /// `book.yaer`, each with the closest key it does have.
+    fn unknown_fields(body: &Value) -> Vec<FieldError>;
+}
+
+/// The keys of the object `body` that aren't in `known`. `prefix` is
+/// where the object sits in the request body, as in `book.`.
+pub fn unknown_keys(body: &Value, known: &[&str], prefix: &str) -> Vec<FieldError> {
+    let object = match body {
+        Value::Object(object) => object,
+        _ => return Vec::new(),
+    };
+    object.keys()
+        .filter(|key| !known.contains(&key.as_str()))
+        .map(|key| FieldError {
+            field: format!("{}{}", prefix, key),
+            message: match closest(key, known) {
+                Some(suggestion) => format!("is not a known field; did you mean {}?", suggestion),
+                None => String::from("is not a known field"),
+            }
+        })
+        .collect()
+}
+
+/// The entry of `known` within two edits of `key`, if there's one.
+fn closest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known.iter()
+        .map(|candidate| (edit_distance(key, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// The Levenshtein distance, counting a swap of neighbours as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut rows = vec![(0..=b.len()).collect::<Vec<usize>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = rows[i - 1][j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            row[j] = substitution.min(rows[i - 1][j] + 1).min(row[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
+
+/// Like `read_json`, refusing keys `T` doesn't have unless the request's
+/// `UnknownFields` says to ignore them.
+pub async fn read_body<T, State>(req: &mut Request<State>) -> Result<T, AppError>
+where
+    T: RequestBody,
+    State: Clone + Send + Sync + 'static,
+{
+    Ok(serde_json::from_value(read_checked::<T, State>(req).await?)?)
+}
+
+/// The body as `read_body` checks it, left as JSON for the handler to
+/// look at before parsing it as a `T`.
+pub async fn read_checked<T, State>(req: &mut Request<State>) -> Result<Value, AppError>
+where
+    T: RequestBody,
+    State: Clone + Send + Sync + 'static,
+{
+    let body: Value = read_json(req).await?;
+    check_fields::<T, State>(req, &body)?;
+    Ok(body)
+}
+
+/// Refuses `body` with a `422` if it has keys `T` doesn't, unless the
+/// request's `UnknownFields` says to ignore them.
+pub fn check_fields<T, State>(req: &Request<State>, body: &Value) -> Result<(), AppError>
+where
+    T: RequestBody,
+{
+    if req.ext::<UnknownFields>().copied().unwrap_or(UnknownFields::Reject) == UnknownFields::Reject {
+        let unknown = T::unknown_fields(body);
+        if !unknown.is_empty() {
+            return Err(AppError::Validation(unknown));
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn unknown_keys_come_with_the_closest_known_one() {
+    let body = serde_json::json!({ "name": "Dune", "yaer": 1965, "publsher": "Chilton", "colour": "red" });
+    let mut unknown = unknown_keys(&body, &["name", "year", "publisher"], "book.");
+    unknown.sort_by(|a, b| a.field.cmp(&b.field));
+    let unknown: Vec<(&str, &str)> = unknown.iter().map(|e| (e.field.as_str(), e.message.as_str())).collect();
+    assert_eq!(vec![
+        ("book.colour", "is not a known field"),
+        ("book.publsher", "is not a known field; did you mean publisher?"),
+        ("book.yaer", "is not a known field; did you mean year?"),
+    ], unknown);
+}
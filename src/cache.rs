@@ -0,0 +1,160 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use crate::Book;
+use crate::config::Config;
+
+/// Used when `book_cache_ttl_ms` isn't set: 5 seconds.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(5);
+/// Used when `book_cache_capacity` isn't set.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// A bounded cache of books, for `get_book`, that drops the least recently
+/// used book when it's full.
+///
+/// Handlers that write a book evict it once the write is done. A book read
+/// from the store is only put in if nothing was evicted since the read
+/// began, as `version` tells, so a read that raced a write can't put the
+/// old row back. Entries also expire after `ttl`; a `ttl` of zero turns the
+/// cache off. The lock is only held to update the maps, never across a
+/// query.
+#[derive(Debug)]
+pub struct BookCache {
+    entries: Mutex<Entries>,
+    ttl: Duration,
+    capacity: usize
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    books: HashMap<Uuid, Entry>,
+    /// The cached ids by when they were last used, least recent first.
+    by_use: BTreeMap<u64, Uuid>,
+    uses: u64,
+    /// How many evictions there have been.
+    version: u64
+}
+
+#[derive(Debug)]
+struct Entry {
+    book: Book,
+    cached_at: Instant,
+    used: u64
+}
+
+impl Entries {
+    fn remove(&mut self, id: Uuid) {
+        if let Some(entry) = self.books.remove(&id) {
+            self.by_use.remove(&entry.used);
+        }
+    }
+
+    fn next_use(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+}
+
+impl BookCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        BookCache { entries: Mutex::new(Entries::default()), ttl, capacity }
+    }
+
+    /// The cache configured by `book_cache_ttl_ms` and `book_cache_capacity`.
+    pub fn from_config(config: &Config) -> Self {
+        BookCache::new(Duration::from_millis(config.book_cache_ttl_ms), config.book_cache_capacity)
+    }
+
+    pub fn get(&self, id: Uuid) -> Option<Book> {
+        let mut entries = self.entries.lock().unwrap();
+        let cached_at = entries.books.get(&id)?.cached_at;
+        if cached_at.elapsed() >= self.ttl {
+            entries.remove(id);
+            return None;
+        }
+        let used = entries.next_use();
+        let entry = entries.books.get_mut(&id)?;
+        let last_used = std::mem::replace(&mut entry.used, used);
+        let book = entry.book.clone();
+        entries.by_use.remove(&last_used);
+        entries.by_use.insert(used, id);
+        Some(book)
+    }
+
+    /// What to give `insert` for a book read from the store after this
+    /// call.
+    pub fn version(&self) -> u64 {
+        self.entries.lock().unwrap().version
+    }
+
+    /// Caches `book`, read from the store at `version`, unless a book was
+    /// evicted since.
+    pub fn insert(&self, book: Book, version: u64) {
+        if self.ttl.is_zero() || self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.version != version {
+            return;
+        }
+        entries.remove(book.id);
+        while entries.books.len() >= self.capacity {
+            match entries.by_use.pop_first() {
+                Some((_, least_used)) => entries.books.remove(&least_used),
+                None => break,
+            };
+        }
+        let used = entries.next_use();
+        entries.by_use.insert(used, book.id);
+        entries.books.insert(book.id, Entry { book, cached_at: Instant::now(), used });
+    }
+
+    pub fn evict(&self, id: Uuid) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(id);
+        entries.version += 1;
+    }
+
+    /// Evicts everything, for writes that don't say which books they hit.
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.books.clear();
+        entries.by_use.clear();
+        entries.version += 1;
+    }
+}
+
+#[test]
+fn the_least_recently_used_book_goes_first() {
+    let book = |name: &str| Book {
+        id: Uuid::new_v4(),
+        name: Some(name.to_owned()),
+        author: None,
+        year: None,
+        published_date: None,
+        publisher: None,
+        language: None,
+        price: None,
+        stock: None,
+        updated_at: None
+    };
+    let (dune, emma, ulysses) = (book("Dune"), book("Emma"), book("Ulysses"));
+    let cache = BookCache::new(Duration::from_secs(60), 2);
+    cache.insert(dune.clone(), cache.version());
+    cache.insert(emma.clone(), cache.version());
+    // Reading Dune makes Emma the least recently used.
+    assert_eq!(Some(&dune), cache.get(dune.id).as_ref());
+    cache.insert(ulysses.clone(), cache.version());
+    assert!(cache.get(emma.id).is_none());
+    assert!(cache.get(dune.id).is_some());
+    assert!(cache.get(ulysses.id).is_some());
+
+    // A read that began before an eviction isn't cached.
+    let version = cache.version();
+    cache.evict(dune.id);
+    cache.insert(dune.clone(), version);
+    assert!(cache.get(dune.id).is_none());
+}
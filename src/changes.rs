@@ -0,0 +1,296 @@
+//! Book changes between instances, over Postgres `LISTEN`/`NOTIFY`.
+//!
+//! `PgBookRepository` notifies `CHANNEL` of every change, of a bulk
+//! write's changes a batch at a time, or of an import's counts, in the
+//! transaction making it, so only committed changes are heard of. Each
+//! instance runs `relay`, which feeds the changes the other instances made
+//! to its `/books/events` and `/ws/books` clients and evicts them from its
+//! cache; its own changes were already handled by the request making them.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use uuid::Uuid;
+
+use crate::repository::ImportCounts;
+use crate::{AuditAction, Book, State, webhook};
+
+/// The channel the changes are notified on.
+pub const CHANNEL: &str = "book_changes";
+/// Postgres refuses notifications of 8000 bytes or more.
+pub const MAX_PAYLOAD_BYTES: usize = 7999;
+/// The wait before reconnecting the first time; it doubles on each failed
+/// attempt up to `MAX_RECONNECT_BACKOFF`.
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// One change as notified, or a batch of them.
+#[derive(Debug, Deserialize)]
+struct Change {
+    origin: Uuid,
+    #[serde(flatten)]
+    changed: Changed
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Changed {
+    /// A change to one book. `book` is left out, leaving just `id`, when it
+    /// would make the payload too long for `NOTIFY`.
+    Book {
+        event: String,
+        book: Option<Book>,
+        id: Option<Uuid>
+    },
+    /// The changes of a batch, such as a bulk update's, by their rows in
+    /// `audit_log`.
+    Batch {
+        audit_ids: Vec<i64>
+    },
+    /// What an import did, in place of its changes, which may be too many
+    /// to send.
+    Import {
+        imported: ImportCounts
+    }
+}
+
+/// The notification of `event` on book `id` made by `origin`, where `book`
+/// is the book's JSON after the change, or before it for a delete.
+pub fn payload(origin: Uuid, event: &str, id: Uuid, book: &str) -> String {
+    let payload = format!(r#"{{"origin":"{}","event":"{}","book":{}}}"#, origin, event, book);
+    if payload.len() <= MAX_PAYLOAD_BYTES {
+        payload
+    } else {
+        format!(r#"{{"origin":"{}","event":"{}","id":"{}"}}"#, origin, event, id)
+    }
+}
+
+/// The notifications of a batch of changes made by `origin`, naming their
+/// rows in `audit_log`: one, unless there are too many for one payload.
+pub fn batch_payloads(origin: Uuid, audit_ids: &[i64]) -> Vec<String> {
+    let batch = |ids: &[String]| format!(r#"{{"origin":"{}","audit_ids":[{}]}}"#, origin, ids.join(","));
+    let mut payloads = Vec::new();
+    let mut ids = Vec::new();
+    let mut len = batch(&[]).len();
+    for id in audit_ids {
+        let id = id.to_string();
+        if !ids.is_empty() && len + 1 + id.len() > MAX_PAYLOAD_BYTES {
+            payloads.push(batch(&ids));
+            ids.clear();
+            len = batch(&[]).len();
+        }
+        len += id.len() + usize::from(!ids.is_empty());
+        ids.push(id);
+    }
+    if !ids.is_empty() {
+        payloads.push(batch(&ids));
+    }
+    payloads
+}
+
+/// The notification of an import made by `origin` that did `counts`.
+pub fn import_payload(origin: Uuid, counts: &ImportCounts) -> String {
+    format!(r#"{{"origin":"{}","imported":{}}}"#, origin, json!(counts))
+}
+
+/// Starts listening on `CHANNEL` for the changes of the instances other
+/// than `origin`, returning once listening or, if the database can't be
+/// reached, once the first attempt failed. The listener reconnects on its
+/// own whenever its connection drops, clearing `state`'s cache since the
+/// changes made in the meantime are missed, and stops when `pool` closes.
+pub async fn relay(pool: PgPool, origin: Uuid, state: &State) {
+    let state = state.clone();
+    let mut listener = connect(&pool).await;
+    async_std::task::spawn(async move {
+        let mut backoff = RECONNECT_BACKOFF;
+        loop {
+            let received = match &mut listener {
+                Ok(connected) => connected.try_recv().await,
+                Err(sqlx::Error::PoolClosed) => return,
+                Err(e) => {
+                    tracing::error!("could not listen for book changes, retrying in {:?}: {}", backoff, e);
+                    async_std::task::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                    listener = connect(&pool).await;
+                    state.cache.clear();
+                    continue;
+                }
+            };
+            match received {
+                Ok(Some(notification)) => {
+                    backoff = RECONNECT_BACKOFF;
+                    relay_change(&state, &pool, origin, notification.payload()).await;
+                }
+                // Reconnected on the next `try_recv`.
+                Ok(None) => {
+                    tracing::warn!("lost the connection listening for book changes, reconnecting");
+                    state.cache.clear();
+                }
+                Err(sqlx::Error::PoolClosed) => return,
+                Err(e) => {
+                    tracing::error!("could not reconnect to listen for book changes, retrying in {:?}: {}", backoff, e);
+                    async_std::task::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+                }
+            }
+        }
+    });
+}
+
+/// A listener on `CHANNEL`, or the error it couldn't be set up for.
+async fn connect(pool: &PgPool) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::connect_with(pool).await?;
+    listener.listen(CHANNEL).await?;
+    Ok(listener)
+}
+
+/// Evicts the book of the change in `payload` and tells the local event
+/// clients about it, unless `origin` made it. A change notified with just
+/// the book's id is looked up: the book as it is now, or, for a delete, as
+/// its audit log last recorded it. A batch is read back from its rows in
+/// `audit_log`, from `pool`. An import may have changed any book, so it
+/// clears the cache.
+async fn relay_change(state: &State, pool: &PgPool, origin: Uuid, payload: &str) {
+    let change = match serde_json::from_str::<Change>(payload) {
+        Ok(change) if change.origin == origin => return,
+        Ok(change) => change,
+        Err(e) => return tracing::error!("ignoring a book change that doesn't parse: {}", e),
+    };
+    let (event, book, id) = match change.changed {
+        Changed::Book { event, book, id } => (event, book, id),
+        Changed::Batch { audit_ids } => return relay_batch(state, pool, &audit_ids).await,
+        Changed::Import { imported } => {
+            state.cache.clear();
+            return state.events.publish_import(&imported);
+        }
+    };
+    let Some(&event) = webhook::EVENTS.iter().find(|known| **known == event) else {
+        return tracing::error!("ignoring a book change of unknown event {:?}", event);
+    };
+    let book = match (book, id) {
+        (Some(book), _) => book,
+        (None, Some(id)) => {
+            state.cache.evict(id);
+            let looked_up = if event == "book.deleted" {
+                state.repo.book_history(id, u32::MAX).await.map(|history| history.into_iter().last().and_then(|entry| entry.old))
+            } else {
+                state.repo.get_book(id).await
+            };
+            match looked_up {
+                Ok(Some(book)) => book,
+                // Gone again since; that change is on its way too.
+                Ok(None) => return,
+                Err(e) => return tracing::error!("could not look up book {} for {}: {}", id, event, e),
+            }
+        }
+        (None, None) => return tracing::error!("ignoring a book change naming no book"),
+    };
+    state.cache.evict(book.id);
+    state.events.publish(event, &book);
+}
+
+/// Evicts each book of a batch of changes and tells the local event
+/// clients about them together, as their rows in `audit_log` recorded
+/// them. If the rows can't be read the whole cache is cleared, as for a
+/// lost connection.
+async fn relay_batch(state: &State, pool: &PgPool, audit_ids: &[i64]) {
+    let rows = sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+        r#"
+        SELECT action, old_value, new_value FROM audit_log
+        WHERE id = ANY($1)
+        ORDER BY id
+        "#)
+        .bind(audit_ids)
+        .fetch_all(pool).await;
+    let rows = match rows {
+        Ok(rows) => rows,
+        Err(e) => {
+            state.cache.clear();
+            return tracing::error!("could not read back a batch of book changes: {}", e);
+        }
+    };
+    let mut changes = Vec::with_capacity(rows.len());
+    for (action, old_value, new_value) in rows {
+        let Some(action) = AuditAction::parse(&action) else {
+            tracing::error!("ignoring a book change of unknown action {:?}", action);
+            continue;
+        };
+        let book = new_value.or(old_value).map(|book| serde_json::from_str::<Book>(&book));
+        match book {
+            Some(Ok(book)) => {
+                state.cache.evict(book.id);
+                changes.push((action.event(), book));
+            }
+            Some(Err(e)) => tracing::error!("ignoring a book change whose book doesn't parse: {}", e),
+            None => tracing::error!("ignoring a book change naming no book"),
+        }
+    }
+    state.events.publish_all(changes.iter().map(|(event, book)| (*event, book)));
+}
+
+#[test]
+fn payloads_too_long_to_notify_carry_just_the_id() {
+    let origin = Uuid::new_v4();
+    let mut book = Book {
+        id: Uuid::new_v4(),
+        name: Some(String::from("Dune")),
+        author: None,
+        year: None,
+        published_date: None,
+        publisher: None,
+        language: None,
+        price: None,
+        stock: None,
+        updated_at: None
+    };
+    let short = payload(origin, "book.created", book.id, &serde_json::to_string(&book).unwrap());
+    let change: Change = serde_json::from_str(&short).unwrap();
+    assert_eq!(origin, change.origin);
+    let Changed::Book { event, book: notified, .. } = change.changed else { panic!("not a book's change: {}", short) };
+    assert_eq!("book.created", event);
+    assert_eq!(Some(&book), notified.as_ref());
+
+    book.name = Some("x".repeat(MAX_PAYLOAD_BYTES));
+    let long = payload(origin, "book.updated", book.id, &serde_json::to_string(&book).unwrap());
+    assert!(long.len() <= MAX_PAYLOAD_BYTES);
+    let change: Change = serde_json::from_str(&long).unwrap();
+    let Changed::Book { book: notified, id, .. } = change.changed else { panic!("not a book's change: {}", long) };
+    assert!(notified.is_none());
+    assert_eq!(Some(book.id), id);
+}
+
+#[test]
+fn batches_are_notified_by_their_audit_rows_in_as_few_payloads_as_fit() {
+    let origin = Uuid::new_v4();
+    let small: Vec<i64> = (1..=500).collect();
+    let payloads = batch_payloads(origin, &small);
+    assert_eq!(1, payloads.len());
+    let change: Change = serde_json::from_str(&payloads[0]).unwrap();
+    assert_eq!(origin, change.origin);
+    let Changed::Batch { audit_ids } = change.changed else { panic!("not a batch: {}", payloads[0]) };
+    assert_eq!(small, audit_ids);
+
+    let large: Vec<i64> = (0..500).map(|n| i64::MAX - n).collect();
+    let payloads = batch_payloads(origin, &large);
+    assert_eq!(2, payloads.len());
+    let mut notified = Vec::new();
+    for payload in &payloads {
+        assert!(payload.len() <= MAX_PAYLOAD_BYTES);
+        let Changed::Batch { audit_ids } = serde_json::from_str::<Change>(payload).unwrap().changed else { panic!("not a batch: {}", payload) };
+        notified.extend(audit_ids);
+    }
+    assert_eq!(large, notified);
+}
+
+#[test]
+fn imports_are_notified_by_their_counts() {
+    let origin = Uuid::new_v4();
+    let counts = ImportCounts { inserted: 400, updated: 2, skipped: 1, deleted: 0 };
+    let change: Change = serde_json::from_str(&import_payload(origin, &counts)).unwrap();
+    assert_eq!(origin, change.origin);
+    let Changed::Import { imported } = change.changed else { panic!("not an import: {:?}", change.changed) };
+    assert_eq!(counts, imported);
+}
@@ -0,0 +1,118 @@
+//! The binary's subcommands. Without one it serves, as it always has.
+
+pub const USAGE: &str = "\
+usage: crud_test [COMMAND] [OPTIONS]
+
+commands:
+    serve      run the HTTP server (the default)
+        --listen HOST:PORT    where to listen (HOST, PORT; default 127.0.0.1:8080)
+        --database-url URL    the database to serve from (DATABASE_URL)
+    migrate    run the database migrations and exit
+    seed       insert sample books, for local development
+        --count N    how many (default 50)
+        --force      seed even when there are books already
+    check      connect to the database and exit 0 if the books table is
+               there (TABLE_NAME, default book) or 1 if it isn't
+    help       show this message
+
+Settings are read from CONFIG_FILE (default config.toml), then from the
+environment, each by its name in upper case; config.toml.example lists
+them. Options given here win over both.";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `settings` are what the options set, by the environment variables
+    /// they stand in for.
+    Serve { settings: Vec<(&'static str, String)> },
+    Migrate,
+    Seed { count: usize, force: bool },
+    Check,
+    Help,
+}
+
+impl Command {
+    /// Parses the arguments after the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, String> {
+        let mut args = args.into_iter();
+        let command = match args.next().as_deref() {
+            None => Command::Serve { settings: Vec::new() },
+            Some("serve") => return Command::parse_serve(args),
+            Some("migrate") => Command::Migrate,
+            Some("seed") => return Command::parse_seed(args),
+            Some("check") => Command::Check,
+            Some("help" | "-h" | "--help") => Command::Help,
+            Some(other) => return Err(format!("unknown command: {}", other)),
+        };
+        match args.next() {
+            Some(extra) => Err(format!("unexpected argument: {}", extra)),
+            None => Ok(command),
+        }
+    }
+
+    /// The value the command's options give the setting `var`, which
+    /// overrides the configuration's.
+    pub fn setting(&self, var: &str) -> Option<String> {
+        match self {
+            Command::Serve { settings } => settings.iter().rev().find(|(name, _)| *name == var).map(|(_, value)| value.clone()),
+            _ => None,
+        }
+    }
+
+    fn parse_serve(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+        let mut settings = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--listen" => {
+                    let value = args.next().ok_or("--listen needs a HOST:PORT")?;
+                    let (host, port) = value.rsplit_once(':').ok_or_else(|| format!("--listen: expected HOST:PORT, got {:?}", value))?;
+                    settings.push(("HOST", host.to_owned()));
+                    settings.push(("PORT", port.to_owned()));
+                }
+                "--database-url" => settings.push(("DATABASE_URL", args.next().ok_or("--database-url needs a URL")?)),
+                "-h" | "--help" => return Ok(Command::Help),
+                _ => return Err(format!("unexpected argument: {}", arg)),
+            }
+        }
+        Ok(Command::Serve { settings })
+    }
+
+    fn parse_seed(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+        let (mut count, mut force) = (crate::seed::DEFAULT_COUNT, false);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--count" => {
+                    let value = args.next().ok_or("--count needs a number")?;
+                    count = value.parse().map_err(|_| format!("--count: invalid number {:?}", value))?;
+                }
+                "--force" => force = true,
+                _ => return Err(format!("unexpected argument: {}", arg)),
+            }
+        }
+        Ok(Command::Seed { count, force })
+    }
+}
+
+#[test]
+fn commands_are_parsed() {
+    let parse = |args: &[&str]| Command::parse(args.iter().map(|arg| arg.to_string()));
+    assert_eq!(Ok(Command::Serve { settings: Vec::new() }), parse(&[]));
+    assert_eq!(Ok(Command::Serve { settings: Vec::new() }), parse(&["serve"]));
+    let serve = parse(&["serve", "--listen", "0.0.0.0:9000", "--database-url", "postgres://db/books"]).unwrap();
+    assert_eq!(Some(String::from("0.0.0.0")), serve.setting("HOST"));
+    assert_eq!(Some(String::from("9000")), serve.setting("PORT"));
+    assert_eq!(Some(String::from("postgres://db/books")), serve.setting("DATABASE_URL"));
+    assert_eq!(None, serve.setting("POOL_SIZE"));
+    assert!(parse(&["serve", "--listen", "9000"]).is_err());
+    assert!(parse(&["serve", "--database-url"]).is_err());
+    assert!(parse(&["serve", "--port", "9000"]).is_err());
+    assert_eq!(Ok(Command::Migrate), parse(&["migrate"]));
+    assert_eq!(Ok(Command::Check), parse(&["check"]));
+    assert!(parse(&["check", "--verbose"]).is_err());
+    assert_eq!(Ok(Command::Seed { count: crate::seed::DEFAULT_COUNT, force: false }), parse(&["seed"]));
+    assert_eq!(Ok(Command::Seed { count: 500, force: true }), parse(&["seed", "--force", "--count", "500"]));
+    assert!(parse(&["seed", "--count"]).is_err());
+    assert!(parse(&["seed", "--count", "many"]).is_err());
+    assert_eq!(Ok(Command::Help), parse(&["--help"]));
+    assert!(parse(&["deploy"]).is_err());
+    assert!(parse(&["seed", "now"]).is_err());
+}
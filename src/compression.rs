@@ -0,0 +1,144 @@
+//! Response compression: gzip or deflate, as the client's `Accept-Encoding`
+//! prefers, for bodies worth compressing. The body is compressed as it's
+//! written, so a streamed body is never held in memory for it.
+
+use async_compression::futures::bufread::{GzipEncoder, ZlibEncoder};
+use async_std::io::BufReader;
+use tide::http::Mime;
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+use crate::config::Config;
+
+/// Used when `compression_min_bytes` isn't set: below about a kilobyte
+/// the gzip header and a round of compression cost more than they save.
+pub const DEFAULT_MIN_BYTES: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Gzip,
+    Deflate
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    /// The encoding `accept_encoding` weighs highest, gzip on a tie, or
+    /// `None` when it takes neither.
+    fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut gzip = None;
+        let mut deflate = None;
+        let mut any = None;
+        for entry in accept_encoding.split(',') {
+            let mut params = entry.split(';');
+            let coding = params.next().unwrap_or_default().trim().to_lowercase();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()))
+                .unwrap_or(1.0);
+            match coding.as_str() {
+                "gzip" | "x-gzip" => gzip = Some(quality),
+                "deflate" => deflate = Some(quality),
+                "*" => any = Some(quality),
+                _ => {}
+            }
+        }
+        let gzip = gzip.or(any).unwrap_or(0.0);
+        let deflate = deflate.or(any).unwrap_or(0.0);
+        if gzip > 0.0 && gzip >= deflate {
+            Some(Encoding::Gzip)
+        } else if deflate > 0.0 {
+            Some(Encoding::Deflate)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a body of type `mime` shrinks when compressed: text, JSON and
+/// the like do, while images and archives already are compressed. Server-
+/// Sent Events are left alone too, since the encoder would hold each event
+/// back until it had a block's worth.
+fn compressible(mime: &Mime) -> bool {
+    let subtype = mime.subtype();
+    match mime.basetype() {
+        "text" => subtype != "event-stream",
+        "application" => {
+            matches!(subtype, "json" | "javascript" | "xml" | "x-ndjson" | "ndjson" | "yaml")
+                || subtype.ends_with("+json")
+                || subtype.ends_with("+xml")
+        }
+        "image" => subtype == "svg+xml",
+        _ => false,
+    }
+}
+
+/// Compresses responses of a compressible type and at least `min_bytes`
+/// long, or of unknown length as a stream is, for clients that accept it.
+#[derive(Clone, Copy, Debug)]
+pub struct Compression {
+    min_bytes: usize
+}
+
+impl Compression {
+    pub fn new(min_bytes: usize) -> Self {
+        Compression { min_bytes }
+    }
+
+    /// The threshold from `compression_min_bytes`.
+    pub fn from_config(config: &Config) -> Self {
+        Compression::new(config.compression_min_bytes)
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Compression {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let encoding = req.header("Accept-Encoding").and_then(|values| Encoding::negotiate(values.as_str()));
+        let is_head = req.method() == tide::http::Method::Head;
+        let mut res = next.run(req).await;
+
+        let eligible = res.content_type().is_some_and(|mime| compressible(&mime))
+            && res.header("Content-Encoding").is_none()
+            && !matches!(res.status(), StatusCode::NoContent | StatusCode::NotModified | StatusCode::SwitchingProtocols);
+        if !eligible {
+            return Ok(res);
+        }
+        res.append_header("Vary", "Accept-Encoding");
+        let Some(encoding) = encoding else {
+            return Ok(res);
+        };
+        if is_head || res.len().is_some_and(|len| len < self.min_bytes) {
+            return Ok(res);
+        }
+
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let mut compressed = match encoding {
+            Encoding::Gzip => Body::from_reader(BufReader::new(GzipEncoder::new(body)), None),
+            Encoding::Deflate => Body::from_reader(BufReader::new(ZlibEncoder::new(body)), None),
+        };
+        compressed.set_mime(mime);
+        res.set_body(compressed);
+        res.insert_header("Content-Encoding", encoding.name());
+        Ok(res)
+    }
+}
+
+#[test]
+fn the_preferred_encoding_is_chosen() {
+    assert_eq!(Some(Encoding::Gzip), Encoding::negotiate("gzip, deflate, br"));
+    assert_eq!(Some(Encoding::Deflate), Encoding::negotiate("gzip;q=0.5, deflate"));
+    assert_eq!(Some(Encoding::Deflate), Encoding::negotiate("gzip;q=0, *"));
+    assert_eq!(Some(Encoding::Gzip), Encoding::negotiate("*"));
+    assert_eq!(None, Encoding::negotiate("br, identity"));
+    assert_eq!(None, Encoding::negotiate("gzip;q=0"));
+
+    assert!(compressible(&tide::http::mime::JSON));
+    assert!(compressible(&"application/problem+json".parse().unwrap()));
+    assert!(!compressible(&"text/event-stream".parse().unwrap()));
+    assert!(!compressible(&tide::http::mime::PNG));
+}
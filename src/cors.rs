@@ -0,0 +1,150 @@
+//! CORS for browser clients served from other origins. Off unless
+//! `CORS_ALLOWED_ORIGINS` says which origins to let in.
+
+use tide::http::Method;
+use tide::{Middleware, Next, Request};
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// How long, in seconds, a browser may cache a preflight's answer.
+pub const MAX_AGE: &str = "600";
+
+/// One entry of the allowed origins.
+#[derive(Clone, Debug, PartialEq)]
+enum OriginPattern {
+    /// `*`: any origin.
+    Any,
+    /// `https://app.example.com`: only that origin.
+    Exact(String),
+    /// `https://*.example.com`: any subdomain of `example.com`, but not
+    /// `example.com` itself. Without the scheme any scheme matches.
+    Subdomains { scheme: Option<String>, suffix: String }
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Self {
+        let pattern = pattern.trim().trim_end_matches('/').to_lowercase();
+        if pattern == "*" {
+            return OriginPattern::Any;
+        }
+        let (scheme, host) = match pattern.split_once("://") {
+            Some((scheme, host)) => (Some(scheme.to_owned()), host),
+            None => (None, pattern.as_str()),
+        };
+        match host.strip_prefix('*').filter(|suffix| suffix.starts_with('.')) {
+            Some(suffix) => OriginPattern::Subdomains { scheme, suffix: suffix.to_owned() },
+            None => OriginPattern::Exact(pattern),
+        }
+    }
+
+    /// Whether `origin`, already lowercased, matches. The port is part of
+    /// the host, so `https://*.example.com` doesn't let in
+    /// `https://a.example.com:8443`.
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(allowed) => allowed == origin,
+            OriginPattern::Subdomains { scheme, suffix } => match origin.split_once("://") {
+                Some((origin_scheme, host)) => {
+                    scheme.as_ref().is_none_or(|scheme| scheme == origin_scheme)
+                        && host.len() > suffix.len()
+                        && host.ends_with(suffix.as_str())
+                        && !host.contains('/')
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+/// Reflects an allowed request `Origin` back in
+/// `Access-Control-Allow-Origin`, and answers preflights for it with the
+/// methods the route's `OPTIONS` handler lists in `Allow`.
+///
+/// A preflight from any other origin is refused with `403`. Other requests
+/// from it are still served, since browsers send `Origin` on same-origin
+/// writes too, but without the header the browser keeps the response from
+/// the page.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    patterns: Vec<OriginPattern>
+}
+
+impl Cors {
+    /// `origins` is a comma-separated list of exact origins, `*.`
+    /// subdomain patterns and `*`.
+    pub fn new(origins: &str) -> Self {
+        let patterns = origins.split(',')
+            .filter(|pattern| !pattern.trim().is_empty())
+            .map(OriginPattern::parse)
+            .collect();
+        Cors { patterns }
+    }
+
+    /// The origins in `cors_allowed_origins`, if it's set.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.cors_allowed_origins.as_deref()
+            .filter(|origins| !origins.trim().is_empty())
+            .map(Cors::new)
+    }
+
+    pub fn allows(&self, origin: &str) -> bool {
+        let origin = origin.to_lowercase();
+        self.patterns.iter().any(|pattern| pattern.matches(&origin))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Cors {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let origin = match req.header("Origin") {
+            Some(values) => values.last().as_str().to_owned(),
+            None => return Ok(next.run(req).await),
+        };
+        let preflight = req.method() == Method::Options && req.header("Access-Control-Request-Method").is_some();
+        if !self.allows(&origin) {
+            let mut res = if preflight {
+                AppError::Forbidden(format!("origin {} is not allowed", origin)).into_response()
+            } else {
+                next.run(req).await
+            };
+            res.append_header("Vary", "Origin");
+            return Ok(res);
+        }
+
+        let requested_headers = req.header("Access-Control-Request-Headers").map(|values| values.as_str().to_owned());
+        let mut res = next.run(req).await;
+        res.insert_header("Access-Control-Allow-Origin", origin);
+        res.append_header("Vary", "Origin");
+        if preflight {
+            if let Some(allow) = res.header("Allow").map(|values| values.as_str().to_owned()) {
+                res.insert_header("Access-Control-Allow-Methods", allow);
+            }
+            if let Some(headers) = requested_headers {
+                res.insert_header("Access-Control-Allow-Headers", headers);
+            }
+            res.insert_header("Access-Control-Max-Age", MAX_AGE);
+        }
+        Ok(res)
+    }
+}
+
+#[test]
+fn origins_match_exactly_or_by_subdomain() {
+    let cors = Cors::new("https://app.example.org, https://*.example.com,*.internal");
+    assert!(cors.allows("https://app.example.org"));
+    assert!(cors.allows("https://shop.example.com"));
+    assert!(cors.allows("https://a.b.Example.com"));
+    assert!(cors.allows("http://tools.internal"));
+
+    assert!(!cors.allows("http://app.example.org"));
+    assert!(!cors.allows("https://example.com"));
+    assert!(!cors.allows("https://evilexample.com"));
+    assert!(!cors.allows("https://shop.example.com:8443"));
+    assert!(!cors.allows("https://shop.example.com.evil.net"));
+    assert!(!cors.allows("null"));
+
+    assert!(Cors::new("*").allows("https://anywhere.net"));
+    assert!(!Cors::new("").allows("https://anywhere.net"));
+}
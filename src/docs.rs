@@ -0,0 +1,41 @@
+//! An interactive API explorer (Swagger UI) at `/docs/`, pointed at
+//! `/openapi.json`. The assets are compiled into the binary, so nothing is
+//! fetched at runtime.
+
+use tide::{Request, Response};
+
+use crate::State;
+use crate::error::AppError;
+
+const INDEX_HTML: &str = include_str!("../assets/swagger-ui/index.html");
+const SWAGGER_UI_CSS: &[u8] = include_bytes!("../assets/swagger-ui/swagger-ui.css");
+const SWAGGER_UI_BUNDLE_JS: &[u8] = include_bytes!("../assets/swagger-ui/swagger-ui-bundle.js");
+
+/// `/docs` redirects to `/docs/`: the page refers to its assets and to the
+/// spec relative to its own URL, which keeps it working behind a path
+/// prefix. The `Location` is relative for the same reason.
+pub async fn redirect(_req: Request<State>) -> Result<Response, AppError> {
+    let mut res = Response::new(301);
+    res.insert_header("Location", "docs/");
+    Ok(res)
+}
+
+pub async fn index(_req: Request<State>) -> Result<Response, AppError> {
+    Ok(asset("text/html; charset=utf-8", INDEX_HTML.as_bytes()))
+}
+
+pub async fn stylesheet(_req: Request<State>) -> Result<Response, AppError> {
+    Ok(asset("text/css; charset=utf-8", SWAGGER_UI_CSS))
+}
+
+pub async fn script(_req: Request<State>) -> Result<Response, AppError> {
+    Ok(asset("application/javascript; charset=utf-8", SWAGGER_UI_BUNDLE_JS))
+}
+
+fn asset(content_type: &str, body: &'static [u8]) -> Response {
+    let mut res = Response::new(200);
+    res.set_body(body);
+    res.insert_header("Content-Type", content_type);
+    res.insert_header("Cache-Control", "public, max-age=3600");
+    res
+}
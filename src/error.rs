@@ -0,0 +1,395 @@
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use tide::http::Mime;
+use tide::{Body, Endpoint, Middleware, Next, Request, Response, StatusCode};
+use tracing::Instrument;
+
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::messages::{Language, localize};
+use crate::repository::RepositoryError;
+use crate::telemetry::{handler_span, record_outcome};
+use crate::timeout::{RequestTimeout, with_deadline};
+
+/// Everything a handler can fail with. Each variant maps to one status
+/// code and is rendered as an RFC 7807 problem document by `ProblemDetails`.
+#[derive(Debug)]
+pub enum AppError {
+    /// `id` names the missing resource when there is one.
+    NotFound { detail: String, id: Option<Uuid> },
+    Validation(Vec<FieldError>),
+    /// `id` names the existing resource when there is one.
+    Conflict { detail: String, id: Option<Uuid> },
+    BadRequest(String),
+    /// Answered with `WWW-Authenticate: Bearer`.
+    Unauthorized(String),
+    Forbidden(String),
+    MethodNotAllowed(String),
+    PayloadTooLarge(String),
+    /// `accepted` lists the media types the route reads instead.
+    UnsupportedMediaType { detail: String, accepted: &'static [&'static str] },
+    /// Answered with `Retry-After: retry_after`, in seconds.
+    TooManyRequests { detail: String, retry_after: u64 },
+    Timeout(String),
+    Unavailable(String),
+    Database(sqlx::Error),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::NotFound { .. } => StatusCode::NotFound,
+            AppError::Validation(_) => StatusCode::UnprocessableEntity,
+            AppError::Conflict { .. } => StatusCode::Conflict,
+            AppError::BadRequest(_) => StatusCode::BadRequest,
+            AppError::Unauthorized(_) => StatusCode::Unauthorized,
+            AppError::Forbidden(_) => StatusCode::Forbidden,
+            AppError::MethodNotAllowed(_) => StatusCode::MethodNotAllowed,
+            AppError::PayloadTooLarge(_) => StatusCode::PayloadTooLarge,
+            AppError::UnsupportedMediaType { .. } => StatusCode::UnsupportedMediaType,
+            AppError::TooManyRequests { .. } => StatusCode::TooManyRequests,
+            AppError::Timeout(_) | AppError::Unavailable(_) => StatusCode::ServiceUnavailable,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::InternalServerError,
+        }
+    }
+
+    pub fn invalid_field(field: &str, message: &str) -> Self {
+        AppError::Validation(vec![FieldError {
+            field: field.to_owned(),
+            message: message.to_owned()
+        }])
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::NotFound { .. } => "not_found",
+            AppError::Validation(_) => "validation_error",
+            AppError::Conflict { .. } => "conflict",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::MethodNotAllowed(_) => "method_not_allowed",
+            AppError::PayloadTooLarge(_) => "payload_too_large",
+            AppError::UnsupportedMediaType { .. } => "unsupported_media_type",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::Timeout(_) => "timeout",
+            AppError::Unavailable(_) => "unavailable",
+            AppError::Database(_) => "database_error",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            AppError::NotFound { .. } => "Resource not found",
+            AppError::Validation(_) => "Validation failed",
+            AppError::Conflict { .. } => "Conflict",
+            AppError::BadRequest(_) => "Bad request",
+            AppError::Unauthorized(_) => "Unauthorized",
+            AppError::Forbidden(_) => "Forbidden",
+            AppError::MethodNotAllowed(_) => "Method not allowed",
+            AppError::PayloadTooLarge(_) => "Payload too large",
+            AppError::UnsupportedMediaType { .. } => "Unsupported media type",
+            AppError::TooManyRequests { .. } => "Too many requests",
+            AppError::Timeout(_) => "Request timed out",
+            AppError::Unavailable(_) => "Service unavailable",
+            AppError::Database(_) => "Database error",
+            AppError::Internal(_) => "Internal server error",
+        }
+    }
+
+    /// The message shown to clients. Database errors are only described
+    /// in the server log so connection strings and SQL never leak out.
+    pub fn message(&self) -> String {
+        match self {
+            AppError::Validation(errors) => errors.iter()
+                .map(FieldError::to_string)
+                .collect::<Vec<_>>()
+                .join("; "),
+            AppError::NotFound { detail, .. }
+            | AppError::Conflict { detail, .. }
+            | AppError::UnsupportedMediaType { detail, .. }
+            | AppError::TooManyRequests { detail, .. } => detail.clone(),
+            AppError::BadRequest(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::MethodNotAllowed(message)
+            | AppError::PayloadTooLarge(message)
+            | AppError::Timeout(message)
+            | AppError::Unavailable(message)
+            | AppError::Internal(message) => message.clone(),
+            AppError::Database(_) => String::from("a database error occurred"),
+        }
+    }
+
+    /// A response carrying this error, for `ProblemDetails` to render.
+    pub fn into_response(self) -> Response {
+        let mut res = Response::new(self.status());
+        match self {
+            AppError::Unauthorized(_) => res.insert_header("WWW-Authenticate", "Bearer"),
+            AppError::TooManyRequests { retry_after, .. } => res.insert_header("Retry-After", retry_after.to_string()),
+            _ => {}
+        }
+        res.insert_ext(self);
+        res
+    }
+
+    /// Writes the server-side errors, whose details `message` hides, to
+    /// the log. Called once by whichever middleware renders the error.
+    pub fn log(&self) {
+        match self {
+            AppError::Database(e) => tracing::error!("database error: {}", e),
+            AppError::Internal(message) => tracing::error!("internal error: {}", message),
+            _ => {}
+        }
+    }
+
+    /// The problem document for this error, in `language`.
+    fn to_problem(&self, instance: &str, language: Language) -> Problem {
+        self.log();
+        let errors: Vec<FieldError> = match self {
+            AppError::Validation(errors) => errors.iter()
+                .map(|error| FieldError { field: error.field.clone(), message: localize(&error.message, language) })
+                .collect(),
+            _ => Vec::new(),
+        };
+        let detail = match self {
+            AppError::Validation(_) => errors.iter().map(FieldError::to_string).collect::<Vec<_>>().join("; "),
+            _ => localize(&self.message(), language),
+        };
+        let id = match self {
+            AppError::NotFound { id, .. } | AppError::Conflict { id, .. } => *id,
+            _ => None,
+        };
+        let accepted = match self {
+            AppError::UnsupportedMediaType { accepted, .. } => accepted.iter().map(|mime| mime.to_string()).collect(),
+            _ => Vec::new(),
+        };
+        Problem {
+            problem_type: format!("/problems/{}", self.code().replace('_', "-")),
+            title: localize(self.title(), language),
+            status: self.status().into(),
+            detail,
+            instance: instance.to_owned(),
+            code: Some(self.code().to_owned()),
+            errors,
+            id,
+            accepted
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String
+}
+
+/// An `application/problem+json` document (RFC 7807). `code`, `errors`,
+/// `id` and `accepted` are extension members: a stable machine-readable
+/// error code, the individual field failures of a validation problem, the
+/// id of the resource a 404 was looking for, and the media types a 415
+/// would have taken.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub instance: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accepted: Vec<String>
+}
+
+impl Problem {
+    /// A problem for error responses that don't come from an `AppError`,
+    /// like tide's own 404 for unknown paths.
+    fn from_status(status: StatusCode, instance: &str, language: Language) -> Self {
+        Problem {
+            problem_type: String::from("about:blank"),
+            title: localize(status.canonical_reason(), language),
+            status: status.into(),
+            detail: localize(status.canonical_reason(), language),
+            instance: instance.to_owned(),
+            code: None,
+            errors: Vec::new(),
+            id: None,
+            accepted: Vec::new()
+        }
+    }
+}
+
+/// Renders every non-2xx response as `application/problem+json`, with
+/// `instance` set to the request path, in the language `Accept-Language`
+/// prefers among those in `messages`. HEAD responses stay bodiless.
+pub struct ProblemDetails;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ProblemDetails {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let instance = req.url().path().to_owned();
+        let is_head = req.method() == tide::http::Method::Head;
+        let language = Language::negotiate(req.header("Accept-Language").map(|values| values.as_str()));
+        let mut res = next.run(req).await;
+
+        let error = AsMut::<tide::http::Response>::as_mut(&mut res).ext_mut().remove::<AppError>();
+        let status = res.status();
+        let problem = match error {
+            Some(err) => err.to_problem(&instance, language),
+            None if !(status.is_client_error() || status.is_server_error()) || res.len() != Some(0) => return Ok(res),
+            None => Problem::from_status(status, &instance, language),
+        };
+        res.insert_header("Content-Language", language.tag());
+        res.append_header("Vary", "Accept-Language");
+        if !is_head {
+            res.set_body(Body::from_json(&problem)?);
+            res.set_content_type(Mime::from_str("application/problem+json").unwrap());
+        }
+        Ok(res)
+    }
+}
+
+/// Used when `retry_after_secs` isn't set.
+pub const DEFAULT_RETRY_AFTER_SECS: u64 = 5;
+
+/// Tells clients how many seconds to back off, in `Retry-After`, on every
+/// `503`: a readiness check failing, a timeout, a pool with no free
+/// connection, or a database that can't be reached.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryAfter {
+    secs: u64
+}
+
+impl RetryAfter {
+    pub fn new(secs: u64) -> Self {
+        RetryAfter { secs }
+    }
+
+    /// The delay from `retry_after_secs`.
+    pub fn from_config(config: &Config) -> Self {
+        RetryAfter::new(config.retry_after_secs)
+    }
+
+    /// Adds the header to `res` if it's a `503` that doesn't have one.
+    pub fn apply(self, res: &mut Response) {
+        if res.status() == StatusCode::ServiceUnavailable && res.header("Retry-After").is_none() {
+            res.insert_header("Retry-After", self.secs.to_string());
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RetryAfter {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let mut res = next.run(req).await;
+        self.apply(&mut res);
+        Ok(res)
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Database(e) => write!(f, "database error: {}", e),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<sqlx::Error> for AppError {
+    fn from(err: sqlx::Error) -> Self {
+        RepositoryError::from(err).into()
+    }
+}
+
+impl From<RepositoryError> for AppError {
+    fn from(err: RepositoryError) -> Self {
+        match err {
+            RepositoryError::Conflict => AppError::Conflict { detail: err.to_string(), id: None },
+            RepositoryError::Duplicate(id) => AppError::Conflict { detail: err.to_string(), id: Some(id) },
+            RepositoryError::Unsupported(what) => AppError::Unavailable(what.to_owned()),
+            RepositoryError::Rejected(err) => *err,
+            RepositoryError::Database(sqlx::Error::PoolTimedOut) => {
+                AppError::Unavailable(String::from("no database connection came free in time"))
+            }
+            err if err.is_unreachable() => {
+                tracing::warn!("the database is unreachable: {}", err);
+                AppError::Unavailable(String::from("the datastore is temporarily unavailable"))
+            }
+            err if err.is_timed_out() => AppError::Timeout(String::from("a database query ran past the statement timeout")),
+            RepositoryError::Database(e) => AppError::Database(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for AppError {
+    fn from(err: serde_json::Error) -> Self {
+        AppError::BadRequest(format!("invalid JSON body: {}", err))
+    }
+}
+
+/// Errors raised by tide itself, e.g. from `body_json`, `param` or `query`.
+impl From<tide::Error> for AppError {
+    fn from(err: tide::Error) -> Self {
+        if let Some(e) = err.downcast_ref::<serde_json::Error>() {
+            return AppError::BadRequest(format!("invalid JSON body: {}", e));
+        }
+        if err.status().is_client_error() {
+            AppError::BadRequest(err.to_string())
+        } else {
+            AppError::Internal(err.to_string())
+        }
+    }
+}
+
+/// Adapts a handler returning `Result<Response, AppError>` into a tide
+/// endpoint, bounding it by the request's `RequestTimeout` and turning
+/// errors into responses through `AppError::into_response`. The handler
+/// runs inside its `telemetry::handler_span`, which gets its outcome.
+pub fn endpoint<State, F, Fut>(handler: F) -> impl Endpoint<State>
+where
+    State: Clone + Send + Sync + 'static,
+    F: Fn(Request<State>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response, AppError>> + Send + 'static,
+{
+    let name = std::any::type_name::<F>().rsplit("::").next().unwrap_or_default();
+    move |req: Request<State>| {
+        let timeout = RequestTimeout::of(&req);
+        let route = format!("{} {}", req.method(), req.url().path());
+        let span = handler_span(&req, name);
+        let fut = with_deadline(timeout, route, handler(req));
+        async move {
+            let res = match fut.await {
+                Ok(res) => {
+                    record_outcome(res.status().into(), None);
+                    res
+                }
+                Err(e) => {
+                    record_outcome(e.status().into(), Some(&e));
+                    e.into_response()
+                }
+            };
+            Ok(res)
+        }.instrument(span)
+    }
+}
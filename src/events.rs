@@ -0,0 +1,241 @@
+//! `GET /books/events`: every book change as a Server-Sent Event. The
+//! WebSocket feed at `/ws/books` follows the same `BookEvents`.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_std::channel::{self, Receiver, Sender, TrySendError};
+use serde_json::{Value, json};
+use tide::http::mime;
+use tide::{Body, Request, Response};
+
+use crate::{Book, streaming};
+use crate::config::Config;
+use crate::error::AppError;
+use crate::repository::ImportCounts;
+
+/// Used when `events_keep_alive_secs` isn't set: 15 seconds, well under
+/// the minute most proxies allow an idle connection.
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// How many recent events are kept to replay after `Last-Event-ID`.
+pub const REPLAYED: usize = 256;
+/// How many writes' events a client may fall behind by before it's
+/// dropped.
+pub const BACKLOG: usize = 64;
+
+/// One book change, or an import's summary, with its encoding for the
+/// SSE stream.
+#[derive(Debug)]
+pub struct Event {
+    pub id: u64,
+    /// One of `webhook::EVENTS`.
+    pub event: &'static str,
+    pub subject: Subject,
+    encoded: Vec<u8>
+}
+
+/// What an event is about: the changed book, as it was before a delete,
+/// or, for `books.imported`, what the import did, since it may have
+/// changed too many books to send each.
+#[derive(Debug)]
+pub enum Subject {
+    Book(Book),
+    Import(ImportCounts)
+}
+
+impl Event {
+    /// The book changed, unless this is an import's summary.
+    pub fn book(&self) -> Option<&Book> {
+        match &self.subject {
+            Subject::Book(book) => Some(book),
+            Subject::Import(_) => None,
+        }
+    }
+}
+
+/// The events of one write, in order, as the clients are sent them.
+pub type Batch = Arc<[Arc<Event>]>;
+
+#[derive(Debug, Default)]
+struct Subscribers {
+    next_id: u64,
+    recent: VecDeque<Arc<Event>>,
+    senders: Vec<Sender<Batch>>
+}
+
+/// Fans book changes out to the clients following `GET /books/events`.
+///
+/// Events are numbered from one in the order they're published, and that
+/// number is their SSE `id`. A client reconnecting with `Last-Event-ID`
+/// gets the events after it that are still among the last `REPLAYED`;
+/// older ones, and those from before a restart, are gone. The events of
+/// one write, however many books it changed, reach each client together,
+/// so a client is only disconnected when it's `BACKLOG` writes behind; one
+/// that went away stops being written to at its next event or keep-alive.
+#[derive(Debug)]
+pub struct BookEvents {
+    subscribers: Mutex<Subscribers>,
+    keep_alive: Duration
+}
+
+impl BookEvents {
+    pub fn new(keep_alive: Duration) -> Self {
+        BookEvents { subscribers: Mutex::new(Subscribers::default()), keep_alive }
+    }
+
+    /// Keeps streams alive every `events_keep_alive_secs`.
+    pub fn from_config(config: &Config) -> Self {
+        BookEvents::new(Duration::from_secs(config.events_keep_alive_secs))
+    }
+
+    /// Sends `event`, one of `webhook::EVENTS`, about `book` to every client
+    /// following the stream.
+    pub fn publish(&self, event: &'static str, book: &Book) {
+        self.publish_all([(event, book)]);
+    }
+
+    /// `publish` for every change a write made, sent to each client as one
+    /// `Batch`.
+    pub fn publish_all<'a>(&self, changes: impl IntoIterator<Item = (&'static str, &'a Book)>) {
+        let subjects = changes.into_iter().map(|(event, book)| (event, Subject::Book(book.clone())));
+        self.send(subjects);
+    }
+
+    /// Sends a `books.imported` event with what an import did to every
+    /// client following the stream.
+    pub fn publish_import(&self, counts: &ImportCounts) {
+        self.send([("books.imported", Subject::Import(*counts))]);
+    }
+
+    /// Numbers and keeps the events, then sends them together.
+    fn send(&self, subjects: impl IntoIterator<Item = (&'static str, Subject)>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let mut batch = Vec::new();
+        for (event, subject) in subjects {
+            let data = match &subject {
+                Subject::Book(book) => serde_json::to_string(book),
+                Subject::Import(counts) => serde_json::to_string(counts),
+            };
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    tracing::error!("could not serialize {} for the event stream: {}", event, e);
+                    continue;
+                }
+            };
+            subscribers.next_id += 1;
+            let id = subscribers.next_id;
+            let encoded = format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, data).into_bytes();
+            let event = Arc::new(Event { id, event, subject, encoded });
+            if subscribers.recent.len() == REPLAYED {
+                subscribers.recent.pop_front();
+            }
+            subscribers.recent.push_back(event.clone());
+            batch.push(event);
+        }
+        if batch.is_empty() {
+            return;
+        }
+        let batch: Batch = batch.into();
+        subscribers.senders.retain(|sender| match sender.try_send(batch.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("dropping an event stream client more than {} writes behind", BACKLOG);
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+    }
+
+    /// How long a stream may sit idle before it's sent a keep-alive: an
+    /// SSE comment, or a WebSocket ping.
+    pub fn keep_alive(&self) -> Duration {
+        self.keep_alive
+    }
+
+    /// How many clients are following the stream.
+    pub fn followers(&self) -> usize {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.senders.retain(|sender| !sender.is_closed());
+        subscribers.senders.len()
+    }
+
+    /// The events after `last_id` still kept, and a receiver for the ones
+    /// to come, a write's at a time. Dropping the receiver unsubscribes;
+    /// it's closed when the client falls too far behind.
+    pub fn subscribe(&self, last_id: Option<u64>) -> (Vec<Arc<Event>>, Receiver<Batch>) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let replay = match last_id {
+            Some(last_id) if last_id < subscribers.next_id => subscribers.recent.iter()
+                .filter(|event| event.id > last_id)
+                .cloned()
+                .collect(),
+            _ => Vec::new(),
+        };
+        let (sender, receiver) = channel::bounded(BACKLOG);
+        subscribers.senders.retain(|sender| !sender.is_closed());
+        subscribers.senders.push(sender);
+        (replay, receiver)
+    }
+
+    /// The `text/event-stream` body for a new client. The task feeding it
+    /// ends, unsubscribing, once the client stops reading.
+    fn stream(&self, last_id: Option<u64>) -> Body {
+        let (replay, events) = self.subscribe(last_id);
+        let keep_alive = self.keep_alive;
+        let (sender, receiver) = channel::bounded(1);
+        async_std::task::spawn(async move {
+            for event in replay {
+                if sender.send(event.encoded.clone()).await.is_err() {
+                    return;
+                }
+            }
+            loop {
+                let chunk = match async_std::future::timeout(keep_alive, events.recv()).await {
+                    Ok(Ok(batch)) => batch.iter().flat_map(|event| event.encoded.iter().copied()).collect(),
+                    // Dropped for falling behind.
+                    Ok(Err(_)) => return,
+                    Err(_) => b": keep-alive\n\n".to_vec(),
+                };
+                if sender.send(chunk).await.is_err() {
+                    return;
+                }
+            }
+        });
+        let mut body = streaming::body(receiver);
+        body.set_mime(mime::SSE);
+        body
+    }
+}
+
+pub fn book_events_doc() -> Value {
+    json!({
+        "operationId": "book_events",
+        "parameters": [{
+            "name": "Last-Event-ID",
+            "in": "header",
+            "required": false,
+            "description": "Replays the events after this one that are among the last 256 since the server started; older ones aren't replayed",
+            "schema": {"type": "integer", "format": "int64"}
+        }],
+        "responses": {
+            "200": {
+                "description": "An endless stream of `book.created`, `book.updated` and `book.deleted` events, each with the book as its JSON `data`, `books.imported` events, each with an import's `inserted`, `updated`, `skipped` and `deleted` counts as its `data`, and keep-alive comments in between",
+                "content": {"text/event-stream": {"schema": {"type": "string"}}}
+            }
+        }
+    })
+}
+
+/// Streams book changes until the client disconnects. `Last-Event-ID`
+/// replays what it missed, as far as `BookEvents` still has it.
+pub async fn book_events(req: Request<crate::State>) -> Result<Response, AppError> {
+    let last_id = req.header("Last-Event-ID").and_then(|values| values.last().as_str().trim().parse().ok());
+    let events = &req.state().events;
+    let mut res = Response::new(200);
+    res.insert_header("Cache-Control", "no-cache");
+    res.set_body(events.stream(last_id));
+    tracing::debug!("{} clients following book events", events.followers());
+    Ok(res)
+}
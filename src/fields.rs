@@ -0,0 +1,77 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::AppError;
+
+/// The keys of a serialized `Book`, in the order errors list them.
+pub const BOOK_FIELDS: &[&str] = &["id", "name", "author", "year", "published_date", "publisher", "language", "price", "stock"];
+
+/// A sparse fieldset from `?fields=`: the book keys a client asked for.
+/// `id` is always kept; without the parameter every key is.
+#[derive(Debug, Default)]
+pub struct FieldSet(Option<Vec<String>>);
+
+impl FieldSet {
+    pub fn parse(fields: Option<&str>) -> Result<Self, AppError> {
+        let fields = match fields {
+            Some(fields) => fields,
+            None => return Ok(FieldSet(None)),
+        };
+        let mut selected = vec![String::from("id")];
+        let mut unknown = Vec::new();
+        for field in fields.split(',').map(str::trim).filter(|field| !field.is_empty()) {
+            if !BOOK_FIELDS.contains(&field) {
+                unknown.push(field);
+            } else if !selected.iter().any(|selected| selected == field) {
+                selected.push(field.to_owned());
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(AppError::BadRequest(format!(
+                "unknown fields: {}; valid fields are {}", unknown.join(", "), BOOK_FIELDS.join(", "))));
+        }
+        Ok(FieldSet(Some(selected)))
+    }
+
+    /// The book fields to read from storage.
+    pub fn columns(&self) -> Vec<&str> {
+        match &self.0 {
+            Some(selected) => selected.iter().map(String::as_str).collect(),
+            None => BOOK_FIELDS.to_vec(),
+        }
+    }
+
+    /// Serializes `book`, dropping the keys that weren't asked for.
+    pub fn project(&self, book: &impl Serialize) -> Result<Value, AppError> {
+        let mut value = serde_json::to_value(book)?;
+        if let (Some(selected), Value::Object(map)) = (&self.0, &mut value) {
+            map.retain(|key, _| selected.contains(key));
+        }
+        Ok(value)
+    }
+}
+
+/// Related data to embed from `?include=`, checked against what the
+/// endpoint can embed.
+#[derive(Debug, Default)]
+pub struct Includes(Vec<String>);
+
+impl Includes {
+    pub fn parse(include: Option<&str>, valid: &[&str]) -> Result<Self, AppError> {
+        let requested: Vec<&str> = include.unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .collect();
+        let unknown: Vec<&str> = requested.iter().copied().filter(|item| !valid.contains(item)).collect();
+        if !unknown.is_empty() {
+            return Err(AppError::BadRequest(format!(
+                "unknown includes: {}; valid includes are {}", unknown.join(", "), valid.join(", "))));
+        }
+        Ok(Includes(requested.into_iter().map(str::to_owned).collect()))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|item| item == name)
+    }
+}
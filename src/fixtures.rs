@@ -0,0 +1,133 @@
+//! Known books for tests to start from, built with defaults for whatever a
+//! test doesn't care about:
+//!
+//! `insert_books(repo, &[BookFixture::new("Dune").year(1965)])`
+//!
+//! They go through the repository, so they work on whichever store the
+//! suite runs against; `TestDb` keeps each test's books to itself.
+
+use std::str::FromStr;
+
+use chrono::Utc;
+use rust_decimal::Decimal;
+use serde_json::json;
+use tide::Body;
+use tide::http::mime;
+use uuid::Uuid;
+
+use crate::Book;
+use crate::repository::{BookFilter, BookRepository};
+
+/// A book named `name`, with a fresh id and no other field set until one
+/// of the builder methods sets it.
+#[derive(Clone, Debug)]
+pub struct BookFixture(Book);
+
+impl BookFixture {
+    pub fn new(name: &str) -> Self {
+        BookFixture(Book {
+            id: Uuid::new_v4(),
+            name: Some(name.to_owned()),
+            author: None,
+            year: None,
+            published_date: None,
+            publisher: None,
+            language: None,
+            price: None,
+            stock: None,
+            updated_at: None
+        })
+    }
+
+    pub fn author(mut self, author: &str) -> Self {
+        self.0.author = Some(author.to_owned());
+        self
+    }
+
+    pub fn year(mut self, year: i32) -> Self {
+        self.0.year = Some(year);
+        self
+    }
+
+    pub fn publisher(mut self, publisher: &str) -> Self {
+        self.0.publisher = Some(publisher.to_owned());
+        self
+    }
+
+    pub fn language(mut self, language: &str) -> Self {
+        self.0.language = Some(language.to_owned());
+        self
+    }
+
+    /// `price` as written, as in `"12.50"`.
+    pub fn price(mut self, price: &str) -> Self {
+        self.0.price = Some(Decimal::from_str(price).unwrap());
+        self
+    }
+
+    pub fn stock(mut self, stock: i32) -> Self {
+        self.0.stock = Some(stock);
+        self
+    }
+
+    /// The book, for a test that sends it itself.
+    pub fn build(self) -> Book {
+        self.0
+    }
+}
+
+/// An `/admin/export` document of `books` whose `export` object says it's
+/// of `version` and holds `row_count` books, written first as
+/// `GET /admin/export` writes it.
+pub fn export_document(books: &[Book], version: u32, row_count: usize) -> Body {
+    let export = json!({"version": version, "exported_at": Utc::now(), "row_count": row_count});
+    let mut body = Body::from_string(format!("{{\"export\": {}, \"books\": {}}}", export, json!(books)));
+    body.set_mime(mime::JSON);
+    body
+}
+
+/// Inserts `fixtures` and returns them as stored, in the same order.
+/// Panics, naming the book, when one can't be inserted, which on a fresh
+/// `TestDb` means the schema is missing or out of date.
+pub async fn insert_books(repo: &dyn BookRepository, fixtures: &[BookFixture]) -> Vec<Book> {
+    let mut books = Vec::new();
+    for fixture in fixtures {
+        match repo.create_book(fixture.0.clone()).await {
+            Ok(book) => books.push(book),
+            Err(e) => panic!(
+                "could not insert the fixture book {:?}: {}; has the test database been migrated?",
+                fixture.0.name.as_deref().unwrap_or_default(), e
+            ),
+        }
+    }
+    books
+}
+
+/// Deletes every book, for a test that wants to start over, and returns
+/// how many there were.
+pub async fn truncate_books(repo: &dyn BookRepository) -> usize {
+    let books = repo.list_books(&["id"], &BookFilter::default(), None).await
+        .unwrap_or_else(|e| panic!("could not list the books to truncate: {}; has the test database been migrated?", e));
+    for book in &books {
+        repo.delete_book(book.id).await.unwrap();
+    }
+    books.len()
+}
+
+#[async_std::test]
+async fn fixtures_are_inserted_and_truncated() {
+    crate::test_db::with_test_db(|app| async move {
+        let repo = app.state().repo.as_ref();
+        let books = insert_books(repo, &[
+            BookFixture::new("Dune").author("Frank Herbert").year(1965).publisher("Chilton").language("en").price("9.99").stock(3),
+            BookFixture::new("Emma")
+        ]).await;
+        assert_eq!(Some("Frank Herbert"), books[0].author.as_deref());
+        assert_eq!(Some(Decimal::new(999, 2)), books[0].price);
+        assert_eq!((None, None), (books[1].author.as_deref(), books[1].year));
+        assert_eq!(Some(&books[0]), repo.get_book(books[0].id).await.unwrap().as_ref());
+
+        assert_eq!(2, truncate_books(repo).await);
+        assert_eq!(0, repo.count_books(&BookFilter::default()).await.unwrap());
+    }).await;
+}
@@ -0,0 +1,56 @@
+//! HAL-style `_links` in book responses, so clients follow links rather
+//! than building URLs themselves. Off unless `hal_links` is on, since it
+//! turns the book list from an array into an object.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use serde_json::{Value, json};
+use tide::Request;
+use uuid::Uuid;
+
+use crate::pagination::{Pagination, page_url};
+use crate::public_url::PublicUrl;
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Link {
+    pub href: String
+}
+
+/// Links by relation, such as `self`.
+pub type Links = BTreeMap<&'static str, Link>;
+
+/// A book, or the projection of one, with its `self` and `collection`
+/// links.
+#[derive(Debug, Serialize)]
+pub struct BookResource<T> {
+    #[serde(flatten)]
+    pub book: T,
+    #[serde(rename = "_links")]
+    pub links: Links
+}
+
+/// `book`, which is the book `id`, with links to itself and to the list
+/// it's in.
+pub fn book<State, T>(urls: &PublicUrl, req: &Request<State>, id: Uuid, book: T) -> BookResource<T> {
+    let mut links = Links::new();
+    links.insert("self", Link { href: urls.book(req, id).to_string() });
+    links.insert("collection", Link { href: urls.books(req).to_string() });
+    BookResource { book, links }
+}
+
+/// A page of the book list as a HAL document, with the books, each with
+/// its own links, under `_embedded` and links to the neighbouring pages
+/// when the list is paginated.
+pub fn list<State>(urls: &PublicUrl, req: &Request<State>, books: Vec<(Uuid, Value)>, pagination: Option<Pagination>, total: u64) -> Value {
+    let url = urls.of(req);
+    let mut links = Links::new();
+    links.insert("self", Link { href: url.to_string() });
+    if let Some(pagination) = pagination {
+        for (page, rel) in pagination.relations(total) {
+            links.insert(rel, Link { href: page_url(&url, page).to_string() });
+        }
+    }
+    let books: Vec<BookResource<Value>> = books.into_iter().map(|(id, row)| book(urls, req, id, row)).collect();
+    json!({ "_embedded": { "books": books }, "_links": links })
+}
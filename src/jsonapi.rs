@@ -0,0 +1,192 @@
+//! The opt-in JSON:API format (https://jsonapi.org) for books.
+//!
+//! Clients asking for `application/vnd.api+json` in `Accept` get books as
+//! resource objects and errors as an `errors` array; clients sending a
+//! body with that content type have it unwrapped before the handler reads
+//! it. Everyone else keeps the plain JSON the handlers produce.
+
+use std::str::FromStr;
+
+use serde_json::{Map, Value, json};
+use tide::http::Mime;
+use tide::{Body, Middleware, Next, Request, Response};
+
+use crate::body::read_json;
+use crate::error::AppError;
+use crate::messages::{Language, localize};
+
+pub const MEDIA_TYPE: &str = "application/vnd.api+json";
+
+/// Whether `req` asks for JSON:API documents in `Accept`.
+pub fn requested<State>(req: &Request<State>) -> bool {
+    req.header("Accept")
+        .is_some_and(|values| values.iter().any(|value| value.as_str().contains(MEDIA_TYPE)))
+}
+
+/// Translates the book routes it's registered on to and from JSON:API.
+/// Registered per route, since only book resources have a mapping.
+pub struct JsonApi;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for JsonApi {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let wants_document = requested(&req);
+        let language = Language::negotiate(req.header("Accept-Language").map(|values| values.as_str()));
+        if req.content_type().is_some_and(|mime| mime.essence() == MEDIA_TYPE) {
+            let book = match read_json(&mut req).await.and_then(from_document) {
+                Ok(book) => book,
+                Err(err) => return render(err.into_response(), wants_document, language).await,
+            };
+            req.set_body(Body::from_json(&book)?);
+        }
+        let res = next.run(req).await;
+        render(res, wants_document, language).await
+    }
+}
+
+async fn render(mut res: Response, wants_document: bool, language: Language) -> tide::Result<Response> {
+    res.append_header("Vary", "Accept");
+    if !wants_document {
+        return Ok(res);
+    }
+    let error = AsMut::<tide::http::Response>::as_mut(&mut res).ext_mut().remove::<AppError>();
+    let document = match error {
+        Some(err) => {
+            res.insert_header("Content-Language", language.tag());
+            res.append_header("Vary", "Accept-Language");
+            error_document(&err, language)
+        }
+        None if res.status().is_success() && res.len() != Some(0) => {
+            let total = res.header("X-Total-Count").and_then(|values| values.as_str().parse().ok());
+            to_document(res.take_body().into_json().await?, total)
+        }
+        // Bodiless responses, and errors tide raised itself, which
+        // `ProblemDetails` still renders.
+        None => return Ok(res),
+    };
+    res.set_body(Body::from_json(&document)?);
+    res.set_content_type(Mime::from_str(MEDIA_TYPE).unwrap());
+    Ok(res)
+}
+
+/// The flat book in a document's primary data, with the resource's `id`
+/// among its fields.
+fn from_document(document: Value) -> Result<Value, AppError> {
+    let mut data = match document {
+        Value::Object(mut document) => match document.remove("data") {
+            Some(Value::Object(data)) => data,
+            _ => return Err(AppError::BadRequest(String::from("a JSON:API document needs a `data` object"))),
+        },
+        _ => return Err(AppError::BadRequest(String::from("a JSON:API document must be an object"))),
+    };
+    match data.get("type").and_then(Value::as_str) {
+        Some("book") => {}
+        Some(other) => return Err(AppError::Conflict {
+            detail: format!("expected a resource of type book, not {}", other),
+            id: None
+        }),
+        None => return Err(AppError::invalid_field("type", "is required")),
+    }
+    let mut book = match data.remove("attributes") {
+        Some(Value::Object(attributes)) => attributes,
+        None => Map::new(),
+        Some(_) => return Err(AppError::invalid_field("attributes", "must be an object")),
+    };
+    if let Some(id) = data.remove("id") {
+        book.insert(String::from("id"), id);
+    }
+    Ok(Value::Object(book))
+}
+
+/// `body` as a document: a book is the primary data, a list of them is an
+/// array of it with the page's `count` and the list's `total` in `meta`.
+fn to_document(body: Value, total: Option<u64>) -> Value {
+    match body {
+        Value::Array(books) => {
+            let mut meta = json!({ "count": books.len() });
+            if let Some(total) = total {
+                meta["total"] = json!(total);
+            }
+            json!({ "data": books.into_iter().map(resource).collect::<Vec<_>>(), "meta": meta })
+        }
+        book => json!({ "data": resource(book) }),
+    }
+}
+
+/// A book's resource object. A `RatedBook` nests the book next to its
+/// rating, which goes in the resource's `meta`.
+fn resource(book: Value) -> Value {
+    let mut book = match book {
+        Value::Object(book) => book,
+        other => return other,
+    };
+    let meta = match book.remove("book") {
+        Some(Value::Object(inner)) if !book.contains_key("id") => Some(std::mem::replace(&mut book, inner)),
+        Some(other) => {
+            book.insert(String::from("book"), other);
+            None
+        }
+        None => None,
+    };
+    let id = book.remove("id").unwrap_or(Value::Null);
+    let mut resource = json!({ "type": "book", "id": id, "attributes": book });
+    if let Some(meta) = meta {
+        resource["meta"] = Value::Object(meta);
+    }
+    resource
+}
+
+/// `err` as a JSON:API `errors` array in `language`, one entry per failed
+/// field of a validation error.
+fn error_document(err: &AppError, language: Language) -> Value {
+    err.log();
+    let error = |detail: String, source: Option<Value>| {
+        let mut error = json!({
+            "status": u16::from(err.status()).to_string(),
+            "code": err.code(),
+            "title": localize(err.title(), language),
+            "detail": localize(&detail, language)
+        });
+        if let Some(source) = source {
+            error["source"] = source;
+        }
+        error
+    };
+    let errors = match err {
+        AppError::Validation(fields) => fields.iter()
+            .map(|field| error(field.message.clone(), Some(source(&field.field))))
+            .collect(),
+        _ => vec![error(err.message(), None)],
+    };
+    json!({ "errors": errors })
+}
+
+/// Where a failed field sits: the header it came from, for the names with
+/// a dash, otherwise a pointer into the request document.
+fn source(field: &str) -> Value {
+    match field {
+        "id" | "type" | "attributes" => json!({ "pointer": format!("/data/{}", field) }),
+        header if header.contains('-') => json!({ "header": header }),
+        field => json!({ "pointer": format!("/data/attributes/{}", field) }),
+    }
+}
+
+#[test]
+fn documents_wrap_and_unwrap_books() {
+    let book = json!({ "id": "b", "name": "Dune", "year": 1965 });
+    assert_eq!(
+        json!({ "data": { "type": "book", "id": "b", "attributes": { "name": "Dune", "year": 1965 } } }),
+        to_document(book.clone(), None));
+    assert_eq!(book, from_document(to_document(book.clone(), None)).unwrap());
+
+    let list = to_document(json!([book.clone(), book.clone()]), Some(5));
+    assert_eq!(json!({ "count": 2, "total": 5 }), list["meta"]);
+    assert_eq!("book", list["data"][1]["type"]);
+
+    let rated = to_document(json!({ "book": book, "avg_rating": 4.5, "review_count": 2 }), None);
+    assert_eq!("b", rated["data"]["id"]);
+    assert_eq!(json!({ "avg_rating": 4.5, "review_count": 2 }), rated["data"]["meta"]);
+
+    assert!(matches!(from_document(json!({ "data": { "type": "author" } })), Err(AppError::Conflict { .. })));
+    assert!(matches!(from_document(json!({ "data": [] })), Err(AppError::BadRequest(_))));
+}
@@ -0,0 +1,56 @@
+//! Languages as ISO 639-1 codes, for a book's `language`.
+
+use crate::error::AppError;
+
+/// Every two-letter code in ISO 639-1, sorted.
+pub const ISO_639_1: &[&str] = &[
+    "aa", "ab", "ae", "af", "ak", "am", "an", "ar", "as", "av", "ay", "az",
+    "ba", "be", "bg", "bi", "bm", "bn", "bo", "br", "bs",
+    "ca", "ce", "ch", "co", "cr", "cs", "cu", "cv", "cy",
+    "da", "de", "dv", "dz",
+    "ee", "el", "en", "eo", "es", "et", "eu",
+    "fa", "ff", "fi", "fj", "fo", "fr", "fy",
+    "ga", "gd", "gl", "gn", "gu", "gv",
+    "ha", "he", "hi", "ho", "hr", "ht", "hu", "hy", "hz",
+    "ia", "id", "ie", "ig", "ii", "ik", "io", "is", "it", "iu",
+    "ja", "jv",
+    "ka", "kg", "ki", "kj", "kk", "kl", "km", "kn", "ko", "kr", "ks", "ku", "kv", "kw", "ky",
+    "la", "lb", "lg", "li", "ln", "lo", "lt", "lu", "lv",
+    "mg", "mh", "mi", "mk", "ml", "mn", "mr", "ms", "mt", "my",
+    "na", "nb", "nd", "ne", "ng", "nl", "nn", "no", "nr", "nv", "ny",
+    "oc", "oj", "om", "or", "os",
+    "pa", "pi", "pl", "ps", "pt",
+    "qu",
+    "rm", "rn", "ro", "ru", "rw",
+    "sa", "sc", "sd", "se", "sg", "si", "sk", "sl", "sm", "sn", "so", "sq", "sr", "ss", "st", "su", "sv", "sw",
+    "ta", "te", "tg", "th", "ti", "tk", "tl", "tn", "to", "tr", "ts", "tt", "tw", "ty",
+    "ug", "uk", "ur", "uz",
+    "ve", "vi", "vo",
+    "wa", "wo",
+    "xh",
+    "yi", "yo",
+    "za", "zh", "zu",
+];
+
+/// `language` lowercased, which is how it's stored. Codes that aren't in
+/// ISO 639-1 are a validation error on `field`.
+pub fn normalize(field: &str, language: Option<String>) -> Result<Option<String>, AppError> {
+    let language = match language {
+        Some(language) => language.to_lowercase(),
+        None => return Ok(None),
+    };
+    if ISO_639_1.binary_search(&language.as_str()).is_err() {
+        return Err(AppError::invalid_field(field, "must be an ISO 639-1 language code, such as en"));
+    }
+    Ok(Some(language))
+}
+
+#[test]
+fn codes_are_checked_and_lowercased() {
+    assert!(ISO_639_1.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(183, ISO_639_1.len());
+    assert_eq!(Some(String::from("en")), normalize("language", Some(String::from("EN"))).unwrap());
+    assert_eq!(None, normalize("language", None).unwrap());
+    assert!(normalize("language", Some(String::from("xx"))).is_err());
+    assert!(normalize("language", Some(String::from("eng"))).is_err());
+}
@@ -0,0 +1,40 @@
+//! `Last-Modified` and `If-Modified-Since` for `GET /books/:id`. A book's
+//! last change is its `updated_at`, which every write stamps, so it's read,
+//! and cached, with the book.
+
+use chrono::{DateTime, Utc};
+use tide::Response;
+
+/// `at` as an HTTP date, as in `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(at: DateTime<Utc>) -> String {
+    at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Whether a request's `If-Modified-Since` is no earlier than
+/// `modified_at`, to the second an HTTP date has. A date that doesn't
+/// parse is ignored, as if it weren't sent.
+pub fn unmodified_since(if_modified_since: Option<&str>, modified_at: DateTime<Utc>) -> bool {
+    let Some(since) = if_modified_since.and_then(|value| DateTime::parse_from_rfc2822(value.trim()).ok()) else {
+        return false;
+    };
+    modified_at.timestamp() <= since.timestamp()
+}
+
+/// The body-less `304` for a book last changed at `modified_at`.
+pub fn not_modified(modified_at: DateTime<Utc>) -> Response {
+    let mut res = Response::new(304);
+    res.insert_header("Last-Modified", http_date(modified_at));
+    res
+}
+
+#[test]
+fn dates_are_compared_to_the_second() {
+    let modified_at = DateTime::parse_from_rfc3339("1994-11-06T08:49:37.250Z").unwrap().with_timezone(&Utc);
+    assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", http_date(modified_at));
+
+    assert!(unmodified_since(Some("Sun, 06 Nov 1994 08:49:37 GMT"), modified_at));
+    assert!(unmodified_since(Some("Sun, 06 Nov 1994 09:00:00 GMT"), modified_at));
+    for since in [None, Some("Sun, 06 Nov 1994 08:49:36 GMT"), Some("yesterday")] {
+        assert!(!unmodified_since(since, modified_at), "{:?}", since);
+    }
+}
@@ -0,0 +1,38 @@
+use tide::{Middleware, Next, Request};
+
+use crate::config::Config;
+
+/// Marks responses from the unversioned aliases of the `/v1` routes as
+/// deprecated, pointing clients at the versioned path.
+///
+/// `Sunset` (RFC 8594) is only sent once `legacy_sunset` names the date,
+/// as an HTTP-date, after which the aliases go away.
+#[derive(Clone, Debug)]
+pub struct LegacyAlias {
+    successor_prefix: &'static str,
+    sunset: Option<String>
+}
+
+impl LegacyAlias {
+    pub fn from_config(successor_prefix: &'static str, config: &Config) -> Self {
+        LegacyAlias {
+            successor_prefix,
+            sunset: config.legacy_sunset.clone()
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for LegacyAlias {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let successor = format!("<{}{}>; rel=\"successor-version\"", self.successor_prefix, req.url().path());
+        let mut res = next.run(req).await;
+        res.insert_header("Deprecation", "true");
+        // Appended, so it doesn't replace the handler's own links.
+        res.append_header("Link", successor);
+        if let Some(sunset) = &self.sunset {
+            res.insert_header("Sunset", sunset.as_str());
+        }
+        Ok(res)
+    }
+}
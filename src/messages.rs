@@ -0,0 +1,149 @@
+//! The languages error responses are written in, picked by the request's
+//! `Accept-Language`, and the catalog of their messages.
+//!
+//! Messages are written in English where they're raised and looked up here
+//! by that text when `ProblemDetails` renders them. A `{}` in a catalog
+//! entry stands for one part that varies, such as a number or a field
+//! name, and is carried over. A message the catalog doesn't have stays in
+//! English.
+
+/// The languages error messages come in; `English` is the default.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Language {
+    English,
+    Spanish,
+}
+
+impl Language {
+    /// The language tag, for `Content-Language`.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::Spanish => "es",
+        }
+    }
+
+    /// The most preferred language of `accept_language` that there are
+    /// messages in, going by each range's primary subtag, so `es-MX` is
+    /// Spanish. English when there's none.
+    pub fn negotiate(accept_language: Option<&str>) -> Language {
+        let mut best = (0.0, Language::English);
+        for range in accept_language.unwrap_or("").split(',') {
+            let mut parts = range.split(';');
+            let tag = parts.next().unwrap_or("").trim();
+            let quality = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+                .unwrap_or(0.0);
+            let primary = tag.split('-').next().unwrap_or("").to_ascii_lowercase();
+            let language = match primary.as_str() {
+                "en" => Language::English,
+                "es" => Language::Spanish,
+                _ => continue,
+            };
+            if quality > best.0 {
+                best = (quality, language);
+            }
+        }
+        best.1
+    }
+}
+
+/// Each English message, as raised, and its Spanish translation.
+const SPANISH: &[(&str, &str)] = &[
+    // `AppError::title`
+    ("Resource not found", "Recurso no encontrado"),
+    ("Validation failed", "La validación falló"),
+    ("Conflict", "Conflicto"),
+    ("Bad request", "Solicitud incorrecta"),
+    ("Unauthorized", "No autorizado"),
+    ("Forbidden", "Prohibido"),
+    ("Method not allowed", "Método no permitido"),
+    ("Payload too large", "Cuerpo demasiado grande"),
+    ("Unsupported media type", "Tipo de medio no admitido"),
+    ("Too many requests", "Demasiadas solicitudes"),
+    ("Request timed out", "La solicitud tardó demasiado"),
+    ("Service unavailable", "Servicio no disponible"),
+    ("Database error", "Error de base de datos"),
+    ("Internal server error", "Error interno del servidor"),
+    // The reasons tide's own error responses are titled with.
+    ("Not Found", "No encontrado"),
+    ("Method Not Allowed", "Método no permitido"),
+    ("Internal Server Error", "Error interno del servidor"),
+    // Details.
+    ("book not found", "libro no encontrado"),
+    ("webhook not found", "webhook no encontrado"),
+    ("there are no books", "no hay libros"),
+    ("the admin routes are off; set ADMIN_TOKEN to turn them on", "las rutas de administración están desactivadas; defina ADMIN_TOKEN para activarlas"),
+    ("an admin bearer token is required", "se necesita un token bearer de administración"),
+    ("the book is out of stock", "el libro está agotado"),
+    ("a database error occurred", "se produjo un error de base de datos"),
+    ("a database query ran past the statement timeout", "una consulta a la base de datos superó el tiempo límite"),
+    ("no database connection came free in time", "ninguna conexión a la base de datos quedó libre a tiempo"),
+    ("the database is unreachable", "no se puede acceder a la base de datos"),
+    ("the datastore is temporarily unavailable", "el almacén de datos no está disponible por el momento"),
+    ("still starting up", "todavía se está iniciando"),
+    ("changes must name at least one field", "los cambios deben indicar al menos un campo"),
+    ("set must name at least one field", "set debe indicar al menos un campo"),
+    ("ids must name at least one book", "ids debe indicar al menos un libro"),
+    ("q must not be blank", "q no debe estar en blanco"),
+    ("page counts from 1", "page empieza en 1"),
+    ("per_page must be at least 1", "per_page debe ser al menos 1"),
+    ("page must be at most {}", "page debe ser como mucho {}"),
+    ("request body exceeds {} bytes", "el cuerpo de la solicitud supera los {} bytes"),
+    ("request did not complete within {}", "la solicitud no terminó en {}"),
+    ("invalid JSON body: {}", "cuerpo JSON no válido: {}"),
+    ("origin {} is not allowed", "el origen {} no está permitido"),
+    ("more than {} requests a minute", "más de {} solicitudes por minuto"),
+    // `FieldError` messages.
+    ("is required", "es obligatorio"),
+    ("must not be empty", "no debe estar vacío"),
+    ("must not be negative", "no debe ser negativo"),
+    ("must be between 1 and 5", "debe estar entre 1 y 5"),
+    ("can't be changed", "no se puede cambiar"),
+    ("must be an ISO 639-1 language code, such as en", "debe ser un código de idioma ISO 639-1, como es"),
+    ("must be an http or https URL", "debe ser una URL http o https"),
+    ("must name at least one event", "debe indicar al menos un evento"),
+    ("was already used with a different request body", "ya se usó con otro cuerpo de solicitud"),
+    ("is not a known field", "no es un campo conocido"),
+    ("is not a known field; did you mean {}?", "no es un campo conocido; ¿quisiste decir {}?"),
+];
+
+/// `message`, raised in English, in `language`.
+pub fn localize(message: &str, language: Language) -> String {
+    let catalog = match language {
+        Language::English => return message.to_owned(),
+        Language::Spanish => SPANISH,
+    };
+    for (english, translated) in catalog {
+        match english.split_once("{}") {
+            None if *english == message => return translated.to_string(),
+            None => {}
+            Some((before, after)) => {
+                let varying = message.strip_prefix(before).and_then(|rest| rest.strip_suffix(after));
+                if let Some(varying) = varying.filter(|varying| !varying.is_empty()) {
+                    return translated.replacen("{}", varying, 1);
+                }
+            }
+        }
+    }
+    message.to_owned()
+}
+
+#[test]
+fn messages_are_looked_up_in_the_preferred_language() {
+    assert_eq!(Language::English, Language::negotiate(None));
+    assert_eq!(Language::Spanish, Language::negotiate(Some("es")));
+    assert_eq!(Language::Spanish, Language::negotiate(Some("fr-CH, es-MX;q=0.8, en;q=0.5")));
+    assert_eq!(Language::English, Language::negotiate(Some("es;q=0.2, EN-gb")));
+    assert_eq!(Language::English, Language::negotiate(Some("de, es;q=0")));
+
+    assert_eq!("libro no encontrado", localize("book not found", Language::Spanish));
+    assert_eq!("book not found", localize("book not found", Language::English));
+    assert_eq!("page debe ser como mucho 1000", localize("page must be at most 1000", Language::Spanish));
+    assert_eq!(
+        "no es un campo conocido; ¿quisiste decir year?",
+        localize("is not a known field; did you mean year?", Language::Spanish)
+    );
+    assert_eq!("something new", localize("something new", Language::Spanish));
+}
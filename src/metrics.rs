@@ -0,0 +1,167 @@
+//! `GET /metrics`: the state of the database connection pools, as
+//! Prometheus gauges, and the Redis book cache's counters when there is
+//! one.
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use tide::{Request, Response};
+
+use crate::State;
+use crate::config::Config;
+use crate::error::AppError;
+use crate::repository::{BookRepository, PoolStats, SharedCacheStats, SlowQueryStats};
+use crate::shedding::DbPermits;
+
+/// Used when `pool_sample_interval_ms` isn't set: 1 second.
+pub const DEFAULT_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reads one gauge off a pool's stats.
+type Reading = fn(&PoolStats) -> u32;
+
+/// Each gauge's name, help and reading.
+const GAUGES: [(&str, &str, Reading); 3] = [
+    ("db_pool_connections", "Open connections in the pool, idle or not.", |stats| stats.size),
+    ("db_pool_idle_connections", "Open connections waiting to be acquired.", |stats| stats.idle),
+    ("db_pool_connections_in_use", "Connections acquired by a query or transaction and not yet given back.", PoolStats::in_use)
+];
+
+/// The pools as last sampled. A scrape reports the last sample rather than
+/// asking the pools, so what it sees is at most `interval` old.
+#[derive(Debug)]
+pub struct PoolGauges {
+    latest: Mutex<Vec<PoolStats>>,
+    interval: Duration
+}
+
+impl PoolGauges {
+    pub fn new(interval: Duration) -> Self {
+        PoolGauges { latest: Mutex::new(Vec::new()), interval }
+    }
+
+    /// The gauges sampled every `pool_sample_interval_ms`.
+    pub fn from_config(config: &Config) -> Self {
+        PoolGauges::new(Duration::from_millis(config.pool_sample_interval_ms))
+    }
+
+    pub fn sample(&self, repo: &dyn BookRepository) {
+        *self.latest.lock().unwrap() = repo.pool_stats();
+    }
+
+    /// Samples `repo` now and then every `interval`, until `repo` is
+    /// dropped with the app.
+    pub fn follow(self: &Arc<Self>, repo: &Arc<dyn BookRepository>) {
+        self.sample(repo.as_ref());
+        let gauges = Arc::clone(self);
+        let repo: Weak<dyn BookRepository> = Arc::downgrade(repo);
+        async_std::task::spawn(async move {
+            loop {
+                async_std::task::sleep(gauges.interval).await;
+                match repo.upgrade() {
+                    Some(repo) => gauges.sample(repo.as_ref()),
+                    None => return,
+                }
+            }
+        });
+    }
+
+    /// The last sample in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let latest = self.latest.lock().unwrap();
+        let mut text = String::new();
+        for (name, help, value) in GAUGES {
+            let _ = writeln!(text, "# HELP {} {}\n# TYPE {} gauge", name, help, name);
+            for stats in latest.iter() {
+                let _ = writeln!(text, "{}{{pool=\"{}\"}} {}", name, stats.pool, value(stats));
+            }
+        }
+        text
+    }
+}
+
+/// The load shedding `permits` do, as a Prometheus gauge and counter.
+fn render_shedding(permits: &DbPermits) -> String {
+    let metrics = [
+        ("db_permits", "gauge", "Requests that may use the database at once.", u64::from(permits.permits())),
+        ("db_requests_shed_total", "counter", "Requests answered 503 for want of a database permit.", permits.shed())
+    ];
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(text, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+    }
+    text
+}
+
+/// `stats` as Prometheus counters.
+fn render_shared_cache(stats: SharedCacheStats) -> String {
+    let counters = [
+        ("redis_book_cache_hits_total", "Books get_book found in Redis.", stats.hits),
+        ("redis_book_cache_misses_total", "Books get_book looked for in Redis and read from the database.", stats.misses),
+        ("redis_book_cache_errors_total", "Redis commands that failed, leaving the database to answer.", stats.errors)
+    ];
+    let mut text = String::new();
+    for (name, help, value) in counters {
+        let _ = writeln!(text, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+    }
+    text
+}
+
+/// `stats` as Prometheus counters.
+fn render_slow_queries(stats: SlowQueryStats) -> String {
+    let counters = [
+        ("db_slow_queries_total", "Repository operations slower than SLOW_QUERY_MS.", stats.slow),
+        ("db_very_slow_queries_total", "Repository operations slower than SLOW_QUERY_ERROR_MS.", stats.very_slow)
+    ];
+    let mut text = String::new();
+    for (name, help, value) in counters {
+        let _ = writeln!(text, "# HELP {} {}\n# TYPE {} counter\n{} {}", name, help, name, name, value);
+    }
+    text
+}
+
+pub fn metrics_doc() -> Value {
+    json!({
+        "operationId": "metrics",
+        "responses": {
+            "200": {
+                "description": "The database connection pools' `db_pool_connections`, `db_pool_idle_connections` and `db_pool_connections_in_use` gauges, labelled by `pool`, as sampled at most `POOL_SAMPLE_INTERVAL_MS` ago, the `db_permits` gauge and `db_requests_shed_total` counter of load shedding, the `db_slow_queries_total` and `db_very_slow_queries_total` counters of slow query logging, then the `redis_book_cache_hits_total`, `redis_book_cache_misses_total` and `redis_book_cache_errors_total` counters when `REDIS_URL` is set",
+                "content": {"text/plain": {"schema": {"type": "string"}}}
+            }
+        }
+    })
+}
+
+pub async fn metrics(req: Request<State>) -> Result<Response, AppError> {
+    let mut res = Response::new(200);
+    let mut text = req.state().pool_gauges.render();
+    text.push_str(&render_shedding(&req.state().db_permits));
+    if let Some(stats) = req.state().repo.slow_query_stats() {
+        text.push_str(&render_slow_queries(stats));
+    }
+    if let Some(stats) = req.state().repo.shared_cache_stats() {
+        text.push_str(&render_shared_cache(stats));
+    }
+    res.set_body(text);
+    res.insert_header("Content-Type", "text/plain; version=0.0.4");
+    Ok(res)
+}
+
+#[test]
+fn gauges_are_rendered_per_pool() {
+    let gauges = PoolGauges::new(DEFAULT_SAMPLE_INTERVAL);
+    *gauges.latest.lock().unwrap() = vec![
+        PoolStats { pool: "primary", size: 3, idle: 1 },
+        PoolStats { pool: "replica", size: 1, idle: 1 }
+    ];
+    let text = gauges.render();
+    assert!(text.contains("# TYPE db_pool_connections_in_use gauge\n"));
+    assert!(text.contains("db_pool_connections_in_use{pool=\"primary\"} 2\n"));
+    assert!(text.contains("db_pool_connections_in_use{pool=\"replica\"} 0\n"));
+    assert!(text.contains("db_pool_idle_connections{pool=\"primary\"} 1\n"));
+
+    let text = render_shared_cache(SharedCacheStats { hits: 4, misses: 2, errors: 0 });
+    assert!(text.contains("# TYPE redis_book_cache_hits_total counter\nredis_book_cache_hits_total 4\n"));
+    assert!(text.contains("redis_book_cache_misses_total 2\n"));
+}
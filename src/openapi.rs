@@ -0,0 +1,336 @@
+use serde_json::{json, Value};
+use tide::{Body, Request, Response};
+
+use crate::State;
+use crate::error::AppError;
+
+/// The OpenAPI 3 description of every route registered in `server`.
+///
+/// Each operation is described by a `*_doc` function sitting next to its
+/// handler, so a handler change and its documentation land in the same
+/// place; this only assembles them under their paths.
+pub fn document() -> Value {
+    let id_param = json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "schema": {"type": "string", "format": "uuid"}
+    });
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "crud_test",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "The `/v1` routes are also served without the prefix, as deprecated aliases marked with a `Deprecation` header."
+        },
+        "paths": {
+            "/v1/books": {
+                "get": crate::list_books_doc(),
+                "post": crate::create_book_doc(),
+                "patch": crate::update_books_doc()
+            },
+            "/v1/books/bulk": {
+                "patch": crate::update_books_by_id_doc()
+            },
+            "/v1/books/with-author": {
+                "post": crate::create_book_with_author_doc()
+            },
+            "/v1/books/search": {
+                "get": crate::search_books_doc()
+            },
+            "/v1/books/export": {
+                "get": crate::backup::export_books_doc()
+            },
+            "/v1/books/restore": {
+                "post": crate::backup::restore_books_doc()
+            },
+            "/v1/books/import": {
+                "post": crate::backup::import_books_doc()
+            },
+            "/v1/books/events": {
+                "get": crate::events::book_events_doc()
+            },
+            "/v1/books/random": {
+                "get": crate::random_book_doc()
+            },
+            "/v1/books/{id}": {
+                "parameters": [id_param],
+                "get": crate::get_book_doc(),
+                "head": crate::head_book_doc(),
+                "put": crate::update_book_doc(),
+                "patch": crate::patch_book_doc(),
+                "delete": crate::delete_book_doc()
+            },
+            "/v1/books/{id}/reviews": {
+                "parameters": [id_param],
+                "get": crate::list_reviews_doc(),
+                "post": crate::create_review_doc()
+            },
+            "/v1/books/{id}/checkout": {
+                "parameters": [id_param],
+                "post": crate::checkout_book_doc()
+            },
+            "/v1/books/{id}/history": {
+                "parameters": [id_param],
+                "get": crate::book_history_doc()
+            },
+            "/v1/books/{id}/similar": {
+                "parameters": [id_param],
+                "get": crate::similar_books_doc()
+            },
+            "/v1/webhooks": {
+                "get": crate::list_webhooks_doc(),
+                "post": crate::create_webhook_doc()
+            },
+            "/v1/webhooks/{id}": {
+                "parameters": [id_param],
+                "delete": crate::delete_webhook_doc()
+            },
+            "/openapi.json": {
+                "get": openapi_doc()
+            },
+            "/health": {
+                "get": crate::health_doc()
+            },
+            "/ready": {
+                "get": crate::ready_doc()
+            },
+            "/metrics": {
+                "get": crate::metrics::metrics_doc()
+            },
+            "/admin/export": {
+                "get": crate::backup::admin_export_doc()
+            },
+            "/admin/import": {
+                "post": crate::backup::admin_import_doc()
+            },
+            "/ws/books": {
+                "get": crate::websocket::book_socket_doc()
+            }
+        },
+        "components": {
+            "schemas": {
+                "Book": {
+                    "type": "object",
+                    "required": ["id"],
+                    "properties": {
+                        "id": {"type": "string", "format": "uuid"},
+                        "name": {"type": "string", "nullable": true},
+                        "author": {"type": "string", "nullable": true},
+                        "year": {"type": "integer", "format": "int32", "nullable": true},
+                        "published_date": {"type": "string", "format": "date", "nullable": true},
+                        "publisher": {"type": "string", "nullable": true},
+                        "language": {"type": "string", "description": "ISO 639-1 code, stored lowercase", "minLength": 2, "maxLength": 2, "nullable": true},
+                        "price": {"type": "string", "format": "decimal", "description": "Exact, so it's a string such as \"19.99\"", "nullable": true},
+                        "stock": {"type": "integer", "format": "int32", "minimum": 0, "description": "Copies left to check out", "nullable": true},
+                        "_links": {"$ref": "#/components/schemas/Links"}
+                    }
+                },
+                "Links": {
+                    "type": "object",
+                    "description": "Only with `HAL_LINKS` on: `self` and `collection` for a book; `self` and the neighbouring pages for a list",
+                    "additionalProperties": {
+                        "type": "object",
+                        "required": ["href"],
+                        "properties": {"href": {"type": "string", "format": "uri"}}
+                    }
+                },
+                "BookList": {
+                    "type": "object",
+                    "description": "The book list with `HAL_LINKS` on",
+                    "required": ["_embedded", "_links"],
+                    "properties": {
+                        "_embedded": {
+                            "type": "object",
+                            "properties": {"books": {"type": "array", "items": {"$ref": "#/components/schemas/Book"}}}
+                        },
+                        "_links": {"$ref": "#/components/schemas/Links"}
+                    }
+                },
+                "RatedBook": {
+                    "type": "object",
+                    "required": ["book", "avg_rating", "review_count"],
+                    "properties": {
+                        "book": {"$ref": "#/components/schemas/Book"},
+                        "avg_rating": {"type": "number", "format": "double", "nullable": true},
+                        "review_count": {"type": "integer", "format": "int64"}
+                    }
+                },
+                "ScoredBook": {
+                    "type": "object",
+                    "required": ["book", "score"],
+                    "properties": {
+                        "book": {"$ref": "#/components/schemas/Book"},
+                        "score": {"type": "number", "format": "float", "minimum": 0, "maximum": 1}
+                    }
+                },
+                "Author": {
+                    "type": "object",
+                    "required": ["id", "name"],
+                    "properties": {
+                        "id": {"type": "string", "format": "uuid"},
+                        "name": {"type": "string"}
+                    }
+                },
+                "NewAuthor": {
+                    "type": "object",
+                    "required": ["name"],
+                    "properties": {
+                        "name": {"type": "string", "minLength": 1}
+                    }
+                },
+                "Review": {
+                    "type": "object",
+                    "required": ["id", "book_id", "rating", "created_at"],
+                    "properties": {
+                        "id": {"type": "string", "format": "uuid"},
+                        "book_id": {"type": "string", "format": "uuid"},
+                        "rating": {"type": "integer", "minimum": 1, "maximum": 5},
+                        "text": {"type": "string", "nullable": true},
+                        "created_at": {"type": "string", "format": "date-time"}
+                    }
+                },
+                "AuditEntry": {
+                    "type": "object",
+                    "required": ["book_id", "action", "created_at"],
+                    "properties": {
+                        "book_id": {"type": "string", "format": "uuid"},
+                        "action": {"type": "string", "enum": ["create", "update", "delete"]},
+                        "old": {"allOf": [book_schema()], "nullable": true},
+                        "new": {"allOf": [book_schema()], "nullable": true},
+                        "created_at": {"type": "string", "format": "date-time"}
+                    }
+                },
+                "NewReview": {
+                    "type": "object",
+                    "required": ["rating"],
+                    "properties": {
+                        "rating": {"type": "integer", "minimum": 1, "maximum": 5},
+                        "text": {"type": "string", "nullable": true}
+                    }
+                },
+                "WebhookSubscription": {
+                    "type": "object",
+                    "required": ["id", "url", "events", "created_at"],
+                    "properties": {
+                        "id": {"type": "string", "format": "uuid"},
+                        "url": {"type": "string", "format": "uri"},
+                        "events": {"type": "array", "items": {"type": "string", "enum": crate::webhook::EVENTS}},
+                        "created_at": {"type": "string", "format": "date-time"}
+                    }
+                },
+                "NewWebhookSubscription": {
+                    "type": "object",
+                    "required": ["url", "secret", "events"],
+                    "properties": {
+                        "url": {"type": "string", "format": "uri"},
+                        "secret": {"type": "string", "minLength": 1, "description": "Keys the HMAC-SHA256 of each delivery's body, sent as `X-Webhook-Signature: sha256=<hex>`"},
+                        "events": {"type": "array", "minItems": 1, "items": {"type": "string", "enum": crate::webhook::EVENTS}}
+                    }
+                },
+                "Problem": {
+                    "type": "object",
+                    "required": ["type", "title", "status", "detail", "instance"],
+                    "properties": {
+                        "type": {"type": "string"},
+                        "title": {"type": "string"},
+                        "status": {"type": "integer"},
+                        "detail": {"type": "string"},
+                        "instance": {"type": "string"},
+                        "code": {"type": "string"},
+                        "id": {"type": "string", "format": "uuid"},
+                        "accepted": {"type": "array", "items": {"type": "string"}},
+                        "errors": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "field": {"type": "string"},
+                                    "message": {"type": "string"}
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            "securitySchemes": {
+                "adminToken": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "The `ADMIN_TOKEN` the server was started with"
+                }
+            }
+        }
+    })
+}
+
+pub fn book_schema() -> Value {
+    json!({"$ref": "#/components/schemas/Book"})
+}
+
+pub fn book_body() -> Value {
+    json!({
+        "required": true,
+        "content": {"application/json": {"schema": book_schema()}}
+    })
+}
+
+/// The `Location` of a `201`'s new book.
+pub fn location_header() -> Value {
+    json!({
+        "description": "The absolute URL of the created book",
+        "schema": {"type": "string", "format": "uri"}
+    })
+}
+
+/// The header a list cut off at `MAX_PAGE_SIZE` carries.
+pub fn clamped_header() -> Value {
+    json!({
+        "description": "The `MAX_PAGE_SIZE` the list was cut off at, when it held more",
+        "schema": {"type": "integer", "format": "int32"}
+    })
+}
+
+/// A `200` with a list that may have been cut off at `MAX_PAGE_SIZE`.
+pub fn clamped_list_response(description: &str, schema: Value) -> Value {
+    let mut response = json_response(description, schema);
+    response["headers"] = json!({(crate::pagination::CLAMPED_HEADER): clamped_header()});
+    response
+}
+
+/// A `201` with the `Location` of the book it created.
+pub fn created_response(description: &str, schema: Value) -> Value {
+    let mut response = json_response(description, schema);
+    response["headers"] = json!({"Location": location_header()});
+    response
+}
+
+pub fn json_response(description: &str, schema: Value) -> Value {
+    json!({
+        "description": description,
+        "content": {"application/json": {"schema": schema}}
+    })
+}
+
+pub fn problem_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {"application/problem+json": {"schema": {"$ref": "#/components/schemas/Problem"}}}
+    })
+}
+
+fn openapi_doc() -> Value {
+    json!({
+        "operationId": "openapi",
+        "responses": {
+            "200": {"description": "This document", "content": {"application/json": {}}}
+        }
+    })
+}
+
+pub async fn openapi(_req: Request<State>) -> Result<Response, AppError> {
+    let mut res = Response::new(200);
+    res.set_body(Body::from_json(&document())?);
+    Ok(res)
+}
@@ -0,0 +1,76 @@
+//! OpenTelemetry export, with the `otel` feature: the `tracing` spans are
+//! sent to the OTLP collector the standard `OTEL_EXPORTER_OTLP_*`
+//! variables configure, over HTTP, and a request's span continues the
+//! trace of its `traceparent`.
+
+use std::env;
+
+use opentelemetry::global;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{SdkTracerProvider, Tracer};
+use tide::Request;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Whether `OTEL_EXPORTER_OTLP_ENDPOINT` or its traces-only counterpart
+/// names a collector. Without one nothing is exported.
+pub fn configured() -> bool {
+    ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"].iter().any(|var| env::var_os(var).is_some())
+}
+
+/// The layer exporting the spans, and the provider to shut down to flush
+/// them, when a collector is configured. The service is named by
+/// `OTEL_SERVICE_NAME`, or else after the crate.
+pub fn layer<S>() -> Option<(OpenTelemetryLayer<S, Tracer>, SdkTracerProvider)>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    if !configured() {
+        return None;
+    }
+    let exporter = match SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            // The subscriber the error would be logged to isn't up yet.
+            eprintln!("spans won't be exported: {}", e);
+            return None;
+        }
+    };
+    let mut resource = Resource::builder();
+    if env::var_os("OTEL_SERVICE_NAME").is_none() {
+        resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+    }
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    Some((tracing_opentelemetry::layer().with_tracer(tracer), provider))
+}
+
+/// A request's headers, for the propagator to read `traceparent` and
+/// `tracestate` from.
+struct Headers<'a, State>(&'a Request<State>);
+
+impl<State> Extractor for Headers<'_, State> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.header(key).map(|values| values.last().as_str())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.header_names().map(|name| name.as_str()).collect()
+    }
+}
+
+/// Makes `span` a child of the span `req`'s `traceparent` names, so the
+/// request shows up in the caller's trace. Without one it starts a trace.
+pub fn continue_trace<State>(req: &Request<State>, span: &Span) {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&Headers(req)));
+    let _ = span.set_parent(parent);
+}
@@ -0,0 +1,235 @@
+//! `?page=` and `?per_page=` on the book list, the headers describing
+//! where a page sits in the whole collection, and the cap on how many rows
+//! any list reads at once.
+
+use tide::http::Url;
+use tide::{Middleware, Next, Request, Response};
+
+use crate::config::Config;
+use crate::error::AppError;
+use crate::repository::Page;
+
+/// Used when only `page` is given.
+pub const DEFAULT_PER_PAGE: u32 = 20;
+/// Used when `max_page_size` isn't set.
+pub const DEFAULT_MAX_PAGE_SIZE: u32 = 100;
+/// Set, to the cap, on a list response that holds fewer rows than asked
+/// for because of it.
+pub const CLAMPED_HEADER: &str = "X-Page-Size-Clamped";
+
+/// The most rows any list or search reads from the store at once. A
+/// larger `per_page`, or an unpaginated list that would be longer, is cut
+/// down to it rather than refused, and the response says so in
+/// `CLAMPED_HEADER`. Registered on the app like `BodyLimit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaxPageSize {
+    rows: u32
+}
+
+impl MaxPageSize {
+    pub fn new(rows: u32) -> Self {
+        MaxPageSize { rows: rows.max(1) }
+    }
+
+    /// The cap from `max_page_size`.
+    pub fn from_config(config: &Config) -> Self {
+        MaxPageSize::new(config.max_page_size)
+    }
+
+    /// The cap set on the request, or `DEFAULT_MAX_PAGE_SIZE`.
+    pub fn of<State>(req: &Request<State>) -> Self {
+        req.ext::<MaxPageSize>().copied().unwrap_or(MaxPageSize::new(DEFAULT_MAX_PAGE_SIZE))
+    }
+
+    /// How many rows to ask the store for: one past the cap, so that
+    /// `truncate` can tell whether there were more.
+    pub fn fetch_limit(self) -> u32 {
+        self.rows.saturating_add(1)
+    }
+
+    /// Drops the rows of a `fetch_limit` read past the cap, and says
+    /// whether there were any.
+    pub fn truncate<T>(self, rows: &mut Vec<T>) -> bool {
+        let clamped = rows.len() > self.rows as usize;
+        rows.truncate(self.rows as usize);
+        clamped
+    }
+
+    /// Sets `CLAMPED_HEADER` on `res`.
+    pub fn mark(self, res: &mut Response) {
+        res.insert_header(CLAMPED_HEADER, self.rows.to_string());
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for MaxPageSize {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(*self);
+        Ok(next.run(req).await)
+    }
+}
+
+/// A one-based page number and its size. `clamped` is set when the
+/// `per_page` asked for was over the `MaxPageSize`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pagination {
+    pub page: u32,
+    pub per_page: u32,
+    pub clamped: bool
+}
+
+/// The `page` and `per_page` of `req`'s query, for any endpoint listing a
+/// collection; see `Pagination::parse`.
+pub fn parse_pagination<State>(req: &Request<State>) -> Result<Option<Pagination>, AppError> {
+    let mut page = None;
+    let mut per_page = None;
+    for (key, value) in req.url().query_pairs() {
+        match key.as_ref() {
+            "page" => page = Some(value.into_owned()),
+            "per_page" => per_page = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    Pagination::parse(page.as_deref(), per_page.as_deref(), MaxPageSize::of(req))
+}
+
+impl Pagination {
+    /// `None` when neither parameter was given, in which case the whole
+    /// list is returned. Anything but a whole number is refused, as is a
+    /// `page` of zero or past `u32::MAX`; `per_page` is clamped to `max`,
+    /// however large.
+    pub fn parse(page: Option<&str>, per_page: Option<&str>, max: MaxPageSize) -> Result<Option<Self>, AppError> {
+        if page.is_none() && per_page.is_none() {
+            return Ok(None);
+        }
+        let page = match page {
+            Some(page) => match whole_number("page", page)? {
+                0 => return Err(AppError::BadRequest(String::from("page counts from 1"))),
+                page => u32::try_from(page).map_err(|_| AppError::BadRequest(format!("page must be at most {}", u32::MAX)))?,
+            },
+            None => 1,
+        };
+        let per_page = match per_page {
+            Some(per_page) => match whole_number("per_page", per_page)? {
+                0 => return Err(AppError::BadRequest(String::from("per_page must be at least 1"))),
+                per_page => per_page,
+            },
+            None => DEFAULT_PER_PAGE.into(),
+        };
+        let clamped = per_page > max.rows as u64;
+        Ok(Some(Pagination { page, per_page: per_page.min(max.rows as u64) as u32, clamped }))
+    }
+
+    pub fn to_page(self) -> Page {
+        Page { limit: self.per_page, offset: (self.page as u64 - 1) * self.per_page as u64 }
+    }
+
+    /// The last page holding any of `total` books; an empty list still has
+    /// a first page.
+    pub fn last_page(self, total: u64) -> u32 {
+        total.div_ceil(self.per_page as u64).clamp(1, u32::MAX as u64) as u32
+    }
+
+    /// The `first`, `prev`, `next` and `last` page numbers of `total`
+    /// books, by relation. There is no `prev` on the first page and no
+    /// `next` on the last.
+    pub fn relations(self, total: u64) -> Vec<(u32, &'static str)> {
+        let last = self.last_page(total);
+        let mut links = vec![(1, "first")];
+        if self.page > 1 {
+            links.push((self.page.min(last + 1) - 1, "prev"));
+        }
+        if self.page < last {
+            links.push((self.page + 1, "next"));
+        }
+        links.push((last, "last"));
+        links
+    }
+
+    /// An RFC 5988 `Link` value with the `relations` of `total` books, as
+    /// `url` with its `page` replaced.
+    pub fn link_header(self, url: &Url, total: u64) -> String {
+        self.relations(total).into_iter()
+            .map(|(page, rel)| format!("<{}>; rel=\"{}\"", page_url(url, page), rel))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// `value` as a non-negative integer, saturating at `u64::MAX` so that huge
+/// values stay huge rather than wrapping.
+fn whole_number(name: &str, value: &str) -> Result<u64, AppError> {
+    if value.starts_with('-') && value.len() > 1 && value[1..].bytes().all(|b| b.is_ascii_digit()) {
+        return Err(AppError::BadRequest(format!("{} must not be negative", name)));
+    }
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(AppError::BadRequest(format!("{} must be a whole number, not {:?}", name, value)));
+    }
+    Ok(value.parse().unwrap_or(u64::MAX))
+}
+
+/// `url` with `page` set, keeping every other query parameter.
+pub fn page_url(url: &Url, page: u32) -> Url {
+    let mut url = url.clone();
+    let pairs: Vec<(String, String)> = url.query_pairs()
+        .filter(|(key, _)| key != "page")
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair("page", &page.to_string());
+    url
+}
+
+#[test]
+fn links_stop_at_the_first_and_last_pages() {
+    let url = Url::parse("http://localhost:8080/books?language=en&page=1&per_page=2").unwrap();
+    let link = |page, total| Pagination { page, per_page: 2, clamped: false }.link_header(&url, total);
+
+    assert_eq!(
+        "<http://localhost:8080/books?language=en&per_page=2&page=1>; rel=\"first\", \
+         <http://localhost:8080/books?language=en&per_page=2&page=2>; rel=\"next\", \
+         <http://localhost:8080/books?language=en&per_page=2&page=3>; rel=\"last\"",
+        link(1, 5));
+    assert!(link(2, 5).contains("page=1>; rel=\"prev\"") && link(2, 5).contains("page=3>; rel=\"next\""));
+    assert!(!link(3, 5).contains("rel=\"next\""));
+    assert!(!link(1, 0).contains("rel=\"prev\"") && !link(1, 0).contains("rel=\"next\""));
+    assert!(link(9, 5).contains("page=3>; rel=\"prev\""));
+
+}
+
+#[test]
+fn parameters_are_checked_and_clamped() {
+    let parse = |page, per_page| Pagination::parse(page, per_page, MaxPageSize::new(DEFAULT_MAX_PAGE_SIZE));
+    let bad_request = |result: Result<Option<Pagination>, AppError>| match result {
+        Err(AppError::BadRequest(message)) => message,
+        other => panic!("expected a bad request, got {:?}", other),
+    };
+
+    assert_eq!(None, parse(None, None).unwrap());
+    assert_eq!(Some(Pagination { page: 2, per_page: DEFAULT_PER_PAGE, clamped: false }), parse(Some("2"), None).unwrap());
+    assert_eq!(Some(Pagination { page: 1, per_page: 5, clamped: false }), parse(None, Some("5")).unwrap());
+
+    assert_eq!("page must not be negative", bad_request(parse(Some("-1"), None)));
+    assert_eq!("per_page must not be negative", bad_request(parse(None, Some("-20"))));
+    assert_eq!("page counts from 1", bad_request(parse(Some("0"), None)));
+    assert_eq!("per_page must be at least 1", bad_request(parse(None, Some("0"))));
+    for junk in ["", "two", "1.5", "+3", "0x10", "-"] {
+        assert!(bad_request(parse(Some(junk), None)).contains("whole number"), "{:?}", junk);
+        assert!(bad_request(parse(None, Some(junk))).contains("whole number"), "{:?}", junk);
+    }
+
+    let clamped = |per_page| parse(None, Some(per_page)).unwrap().unwrap();
+    assert_eq!(Pagination { page: 1, per_page: DEFAULT_MAX_PAGE_SIZE, clamped: false }, clamped("100"));
+    assert_eq!(Pagination { page: 1, per_page: DEFAULT_MAX_PAGE_SIZE, clamped: true }, clamped("101"));
+    assert_eq!(DEFAULT_MAX_PAGE_SIZE, clamped("99999999999999999999999").per_page);
+    let small = Pagination::parse(None, Some("20"), MaxPageSize::new(5)).unwrap().unwrap();
+    assert_eq!((5, true), (small.per_page, small.clamped));
+
+    // The furthest page still has an offset that fits.
+    let last = parse(Some("4294967295"), Some("100")).unwrap().unwrap();
+    assert_eq!(Page { limit: 100, offset: (u32::MAX as u64 - 1) * 100 }, last.to_page());
+    assert!(bad_request(parse(Some("4294967296"), None)).contains("at most"));
+    assert!(bad_request(parse(Some("99999999999999999999999"), None)).contains("at most"));
+}
@@ -0,0 +1,150 @@
+//! Partial updates of a book with `PATCH /books/:id`. The body's
+//! `Content-Type` says which patch format it is in.
+
+use serde_json::{Map, Value};
+
+use crate::error::{AppError, FieldError};
+
+/// JSON Merge Patch (RFC 7386): an object of the fields to change.
+pub const MERGE_PATCH: &str = "application/merge-patch+json";
+/// JSON Patch (RFC 6902): a list of operations on the book's fields.
+pub const JSON_PATCH: &str = "application/json-patch+json";
+
+/// The media types `PATCH /books/:id` takes, for its `415`.
+pub const MEDIA_TYPES: &[&str] = &[MERGE_PATCH, JSON_PATCH];
+
+/// Applies the merge patch `patch` to `target`. A key the patch leaves
+/// out is untouched, a `null` removes it and any other value replaces it,
+/// objects being merged key by key. A patch that isn't an object replaces
+/// the whole target.
+pub fn merge(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge(target.entry(key.as_str()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Applies the JSON Patch `operations` to `target`, a book without its
+/// `null` fields, whose top-level `fields` are all a path may name. `add`,
+/// `replace`, `remove` and `test` are supported. An operation that can't
+/// be applied is a `422` naming its index and a failed `test` is a `409`;
+/// either way `target` is left as it was.
+pub fn apply(target: &mut Map<String, Value>, operations: &Value, fields: &[&str]) -> Result<(), AppError> {
+    let operations = operations.as_array()
+        .ok_or_else(|| AppError::BadRequest(String::from("a JSON Patch is an array of operations")))?;
+    let mut patched = target.clone();
+    for (index, operation) in operations.iter().enumerate() {
+        let invalid = |member: &str, message: String| AppError::Validation(vec![FieldError {
+            field: format!("[{}].{}", index, member),
+            message
+        }]);
+        let op = operation.get("op").and_then(Value::as_str)
+            .ok_or_else(|| invalid("op", String::from("is missing")))?;
+        let path = operation.get("path").and_then(Value::as_str)
+            .ok_or_else(|| invalid("path", String::from("is missing")))?;
+        let field = path.strip_prefix('/')
+            .filter(|field| fields.contains(field))
+            .ok_or_else(|| invalid("path", format!("{} is not a book field that can be patched", path)))?;
+        let value = || operation.get("value").cloned()
+            .ok_or_else(|| invalid("value", format!("is needed by {}", op)));
+        match op {
+            "add" => {
+                patched.insert(field.to_owned(), value()?);
+            }
+            "replace" | "remove" if !patched.contains_key(field) => {
+                return Err(invalid("path", format!("{} has no value to {}", path, op)));
+            }
+            "replace" => {
+                patched.insert(field.to_owned(), value()?);
+            }
+            "remove" => {
+                patched.remove(field);
+            }
+            "test" => {
+                let expected = value()?;
+                let actual = patched.get(field).unwrap_or(&Value::Null);
+                if *actual != expected {
+                    return Err(AppError::Conflict {
+                        detail: format!("operation {} failed: {} is {}, not {}", index, path, actual, expected),
+                        id: None
+                    });
+                }
+            }
+            _ => return Err(invalid("op", format!("{} is not one of add, replace, remove or test", op))),
+        }
+    }
+    *target = patched;
+    Ok(())
+}
+
+#[test]
+fn merge_patches_clear_nulls_and_keep_what_is_left_out() {
+    use serde_json::json;
+
+    let mut book = json!({"name": "Dune", "year": 1965, "publisher": "Chilton", "tags": {"genre": "sf", "era": "60s"}});
+    merge(&mut book, &json!({"year": 1966, "publisher": null, "language": "en", "tags": {"era": null}}));
+    assert_eq!(json!({"name": "Dune", "year": 1966, "language": "en", "tags": {"genre": "sf"}}), book);
+
+    merge(&mut book, &json!({}));
+    assert_eq!(json!({"name": "Dune", "year": 1966, "language": "en", "tags": {"genre": "sf"}}), book);
+
+    merge(&mut book, &json!({"tags": ["sf"]}));
+    assert_eq!(json!(["sf"]), book["tags"]);
+
+    let mut book = json!({"name": "Dune"});
+    merge(&mut book, &json!(["not", "an", "object"]));
+    assert_eq!(json!(["not", "an", "object"]), book);
+}
+
+#[test]
+fn json_patches_apply_all_their_operations_or_none() {
+    use serde_json::json;
+
+    let fields = &["name", "year", "publisher"];
+    let book = || json!({"name": "Dune", "year": 1965}).as_object().unwrap().clone();
+    let mut patched = book();
+    apply(&mut patched, &json!([
+        {"op": "test", "path": "/year", "value": 1965},
+        {"op": "replace", "path": "/year", "value": 1966},
+        {"op": "add", "path": "/publisher", "value": "Chilton"},
+        {"op": "remove", "path": "/name"},
+        {"op": "test", "path": "/name", "value": null}
+    ]), fields).unwrap();
+    assert_eq!(json!({"year": 1966, "publisher": "Chilton"}), Value::Object(patched));
+
+    let failure = |operations: Value| {
+        let mut patched = book();
+        let err = apply(&mut patched, &operations, fields).unwrap_err();
+        assert_eq!(book(), patched);
+        match err {
+            AppError::Validation(errors) => format!("{} {}", errors[0].field, errors[0].message),
+            err => format!("{} {}", err.status(), err.message()),
+        }
+    };
+    let replace = json!({"op": "replace", "path": "/year", "value": 2000});
+    assert_eq!("[1].path /id is not a book field that can be patched",
+        failure(json!([replace, {"op": "replace", "path": "/id", "value": 1}])));
+    assert_eq!("[1].path /nonexistent is not a book field that can be patched",
+        failure(json!([replace, {"op": "add", "path": "/nonexistent", "value": 1}])));
+    assert_eq!("[0].path /publisher has no value to replace",
+        failure(json!([{"op": "replace", "path": "/publisher", "value": "Ace"}, replace])));
+    assert_eq!("[1].op move is not one of add, replace, remove or test",
+        failure(json!([replace, {"op": "move", "from": "/name", "path": "/publisher"}])));
+    assert_eq!("[0].value is needed by add", failure(json!([{"op": "add", "path": "/publisher"}])));
+    assert_eq!("409 operation 1 failed: /year is 2000, not 1965",
+        failure(json!([replace, {"op": "test", "path": "/year", "value": 1965}])));
+}
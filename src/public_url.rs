@@ -0,0 +1,89 @@
+//! The URLs clients reach the server at, which aren't the ones it sees
+//! when it runs behind a proxy.
+
+use tide::Request;
+use tide::http::Url;
+use uuid::Uuid;
+
+use crate::config::Config;
+
+#[derive(Clone, Debug, Default)]
+pub struct PublicUrl {
+    external_url: Option<Url>
+}
+
+impl PublicUrl {
+    pub fn new(external_url: Option<Url>) -> Self {
+        PublicUrl { external_url }
+    }
+
+    /// URLs under `external_url` when that's set, and otherwise under the
+    /// one the client used.
+    pub fn from_config(config: &Config) -> Self {
+        PublicUrl::new(config.external_url.clone())
+    }
+
+    /// `req`'s URL as the client sees it. Behind a proxy, that's the scheme
+    /// in `X-Forwarded-Proto` and the host in `X-Forwarded-Host`, unless
+    /// `external_url` overrides both.
+    pub fn of<State>(&self, req: &Request<State>) -> Url {
+        let url = req.url();
+        let mut path = url.path().to_owned();
+        if let Some(query) = url.query() {
+            path = format!("{}?{}", path, query);
+        }
+        if let Some(external_url) = &self.external_url {
+            let base = external_url.as_str().trim_end_matches('/');
+            return Url::parse(&format!("{}{}", base, path)).unwrap_or_else(|_| url.clone());
+        }
+        let forwarded = |name: &str| req.header(name)
+            .and_then(|values| values.last().as_str().split(',').next().map(|value| value.trim().to_owned()))
+            .filter(|value| !value.is_empty());
+        let scheme = forwarded("X-Forwarded-Proto").unwrap_or_else(|| url.scheme().to_owned());
+        let host = forwarded("X-Forwarded-Host")
+            .or_else(|| forwarded("Host"))
+            .or_else(|| url.host_str().map(|host| match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host.to_owned(),
+            }))
+            .unwrap_or_default();
+        Url::parse(&format!("{}://{}{}", scheme, host, path)).unwrap_or_else(|_| url.clone())
+    }
+
+    /// The book list `req`'s path is under, such as `/v1/books` for
+    /// `/v1/books/{id}`, without a query.
+    pub fn books<State>(&self, req: &Request<State>) -> Url {
+        let mut url = self.of(req);
+        let path = url.path().to_owned();
+        let end = path.find("/books").map_or(path.len(), |start| start + "/books".len());
+        url.set_path(&path[..end]);
+        url.set_query(None);
+        url
+    }
+
+    /// The book `id` in the list `req` is under.
+    pub fn book<State>(&self, req: &Request<State>, id: Uuid) -> Url {
+        let mut url = self.books(req);
+        url.path_segments_mut().unwrap().push(&id.to_string());
+        url
+    }
+}
+
+#[test]
+fn urls_follow_the_proxy_or_the_external_url() {
+    let mut req = tide::http::Request::new(tide::http::Method::Get, "http://10.0.0.5:8080/v1/books/7?fields=name");
+    req.insert_header("X-Forwarded-Proto", "https");
+    req.insert_header("X-Forwarded-Host", "books.example.com, 10.0.0.1");
+    let req: Request<()> = req.into();
+
+    let id = Uuid::nil();
+    let urls = PublicUrl::new(None);
+    assert_eq!("https://books.example.com/v1/books/7?fields=name", urls.of(&req).as_str());
+    assert_eq!(format!("https://books.example.com/v1/books/{}", id), urls.book(&req, id).as_str());
+
+    let urls = PublicUrl::new(Some(Url::parse("https://example.com/api/").unwrap()));
+    assert_eq!("https://example.com/api/v1/books", urls.books(&req).as_str());
+
+    let req: Request<()> = tide::http::Request::new(tide::http::Method::Get, "http://localhost:8080/books").into();
+    assert_eq!("http://localhost:8080/books", PublicUrl::new(None).books(&req).as_str());
+}
@@ -0,0 +1,142 @@
+//! Rate limiting: a token bucket per client, so one client can't take the
+//! server for itself. Off unless `RATE_LIMIT_PER_MINUTE` sets the rate.
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use tide::{Middleware, Next, Request};
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// How often buckets that have filled up again are dropped.
+pub const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A client's tokens as of `refilled`.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant
+}
+
+/// Lets each client make `per_minute` requests in a burst, then one every
+/// `60 / per_minute` seconds; a request with no token left is answered
+/// `429` with how long until there is one. A client is its `X-Api-Key`
+/// when that's one of `api_keys` and its IP address otherwise, so making
+/// up keys neither gets a client more requests nor more buckets.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    per_minute: u32,
+    api_keys: Arc<HashSet<String>>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>
+}
+
+impl RateLimit {
+    /// The limit, with a task dropping full buckets every
+    /// `CLEANUP_INTERVAL` until the limit is dropped with the app.
+    pub fn new(per_minute: u32, api_keys: HashSet<String>) -> Self {
+        let limit = RateLimit { per_minute: per_minute.max(1), api_keys: Arc::new(api_keys), buckets: Arc::default() };
+        let buckets: Weak<Mutex<HashMap<String, Bucket>>> = Arc::downgrade(&limit.buckets);
+        let per_minute = limit.per_minute;
+        async_std::task::spawn(async move {
+            loop {
+                async_std::task::sleep(CLEANUP_INTERVAL).await;
+                match buckets.upgrade() {
+                    Some(buckets) => RateLimit::forget_full(&buckets, per_minute, Instant::now()),
+                    None => return,
+                }
+            }
+        });
+        limit
+    }
+
+    /// The limit from `rate_limit_per_minute`, or `None` when it isn't
+    /// set, with a bucket of their own for the comma-separated
+    /// `rate_limit_api_keys`.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let api_keys = config.rate_limit_api_keys.as_deref().unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned)
+            .collect();
+        config.rate_limit_per_minute.map(|per_minute| RateLimit::new(per_minute, api_keys))
+    }
+
+    fn per_second(per_minute: u32) -> f64 {
+        f64::from(per_minute) / 60.0
+    }
+
+    /// Takes one of `client`'s tokens at `now`, or says how many seconds
+    /// until it has one.
+    fn take(&self, client: String, now: Instant) -> Result<(), u64> {
+        let capacity = f64::from(self.per_minute);
+        let rate = RateLimit::per_second(self.per_minute);
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client).or_insert(Bucket { tokens: capacity, refilled: now });
+        let elapsed = now.saturating_duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.refilled = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+        }
+    }
+
+    /// Drops the buckets that would be full by `now`, which are the same
+    /// as no bucket at all.
+    fn forget_full(buckets: &Mutex<HashMap<String, Bucket>>, per_minute: u32, now: Instant) {
+        let capacity = f64::from(per_minute);
+        let rate = RateLimit::per_second(per_minute);
+        buckets.lock().unwrap().retain(|_, bucket| {
+            bucket.tokens + now.saturating_duration_since(bucket.refilled).as_secs_f64() * rate < capacity
+        });
+    }
+}
+
+impl RateLimit {
+    /// Who `req` comes from, for their bucket: a known `X-Api-Key`, or
+    /// else the peer's address rather than `Forwarded`, which any client
+    /// can write.
+    fn client<State>(&self, req: &Request<State>) -> String {
+        if let Some(key) = req.header("X-Api-Key").map(|values| values.last().as_str()).filter(|key| self.api_keys.contains(*key)) {
+            return format!("key:{}", key);
+        }
+        let ip = req.peer_addr()
+            .map(|addr| addr.parse::<SocketAddr>().map(|addr| addr.ip().to_string()).unwrap_or_else(|_| addr.to_owned()));
+        format!("ip:{}", ip.unwrap_or_default())
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RateLimit {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Err(retry_after) = self.take(self.client(&req), Instant::now()) {
+            return Ok(AppError::TooManyRequests {
+                detail: format!("more than {} requests a minute", self.per_minute),
+                retry_after
+            }.into_response());
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+#[async_std::test]
+async fn buckets_refill_and_are_forgotten_when_full() {
+    let limit = RateLimit::new(2, HashSet::new());
+    let start = Instant::now();
+    assert_eq!(Ok(()), limit.take(String::from("a"), start));
+    assert_eq!(Ok(()), limit.take(String::from("a"), start));
+    assert_eq!(Err(30), limit.take(String::from("a"), start));
+    assert_eq!(Ok(()), limit.take(String::from("b"), start));
+    assert_eq!(Ok(()), limit.take(String::from("a"), start + Duration::from_secs(30)));
+
+    RateLimit::forget_full(&limit.buckets, limit.per_minute, start + Duration::from_secs(45));
+    assert_eq!(vec!["a"], limit.buckets.lock().unwrap().keys().map(String::as_str).collect::<Vec<_>>());
+    RateLimit::forget_full(&limit.buckets, limit.per_minute, start + Duration::from_secs(90));
+    assert!(limit.buckets.lock().unwrap().is_empty());
+}
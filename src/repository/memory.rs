@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::RwLock;
+
+use async_std::channel::Sender;
+use sqlx::types::chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{AuditAction, AuditEntry, Author, Book, NewAuthor, NewReview, RatedBook, Review, ScoredBook, WebhookSubscription};
+use super::{BookChange, BookFilter, BookPatch, BookRepository, IdempotencyKey, IdempotentResponse, ImportBatches, ImportCounts, ImportMode, Page, PoolStats, RepositoryError, SharedCacheStats, SlowQueryStats, idempotent_response_body, next_batch};
+
+/// `(scope, key)` of an idempotency key.
+type ScopedKey = (&'static str, String);
+
+#[derive(Debug)]
+struct KeyRecord {
+    book_id: Uuid,
+    response: IdempotentResponse,
+    created_at: DateTime<Utc>
+}
+
+/// Fails with `Duplicate` if `book`'s name and author are taken.
+fn refuse_duplicate(books: &HashMap<Uuid, Book>, book: &Book) -> Result<(), RepositoryError> {
+    let same = |a: &Option<String>, b: &Option<String>| match (a, b) {
+        (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+        _ => false,
+    };
+    match books.values().find(|other| same(&other.name, &book.name) && same(&other.author, &book.author)) {
+        Some(other) => Err(RepositoryError::Duplicate(other.id)),
+        None => Ok(()),
+    }
+}
+
+/// `book` as written now, dated for its `updated_at`.
+fn written(book: Book) -> Book {
+    Book { updated_at: Some(Utc::now()), ..book }
+}
+
+/// Whether `book` meets every condition of `filter`.
+fn matches(book: &Book, filter: &BookFilter) -> bool {
+    filter.published_after.is_none_or(|after| book.published_date.is_some_and(|date| date > after))
+        && filter.name_contains.as_ref().is_none_or(|term| {
+            book.name.as_ref().is_some_and(|name| name.to_lowercase().contains(&term.to_lowercase()))
+        })
+        && filter.publisher.as_ref().is_none_or(|term| {
+            book.publisher.as_ref().is_some_and(|publisher| publisher.to_lowercase().contains(&term.to_lowercase()))
+        })
+        && filter.language.as_ref().is_none_or(|language| book.language.as_ref() == Some(language))
+        && filter.max_price.is_none_or(|max_price| book.price.is_some_and(|price| price <= max_price))
+        && filter.author.as_ref().is_none_or(|term| {
+            book.author.as_ref().is_some_and(|author| author.to_lowercase().contains(&term.to_lowercase()))
+        })
+        && filter.year_min.is_none_or(|year_min| book.year.is_some_and(|year| year >= year_min))
+}
+
+/// Keeps everything in process memory, for tests and for running the
+/// binary with `STORE=memory` when no Postgres is around.
+#[derive(Debug, Default)]
+pub struct InMemoryBookRepository {
+    books: RwLock<HashMap<Uuid, Book>>,
+    reviews: RwLock<HashMap<Uuid, Vec<Review>>>,
+    authors: RwLock<HashMap<Uuid, Author>>,
+    idempotency_keys: RwLock<HashMap<ScopedKey, KeyRecord>>,
+    audit_log: RwLock<Vec<AuditEntry>>,
+    webhooks: RwLock<Vec<WebhookSubscription>>
+}
+
+impl InMemoryBookRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Logs a change. Called while the books are still locked for it.
+    fn audit(&self, action: AuditAction, old: Option<&Book>, new: Option<&Book>) {
+        self.audit_log.write().unwrap().push(AuditEntry {
+            book_id: old.or(new).map(|book| book.id).expect("a change has a book before or after it"),
+            action,
+            old: old.cloned(),
+            new: new.cloned(),
+            created_at: Utc::now()
+        });
+    }
+}
+
+#[tide::utils::async_trait]
+impl BookRepository for InMemoryBookRepository {
+    async fn create_book(&self, book: Book) -> Result<Book, RepositoryError> {
+        let mut books = self.books.write().unwrap();
+        refuse_duplicate(&books, &book)?;
+        match books.entry(book.id) {
+            Entry::Occupied(_) => Err(RepositoryError::Conflict),
+            Entry::Vacant(entry) => {
+                self.audit(AuditAction::Create, None, Some(&book));
+                Ok(entry.insert(written(book)).clone())
+            }
+        }
+    }
+
+    async fn find_idempotent_response(&self, key: &IdempotencyKey) -> Result<Option<IdempotentResponse>, RepositoryError> {
+        let keys = self.idempotency_keys.read().unwrap();
+        let response = keys.get(&(key.scope, key.key.clone()))
+            .filter(|record| record.created_at >= key.expires_before)
+            .map(|record| record.response.clone());
+        Ok(response)
+    }
+
+    async fn create_book_with_key(&self, book: Book, key: &IdempotencyKey) -> Result<Book, RepositoryError> {
+        let mut books = self.books.write().unwrap();
+        let mut keys = self.idempotency_keys.write().unwrap();
+        keys.retain(|_, record| record.created_at >= key.expires_before);
+        refuse_duplicate(&books, &book)?;
+        let scoped_key = (key.scope, key.key.clone());
+        if keys.contains_key(&scoped_key) || books.contains_key(&book.id) {
+            return Err(RepositoryError::Conflict);
+        }
+        keys.insert(scoped_key, KeyRecord {
+            book_id: book.id,
+            response: IdempotentResponse {
+                request_hash: key.request_hash.clone(),
+                response_body: idempotent_response_body(&book)
+            },
+            created_at: Utc::now()
+        });
+        self.audit(AuditAction::Create, None, Some(&book));
+        let book = written(book);
+        books.insert(book.id, book.clone());
+        Ok(book)
+    }
+
+    async fn create_book_with_author(&self, book: Book, author: NewAuthor) -> Result<(Book, Author), RepositoryError> {
+        let mut books = self.books.write().unwrap();
+        refuse_duplicate(&books, &book)?;
+        if books.contains_key(&book.id) {
+            return Err(RepositoryError::Conflict);
+        }
+        let author = Author { id: Uuid::new_v4(), name: author.name };
+        self.authors.write().unwrap().insert(author.id, author.clone());
+        self.audit(AuditAction::Create, None, Some(&book));
+        let book = written(book);
+        books.insert(book.id, book.clone());
+        Ok((book, author))
+    }
+
+    async fn list_books(&self, columns: &[&str], filter: &BookFilter, page: Option<Page>) -> Result<Vec<Book>, RepositoryError> {
+        let mut rows: Vec<Book> = self.books.read().unwrap().values()
+            .filter(|book| matches(book, filter))
+            .map(|book| Book {
+                id: book.id,
+                name: book.name.clone().filter(|_| columns.contains(&"name")),
+                author: book.author.clone().filter(|_| columns.contains(&"author")),
+                year: book.year.filter(|_| columns.contains(&"year")),
+                published_date: book.published_date.filter(|_| columns.contains(&"published_date")),
+                publisher: book.publisher.clone().filter(|_| columns.contains(&"publisher")),
+                language: book.language.clone().filter(|_| columns.contains(&"language")),
+                price: book.price.filter(|_| columns.contains(&"price")),
+                stock: book.stock.filter(|_| columns.contains(&"stock")),
+                updated_at: None
+            })
+            .collect();
+        rows.sort_by_key(|book| book.id);
+        if let Some(page) = page {
+            rows = rows.into_iter().skip(page.offset as usize).take(page.limit as usize).collect();
+        }
+        Ok(rows)
+    }
+
+    async fn count_books(&self, filter: &BookFilter) -> Result<u64, RepositoryError> {
+        Ok(self.books.read().unwrap().values().filter(|book| matches(book, filter)).count() as u64)
+    }
+
+    async fn get_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        Ok(self.books.read().unwrap().get(&id).cloned())
+    }
+
+    async fn search_books(&self, _term: &str, _threshold: f32, _limit: u32) -> Result<Vec<ScoredBook>, RepositoryError> {
+        Err(RepositoryError::Unsupported("fuzzy search needs Postgres with the pg_trgm extension"))
+    }
+
+    async fn random_book(&self) -> Result<Option<Book>, RepositoryError> {
+        let books = self.books.read().unwrap();
+        if books.is_empty() {
+            return Ok(None);
+        }
+        // A v4 UUID is 122 random bits, plenty for picking an index.
+        let index = (Uuid::new_v4().as_u128() % books.len() as u128) as usize;
+        Ok(books.values().nth(index).cloned())
+    }
+
+    async fn similar_books(&self, book: &Book, limit: u32) -> Result<Vec<Book>, RepositoryError> {
+        let Some(author) = book.author.as_ref().map(|author| author.to_lowercase()) else {
+            return Ok(Vec::new());
+        };
+        let mut rows: Vec<Book> = self.books.read().unwrap().values()
+            .filter(|other| other.id != book.id && other.author.as_ref().is_some_and(|other| other.to_lowercase() == author))
+            .cloned()
+            .collect();
+        rows.sort_by_key(|row| row.id);
+        rows.truncate(limit as usize);
+        Ok(rows)
+    }
+
+    async fn book_exists(&self, id: Uuid) -> Result<bool, RepositoryError> {
+        Ok(self.books.read().unwrap().contains_key(&id))
+    }
+
+    async fn get_rated_book(&self, id: Uuid) -> Result<Option<RatedBook>, RepositoryError> {
+        let book = match self.books.read().unwrap().get(&id) {
+            Some(book) => book.clone(),
+            None => return Ok(None),
+        };
+        let reviews = self.reviews.read().unwrap();
+        let ratings: Vec<i32> = reviews.get(&id)
+            .map(|reviews| reviews.iter().map(|review| review.rating).collect())
+            .unwrap_or_default();
+        let review_count = ratings.len() as i64;
+        let avg_rating = if ratings.is_empty() {
+            None
+        } else {
+            Some(ratings.iter().sum::<i32>() as f64 / review_count as f64)
+        };
+        Ok(Some(RatedBook { book, avg_rating, review_count }))
+    }
+
+    async fn update_book(&self, id: Uuid, book: Book) -> Result<Option<Book>, RepositoryError> {
+        self.change_book(id, &|_| Ok(book.clone())).await
+    }
+
+    async fn change_book(&self, id: Uuid, change: &BookChange<'_>) -> Result<Option<Book>, RepositoryError> {
+        let mut books = self.books.write().unwrap();
+        let row = match books.get_mut(&id) {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let old = row.clone();
+        let book = change(old.clone())?;
+        row.name = book.name;
+        row.author = book.author;
+        row.year = book.year;
+        row.published_date = book.published_date;
+        row.publisher = book.publisher;
+        row.language = book.language;
+        row.price = book.price;
+        row.stock = book.stock;
+        row.updated_at = Some(Utc::now());
+        self.audit(AuditAction::Update, Some(&old), Some(row));
+        Ok(Some(row.clone()))
+    }
+
+    async fn upsert_book(&self, id: Uuid, book: Book) -> Result<(Book, bool), RepositoryError> {
+        let mut books = self.books.write().unwrap();
+        let row = written(Book { id, ..book });
+        let old = books.insert(id, row.clone());
+        match &old {
+            Some(old) => self.audit(AuditAction::Update, Some(old), Some(&row)),
+            None => self.audit(AuditAction::Create, None, Some(&row)),
+        }
+        Ok((row, old.is_none()))
+    }
+
+    async fn update_books(&self, filter: &BookPatch, set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        let matches = |book: &Book| {
+            filter.name.as_ref().is_none_or(|name| book.name.as_ref() == Some(name))
+                && filter.author.as_ref().is_none_or(|author| book.author.as_ref() == Some(author))
+                && filter.year.is_none_or(|year| book.year == Some(year))
+                && filter.publisher.as_ref().is_none_or(|publisher| book.publisher.as_ref() == Some(publisher))
+                && filter.language.as_ref().is_none_or(|language| book.language.as_ref() == Some(language))
+                && filter.price.is_none_or(|price| book.price == Some(price))
+                && filter.stock.is_none_or(|stock| book.stock == Some(stock))
+        };
+        let mut updated = Vec::new();
+        for book in self.books.write().unwrap().values_mut().filter(|book| matches(book)) {
+            let new = set.apply(book);
+            if new != *book {
+                self.audit(AuditAction::Update, Some(book), Some(&new));
+            }
+            *book = written(new);
+            updated.push(book.clone());
+        }
+        Ok(updated)
+    }
+
+    async fn update_books_by_id(&self, ids: &[Uuid], set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        let mut books = self.books.write().unwrap();
+        let mut updated = Vec::new();
+        for book in books.values_mut().filter(|book| ids.contains(&book.id)) {
+            let new = set.apply(book);
+            if new != *book {
+                self.audit(AuditAction::Update, Some(book), Some(&new));
+            }
+            *book = written(new);
+            updated.push(book.clone());
+        }
+        Ok(updated)
+    }
+
+    async fn checkout_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let mut books = self.books.write().unwrap();
+        let row = books.get_mut(&id)
+            .filter(|book| book.stock.is_some_and(|stock| stock > 0))
+            .map(|book| {
+                let old = book.clone();
+                book.stock = book.stock.map(|stock| stock - 1);
+                book.updated_at = Some(Utc::now());
+                self.audit(AuditAction::Update, Some(&old), Some(book));
+                book.clone()
+            });
+        Ok(row)
+    }
+
+    async fn delete_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let mut books = self.books.write().unwrap();
+        let removed = books.remove(&id);
+        if let Some(book) = &removed {
+            self.audit(AuditAction::Delete, Some(book), None);
+        }
+        self.reviews.write().unwrap().remove(&id);
+        self.idempotency_keys.write().unwrap().retain(|_, record| record.book_id != id);
+        Ok(removed)
+    }
+
+    async fn export_books(&self, filter: &BookFilter, counted: Sender<u64>, out: Sender<Book>) -> Result<u64, RepositoryError> {
+        let mut books: Vec<Book> = self.books.read().unwrap().values()
+            .filter(|book| matches(book, filter))
+            .cloned()
+            .collect();
+        books.sort_by_key(|book| book.id);
+        // Not wanted by every export.
+        let _ = counted.send(books.len() as u64).await;
+        let mut exported = 0;
+        for book in books {
+            if out.send(book).await.is_err() {
+                break;
+            }
+            exported += 1;
+        }
+        Ok(exported)
+    }
+
+    async fn restore_books(&self, books: Vec<Book>) -> Result<Vec<Book>, RepositoryError> {
+        let mut stored = self.books.write().unwrap();
+        let mut restored = stored.clone();
+        let mut rows = Vec::with_capacity(books.len());
+        for book in books {
+            refuse_duplicate(&restored, &book)?;
+            let row = written(book);
+            if restored.insert(row.id, row.clone()).is_some() {
+                return Err(RepositoryError::Conflict);
+            }
+            rows.push(row);
+        }
+        *stored = restored;
+        for row in &rows {
+            self.audit(AuditAction::Create, None, Some(row));
+        }
+        Ok(rows)
+    }
+
+    async fn import_books(&self, books: ImportBatches, mode: ImportMode) -> Result<ImportCounts, RepositoryError> {
+        // Read in full first: the lock can't be held across the awaits.
+        let mut read = Vec::new();
+        while let Some(batch) = next_batch(&books).await? {
+            read.extend(batch);
+        }
+        let mut stored = self.books.write().unwrap();
+        let mut imported = stored.clone();
+        let mut counts = ImportCounts::default();
+        let mut changes = Vec::new();
+        if mode == ImportMode::Replace {
+            let mut deleted: Vec<Book> = imported.drain().map(|(_, book)| book).collect();
+            deleted.sort_by_key(|book| book.id);
+            counts.deleted = deleted.len() as u64;
+            changes.extend(deleted.into_iter().map(|book| (AuditAction::Delete, Some(book), None)));
+        }
+        for book in read {
+            match imported.get(&book.id) {
+                Some(old) if *old == book => counts.skipped += 1,
+                Some(old) => {
+                    let old = old.clone();
+                    let row = written(book);
+                    counts.updated += 1;
+                    imported.insert(row.id, row.clone());
+                    changes.push((AuditAction::Update, Some(old), Some(row)));
+                }
+                None => {
+                    refuse_duplicate(&imported, &book)?;
+                    let row = written(book);
+                    counts.inserted += 1;
+                    imported.insert(row.id, row.clone());
+                    changes.push((AuditAction::Create, None, Some(row)));
+                }
+            }
+        }
+        // As a database's cascades take them with the deleted books.
+        if mode == ImportMode::Replace {
+            self.reviews.write().unwrap().clear();
+            self.idempotency_keys.write().unwrap().clear();
+        }
+        *stored = imported;
+        for (action, old, new) in &changes {
+            self.audit(*action, old.as_ref(), new.as_ref());
+        }
+        Ok(counts)
+    }
+
+    async fn book_history(&self, id: Uuid, limit: u32) -> Result<Vec<AuditEntry>, RepositoryError> {
+        let log = self.audit_log.read().unwrap();
+        Ok(log.iter().filter(|entry| entry.book_id == id).take(limit as usize).cloned().collect())
+    }
+
+    async fn create_review(&self, book_id: Uuid, review: NewReview) -> Result<Option<Review>, RepositoryError> {
+        let books = self.books.read().unwrap();
+        if !books.contains_key(&book_id) {
+            return Ok(None);
+        }
+        let row = Review {
+            id: Uuid::new_v4(),
+            book_id,
+            rating: review.rating,
+            text: review.text,
+            created_at: Utc::now()
+        };
+        self.reviews.write().unwrap().entry(book_id).or_default().push(row.clone());
+        Ok(Some(row))
+    }
+
+    async fn list_reviews(&self, book_id: Uuid, limit: u32) -> Result<Vec<Review>, RepositoryError> {
+        let reviews = self.reviews.read().unwrap();
+        Ok(reviews.get(&book_id).into_iter().flatten().take(limit as usize).cloned().collect())
+    }
+
+    async fn list_reviews_for_books(&self, book_ids: &[Uuid]) -> Result<Vec<Review>, RepositoryError> {
+        let reviews = self.reviews.read().unwrap();
+        let mut rows: Vec<Review> = book_ids.iter()
+            .filter_map(|book_id| reviews.get(book_id))
+            .flatten()
+            .cloned()
+            .collect();
+        rows.sort_by_key(|review| review.created_at);
+        Ok(rows)
+    }
+
+    async fn create_webhook(&self, subscription: WebhookSubscription) -> Result<WebhookSubscription, RepositoryError> {
+        let mut webhooks = self.webhooks.write().unwrap();
+        if webhooks.iter().any(|existing| existing.id == subscription.id) {
+            return Err(RepositoryError::Conflict);
+        }
+        webhooks.push(subscription.clone());
+        Ok(subscription)
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<WebhookSubscription>, RepositoryError> {
+        Ok(self.webhooks.read().unwrap().clone())
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<Option<WebhookSubscription>, RepositoryError> {
+        let mut webhooks = self.webhooks.write().unwrap();
+        Ok(webhooks.iter().position(|subscription| subscription.id == id).map(|index| webhooks.remove(index)))
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        Ok(())
+    }
+
+    async fn migration_version(&self) -> Result<Option<i64>, RepositoryError> {
+        Ok(None)
+    }
+
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        Vec::new()
+    }
+
+    fn shared_cache_stats(&self) -> Option<SharedCacheStats> {
+        None
+    }
+
+    fn slow_query_stats(&self) -> Option<SlowQueryStats> {
+        None
+    }
+}
@@ -0,0 +1,829 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use async_std::channel::{self, Receiver, Sender};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, NaiveDate, Utc};
+use uuid::Uuid;
+
+use crate::{AuditAction, AuditEntry, Author, Book, NewAuthor, NewReview, RatedBook, Review, ScoredBook, WebhookSubscription};
+use crate::error::AppError;
+use crate::fields::BOOK_FIELDS;
+
+mod memory;
+#[cfg(feature = "mysql")]
+mod mysql;
+mod postgres;
+#[cfg(feature = "redis")]
+mod redis;
+pub(crate) mod retry;
+pub(crate) mod slow_query;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+pub use memory::InMemoryBookRepository;
+#[cfg(feature = "mysql")]
+pub use mysql::MySqlBookRepository;
+pub use postgres::PgBookRepository;
+#[cfg(feature = "redis")]
+pub use redis::{RedisCached, RedisClient};
+pub use retry::RetryTransient;
+pub use slow_query::SlowQueryLog;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteBookRepository;
+
+/// Storage operations backing the book and review handlers.
+///
+/// `list_books` returns books ordered by id and `list_reviews` returns
+/// reviews oldest first, whichever backend is in use. Every write to a book
+/// records an `AuditEntry` in the same transaction, so the log can't miss
+/// a change or record one that was rolled back.
+#[tide::utils::async_trait]
+pub trait BookRepository: fmt::Debug + Send + Sync + 'static {
+    /// A book with the same name and author, compared case-insensitively,
+    /// is a `Duplicate` carrying the existing book's id.
+    async fn create_book(&self, book: Book) -> Result<Book, RepositoryError>;
+    /// What was recorded for `key`, unless it has expired.
+    async fn find_idempotent_response(&self, key: &IdempotencyKey) -> Result<Option<IdempotentResponse>, RepositoryError>;
+    /// Creates the book and records `key` with the created book as its
+    /// response, atomically, purging expired keys on the way. A live record
+    /// of the key is a `Conflict`, and duplicates are refused as in
+    /// `create_book`.
+    async fn create_book_with_key(&self, book: Book, key: &IdempotencyKey) -> Result<Book, RepositoryError>;
+    /// Creates the author and the book as written by them in one
+    /// transaction, so a failure leaves neither behind. Duplicates are
+    /// refused as in `create_book`.
+    async fn create_book_with_author(&self, book: Book, author: NewAuthor) -> Result<(Book, Author), RepositoryError>;
+    /// The books matching `filter`, or just `page` of them. Only reads
+    /// `columns` (and `id`); the other fields come back `None`.
+    async fn list_books(&self, columns: &[&str], filter: &BookFilter, page: Option<Page>) -> Result<Vec<Book>, RepositoryError>;
+    /// How many books match `filter`, across all pages.
+    async fn count_books(&self, filter: &BookFilter) -> Result<u64, RepositoryError>;
+    /// The books whose name or author is at least `threshold` similar to
+    /// `term` by trigrams, best match first. Only Postgres with `pg_trgm`
+    /// can answer this; the other stores fail with `Unsupported`. At most
+    /// `limit` are returned.
+    async fn search_books(&self, term: &str, threshold: f32, limit: u32) -> Result<Vec<ScoredBook>, RepositoryError>;
+    async fn get_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError>;
+    /// A uniformly chosen book, or `None` when there are none.
+    async fn random_book(&self) -> Result<Option<Book>, RepositoryError>;
+    async fn book_exists(&self, id: Uuid) -> Result<bool, RepositoryError>;
+    /// Up to `limit` other books like `book`, ordered by id: those by the
+    /// same author, compared case-insensitively. A book without an author
+    /// has none.
+    async fn similar_books(&self, book: &Book, limit: u32) -> Result<Vec<Book>, RepositoryError>;
+    /// Like `get_book`, with the average rating and count of its reviews.
+    async fn get_rated_book(&self, id: Uuid) -> Result<Option<RatedBook>, RepositoryError>;
+    async fn update_book(&self, id: Uuid, book: Book) -> Result<Option<Book>, RepositoryError>;
+    /// Replaces the book with `id` by what `change` makes of it, reading
+    /// and writing it in one transaction with the row locked, so no other
+    /// write lands in between. `None` if there's no such book.
+    async fn change_book(&self, id: Uuid, change: &BookChange<'_>) -> Result<Option<Book>, RepositoryError>;
+    /// Inserts the book under `id`, or replaces it if it exists. The flag
+    /// is `true` when a new row was inserted.
+    async fn upsert_book(&self, id: Uuid, book: Book) -> Result<(Book, bool), RepositoryError>;
+    /// Applies `set` to every book whose fields equal all of `filter`'s, in
+    /// one UPDATE, and returns the books it changed as they are afterwards.
+    /// An empty filter matches every book.
+    async fn update_books(&self, filter: &BookPatch, set: &BookPatch) -> Result<Vec<Book>, RepositoryError>;
+    /// Applies `set` to the books with the given `ids` in one transaction
+    /// and returns those of them that exist, as they are afterwards. Ids
+    /// without a book are skipped.
+    async fn update_books_by_id(&self, ids: &[Uuid], set: &BookPatch) -> Result<Vec<Book>, RepositoryError>;
+    /// Takes one copy of the book out of stock in a single conditional
+    /// UPDATE, so concurrent checkouts can't oversell. `None` when the
+    /// book doesn't exist or has none left; a book whose stock was never
+    /// set has none.
+    async fn checkout_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError>;
+    /// The deleted book, or `None` when there was none.
+    async fn delete_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError>;
+    /// Sends how many books `filter` lets through to `counted`, then every
+    /// one of them, ordered by id, to `out` as it's read, without holding
+    /// them all at once. The count and the books are read from one
+    /// snapshot, so the count is what's sent even with writes going on.
+    /// Stops early, without an error, once `out` is closed. Returns how
+    /// many books were sent.
+    async fn export_books(&self, filter: &BookFilter, counted: Sender<u64>, out: Sender<Book>) -> Result<u64, RepositoryError>;
+    /// Creates all of `books` in one transaction, as an export wrote them.
+    /// A book that's already there, by id or by name and author, fails the
+    /// whole restore as in `create_book`. Returns the books as written.
+    async fn restore_books(&self, books: Vec<Book>) -> Result<Vec<Book>, RepositoryError>;
+    /// Writes the books of an import, as an export wrote them, in one
+    /// transaction, taking them from `books` a batch at a time as they're
+    /// read and writing each batch in a few multi-row statements. In
+    /// `Merge` they're inserted or replace the books with their ids; in
+    /// `Replace` every book is deleted first, `IMPORT_BATCH` at a time. A
+    /// book stored as it already was is skipped, and a new one whose name
+    /// and author are taken fails the whole import as in `create_book`.
+    /// Only the counts are kept, so an import's books are never all held
+    /// at once.
+    async fn import_books(&self, books: ImportBatches, mode: ImportMode) -> Result<ImportCounts, RepositoryError>;
+    /// The first `limit` changes recorded for the book, oldest first. The
+    /// log outlives the book, so a deleted book's history ends with its
+    /// deletion.
+    async fn book_history(&self, id: Uuid, limit: u32) -> Result<Vec<AuditEntry>, RepositoryError>;
+
+    /// Returns `None` when the book being reviewed doesn't exist.
+    async fn create_review(&self, book_id: Uuid, review: NewReview) -> Result<Option<Review>, RepositoryError>;
+    /// The book's first `limit` reviews.
+    async fn list_reviews(&self, book_id: Uuid, limit: u32) -> Result<Vec<Review>, RepositoryError>;
+    /// The reviews of all of `book_ids` in one query, oldest first.
+    async fn list_reviews_for_books(&self, book_ids: &[Uuid]) -> Result<Vec<Review>, RepositoryError>;
+
+    async fn create_webhook(&self, subscription: WebhookSubscription) -> Result<WebhookSubscription, RepositoryError>;
+    /// Every webhook subscription, oldest first.
+    async fn list_webhooks(&self) -> Result<Vec<WebhookSubscription>, RepositoryError>;
+    /// The deleted subscription, or `None` when there was none.
+    async fn delete_webhook(&self, id: Uuid) -> Result<Option<WebhookSubscription>, RepositoryError>;
+
+    /// Succeeds when the store can answer a query, for readiness checks.
+    async fn ping(&self) -> Result<(), RepositoryError>;
+    /// The version of the newest migration applied to the store, as sqlx
+    /// numbers them; `None` before its migrations table exists, or for a
+    /// store without migrations.
+    async fn migration_version(&self) -> Result<Option<i64>, RepositoryError>;
+    /// The state of the store's connection pools right now, for `/metrics`;
+    /// none for a store without one.
+    fn pool_stats(&self) -> Vec<PoolStats>;
+    /// How the Redis book cache in front of the store has done, for
+    /// `/metrics`; `None` without one.
+    fn shared_cache_stats(&self) -> Option<SharedCacheStats>;
+    /// How many operations `SlowQueryLog` has logged as slow, for
+    /// `/metrics`; `None` without one.
+    fn slow_query_stats(&self) -> Option<SlowQueryStats>;
+}
+
+/// The SELECT list for reading only `columns` of a book, with the others
+/// selected as NULL so rows still decode into `Book`. Anything that isn't
+/// a book column is ignored, which keeps the names safe to splice in.
+fn select_list(columns: &[&str]) -> String {
+    BOOK_FIELDS.iter()
+        .map(|field| if *field == "id" || columns.contains(field) {
+            field.to_string()
+        } else {
+            format!("NULL AS {}", field)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Which books `list_books` returns; every condition that is set must hold.
+#[derive(Debug, Default)]
+pub struct BookFilter {
+    /// Only books published after this date.
+    pub published_after: Option<NaiveDate>,
+    /// Only books whose name contains this, ignoring case. `%` and `_` are
+    /// matched literally.
+    pub name_contains: Option<String>,
+    /// Only books whose publisher contains this, ignoring case, matched
+    /// like `name_contains`.
+    pub publisher: Option<String>,
+    /// Only books in this language, as a lowercase ISO 639-1 code.
+    pub language: Option<String>,
+    /// Only books priced at most this. Books without a price are left out.
+    pub max_price: Option<Decimal>,
+    /// Only books whose author contains this, matched like `name_contains`.
+    pub author: Option<String>,
+    /// Only books from this year or later.
+    pub year_min: Option<i32>
+}
+
+/// How a backend spells the conditions `filter_sql` builds.
+struct Dialect {
+    /// As for `bulk_update_sql`.
+    placeholder: fn(usize) -> String,
+    /// `contains(column, parameter)` is a case-insensitive substring match,
+    /// with `!` as the escape character; the term is bound through
+    /// `like_escape`.
+    contains: fn(&str, &str) -> String,
+    /// `at_most(column, parameter)` compares them as decimals.
+    at_most: fn(&str, &str) -> String
+}
+
+/// The WHERE clause for `filter`, empty when it has no conditions. Values
+/// are bound in field order.
+fn filter_sql(filter: &BookFilter, dialect: &Dialect) -> String {
+    let Dialect { placeholder, contains, at_most } = dialect;
+    let mut conditions = Vec::new();
+    if filter.published_after.is_some() {
+        conditions.push(format!("published_date > {}", placeholder(conditions.len() + 1)));
+    }
+    if filter.name_contains.is_some() {
+        conditions.push(contains("name", &placeholder(conditions.len() + 1)));
+    }
+    if filter.publisher.is_some() {
+        conditions.push(contains("publisher", &placeholder(conditions.len() + 1)));
+    }
+    if filter.language.is_some() {
+        conditions.push(format!("language = {}", placeholder(conditions.len() + 1)));
+    }
+    if filter.max_price.is_some() {
+        conditions.push(at_most("price", &placeholder(conditions.len() + 1)));
+    }
+    if filter.author.is_some() {
+        conditions.push(contains("author", &placeholder(conditions.len() + 1)));
+    }
+    if filter.year_min.is_some() {
+        conditions.push(format!("year >= {}", placeholder(conditions.len() + 1)));
+    }
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+/// `term` with LIKE's wildcards, and the `!` escaping them, escaped.
+fn like_escape(term: &str) -> String {
+    let mut escaped = String::with_capacity(term.len());
+    for c in term.chars() {
+        if matches!(c, '!' | '%' | '_') {
+            escaped.push('!');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The books `list_books` returns when it's paginated: `limit` of them,
+/// after skipping `offset`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Page {
+    pub limit: u32,
+    pub offset: u64
+}
+
+/// How `import_books` treats the books already stored.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportMode {
+    /// Upserts by id, leaving the books the import doesn't hold as they are.
+    #[default]
+    Merge,
+    /// Empties the table before loading it.
+    Replace
+}
+
+/// How many books `Replace` deletes, and an import writes, in one
+/// statement.
+pub const IMPORT_BATCH: u32 = 500;
+
+/// The books of an import as they're read, `IMPORT_BATCH` or fewer at a
+/// time. An empty batch ends them; an `Err`, or the sender going away
+/// before the end, abandons the import with nothing written.
+pub type ImportBatches = Receiver<Result<Vec<Book>, RepositoryError>>;
+
+/// `books`, already read in full, as the batches of an import.
+pub fn import_batches(books: Vec<Book>) -> ImportBatches {
+    let (sender, batches) = channel::unbounded();
+    let mut books = books.into_iter().peekable();
+    while books.peek().is_some() {
+        let batch = books.by_ref().take(IMPORT_BATCH as usize).collect();
+        sender.try_send(Ok(batch)).expect("an unbounded channel takes every batch");
+    }
+    sender.try_send(Ok(Vec::new())).expect("an unbounded channel takes every batch");
+    batches
+}
+
+/// The next batch of an import's books, or `None` once they've ended.
+async fn next_batch(books: &ImportBatches) -> Result<Option<Vec<Book>>, RepositoryError> {
+    match books.recv().await {
+        Ok(Ok(batch)) if batch.is_empty() => Ok(None),
+        Ok(Ok(batch)) => Ok(Some(batch)),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(RepositoryError::Rejected(Box::new(AppError::BadRequest(String::from("the import's books ended early"))))),
+    }
+}
+
+/// `batch` split before each book whose id is already in the run being
+/// built, so that no multi-row write names a book twice.
+fn distinct_runs(batch: Vec<Book>) -> Vec<Vec<Book>> {
+    let mut runs = vec![Vec::new()];
+    let mut ids = HashSet::new();
+    for book in batch {
+        if !ids.insert(book.id) {
+            runs.push(Vec::new());
+            ids.clear();
+            ids.insert(book.id);
+        }
+        runs.last_mut().expect("there's always a run").push(book);
+    }
+    runs
+}
+
+/// Fails with `Duplicate` if two of `books`, new books written in one
+/// statement, have the same name and author, as the second one's create
+/// would have failed.
+fn refuse_repeats(books: &[&Book]) -> Result<(), RepositoryError> {
+    let mut pairs = HashMap::new();
+    for book in books {
+        if let (Some(name), Some(author)) = (&book.name, &book.author) {
+            if let Some(first) = pairs.insert((name.to_lowercase(), author.to_lowercase()), book.id) {
+                return Err(RepositoryError::Duplicate(first));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What an import did to the books.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ImportCounts {
+    pub inserted: u64,
+    pub updated: u64,
+    /// Books already stored exactly as imported.
+    pub skipped: u64,
+    /// Books `Replace` deleted beforehand.
+    pub deleted: u64
+}
+
+/// A snapshot of one connection pool.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PoolStats {
+    /// `primary`, or `replica` for a Postgres read replica.
+    pub pool: &'static str,
+    /// Open connections, idle or not.
+    pub size: u32,
+    pub idle: u32
+}
+
+impl PoolStats {
+    pub fn of<DB: sqlx::Database>(pool: &'static str, db_pool: &sqlx::Pool<DB>) -> Self {
+        PoolStats {
+            pool,
+            size: db_pool.size(),
+            idle: db_pool.num_idle() as u32
+        }
+    }
+
+    /// The connections a query or transaction has acquired and not yet
+    /// given back.
+    pub fn in_use(&self) -> u32 {
+        self.size.saturating_sub(self.idle)
+    }
+}
+
+/// What the Redis book cache has counted since startup.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SharedCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Commands that failed, Redis being unreachable mostly.
+    pub errors: u64
+}
+
+/// What `SlowQueryLog` has counted since startup.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlowQueryStats {
+    /// Operations past the warning threshold, including those past the
+    /// error one.
+    pub slow: u64,
+    /// Operations past the error threshold.
+    pub very_slow: u64
+}
+
+/// The LIMIT clause for `page`, empty when there is none. Both are plain
+/// numbers, so they're spliced in rather than bound.
+fn page_sql(page: Option<Page>) -> String {
+    match page {
+        Some(page) => format!("LIMIT {} OFFSET {}", page.limit, page.offset),
+        None => String::new(),
+    }
+}
+
+/// Some of a book's fields, for `update_books` to match on or to set.
+/// Unknown fields are rejected rather than ignored, so a typo can't widen
+/// a filter.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BookPatch {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub year: Option<i32>,
+    pub publisher: Option<String>,
+    pub language: Option<String>,
+    pub price: Option<Decimal>,
+    pub stock: Option<i32>
+}
+
+impl BookPatch {
+    pub fn is_empty(&self) -> bool {
+        self.columns().is_empty()
+    }
+
+    /// `book` with the fields that are present replaced.
+    fn apply(&self, book: &Book) -> Book {
+        let mut book = book.clone();
+        if let Some(name) = &self.name {
+            book.name = Some(name.clone());
+        }
+        if let Some(author) = &self.author {
+            book.author = Some(author.clone());
+        }
+        if let Some(year) = self.year {
+            book.year = Some(year);
+        }
+        if let Some(publisher) = &self.publisher {
+            book.publisher = Some(publisher.clone());
+        }
+        if let Some(language) = &self.language {
+            book.language = Some(language.clone());
+        }
+        if let Some(price) = self.price {
+            book.price = Some(price);
+        }
+        if let Some(stock) = self.stock {
+            book.stock = Some(stock);
+        }
+        book
+    }
+
+    /// The fields that are present, in the order their values are bound.
+    fn columns(&self) -> Vec<&'static str> {
+        [
+            ("name", self.name.is_some()),
+            ("author", self.author.is_some()),
+            ("year", self.year.is_some()),
+            ("publisher", self.publisher.is_some()),
+            ("language", self.language.is_some()),
+            ("price", self.price.is_some()),
+            ("stock", self.stock.is_some())
+        ]
+            .into_iter()
+            .filter(|(_, present)| *present)
+            .map(|(column, _)| column)
+            .collect()
+    }
+}
+
+/// The `suffix` of the bulk UPDATEs on the backends that can return the
+/// rows they wrote.
+const RETURNING_BOOK: &str = " RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at";
+
+/// The UPDATE behind `update_books`, with the values of `set` and then of
+/// `filter` bound in `BookPatch::columns` order. `placeholder(n)` is the
+/// backend's syntax for the `n`th parameter, counting from one, and `now`
+/// its SQL for the time, stamped in `updated_at`. `suffix` is appended, for
+/// a `RETURNING` clause.
+fn bulk_update_sql(table: &TableName, filter: &BookPatch, set: &BookPatch, placeholder: fn(usize) -> String, now: &str, suffix: &str) -> String {
+    let mut n = 0;
+    let mut next = || {
+        n += 1;
+        placeholder(n)
+    };
+    let assignments = equalities(set, &mut next);
+    let conditions = equalities(filter, &mut next);
+    let mut sql = format!("UPDATE {} SET {}, updated_at = {}", table, assignments.join(", "), now);
+    if !conditions.is_empty() {
+        sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    sql.push_str(suffix);
+    sql
+}
+
+/// The SELECT of the books `update_books` is about to change, for the
+/// audit log, with `filter`'s values bound as in `bulk_update_sql` but
+/// numbered from one. `suffix` is appended, for a locking clause.
+fn bulk_match_sql(table: &TableName, filter: &BookPatch, placeholder: fn(usize) -> String, suffix: &str) -> String {
+    let mut n = 0;
+    let conditions = equalities(filter, &mut || {
+        n += 1;
+        placeholder(n)
+    });
+    let mut sql = format!("SELECT * FROM {}", table);
+    if !conditions.is_empty() {
+        sql.push_str(&format!(" WHERE {}", conditions.join(" AND ")));
+    }
+    sql.push_str(suffix);
+    sql
+}
+
+/// The UPDATE behind `update_books_by_id`, with the values of `set` bound
+/// in `BookPatch::columns` order and then the `ids` ids, and `updated_at`
+/// stamped and `suffix` appended as in `bulk_update_sql`.
+fn bulk_update_by_id_sql(table: &TableName, set: &BookPatch, ids: usize, placeholder: fn(usize) -> String, now: &str, suffix: &str) -> String {
+    let mut n = 0;
+    let mut next = || {
+        n += 1;
+        placeholder(n)
+    };
+    let assignments = equalities(set, &mut next);
+    let ids: Vec<String> = (0..ids).map(|_| next()).collect();
+    format!("UPDATE {} SET {}, updated_at = {} WHERE id IN ({}){}", table, assignments.join(", "), now, ids.join(", "), suffix)
+}
+
+/// The SELECT of the `ids` books `update_books_by_id` is about to change,
+/// for the audit log, with the ids numbered from one. `suffix` is
+/// appended, for a locking clause.
+fn bulk_match_by_id_sql(table: &TableName, ids: usize, placeholder: fn(usize) -> String, suffix: &str) -> String {
+    let ids: Vec<String> = (1..=ids).map(placeholder).collect();
+    format!("SELECT * FROM {} WHERE id IN ({}){}", table, ids.join(", "), suffix)
+}
+
+/// The multi-row INSERT behind `import_books`, of `rows` books, each one's
+/// `id`, `name`, `author`, `year`, `published_date`, `publisher`,
+/// `language`, `price` and `stock` bound in turn, with `updated_at`
+/// stamped as in `bulk_update_sql`. `suffix` is appended, for the
+/// backend's upsert.
+fn import_sql(table: &TableName, rows: usize, placeholder: fn(usize) -> String, now: &str, suffix: &str) -> String {
+    let values: Vec<String> = (0..rows)
+        .map(|row| {
+            let row: Vec<String> = (9 * row + 1..=9 * row + 9).map(placeholder).collect();
+            format!("({}, {})", row.join(", "), now)
+        })
+        .collect();
+    format!(
+        "INSERT INTO {} (id, name, author, year, published_date, publisher, language, price, stock, updated_at) VALUES {}{}",
+        table, values.join(", "), suffix)
+}
+
+/// The SELECT of a book with the name and author of any of `books` new
+/// books, with each one's name and then author bound in turn.
+/// `same(name, author)` is the backend's condition on a book having those
+/// two parameters' name and author, ignoring case. `suffix` is appended,
+/// for a locking clause.
+fn duplicate_sql(table: &TableName, books: usize, placeholder: fn(usize) -> String, same: fn(&str, &str) -> String, suffix: &str) -> String {
+    let pairs: Vec<String> = (0..books)
+        .map(|book| format!("({})", same(&placeholder(2 * book + 1), &placeholder(2 * book + 2))))
+        .collect();
+    format!("SELECT id FROM {} WHERE {} LIMIT 1{}", table, pairs.join(" OR "), suffix)
+}
+
+/// The multi-row INSERT of `rows` audit records, each one's `book_id`,
+/// `action`, `old_value`, `new_value` and `created_at` bound in turn.
+/// `suffix` is appended, for a `RETURNING` clause.
+fn audit_sql(rows: usize, placeholder: fn(usize) -> String, suffix: &str) -> String {
+    let values: Vec<String> = (0..rows)
+        .map(|row| {
+            let row: Vec<String> = (5 * row + 1..=5 * row + 5).map(placeholder).collect();
+            format!("({})", row.join(", "))
+        })
+        .collect();
+    format!("INSERT INTO audit_log (book_id, action, old_value, new_value, created_at) VALUES {}{}", values.join(", "), suffix)
+}
+
+/// `column = placeholder` for each field of `patch` that is present.
+fn equalities(patch: &BookPatch, next: &mut impl FnMut() -> String) -> Vec<String> {
+    patch.columns().into_iter()
+        .map(|column| format!("{} = {}", column, next()))
+        .collect()
+}
+
+/// A change about to be written to `audit_log`, with both versions of the
+/// book as JSON.
+struct AuditRecord {
+    book_id: Uuid,
+    action: &'static str,
+    old_value: Option<String>,
+    new_value: Option<String>
+}
+
+impl AuditRecord {
+    fn new(action: AuditAction, old: Option<&Book>, new: Option<&Book>) -> Self {
+        let json = |book: &Book| serde_json::to_string(book).expect("a Book always serializes");
+        AuditRecord {
+            book_id: old.or(new).map(|book| book.id).expect("a change has a book before or after it"),
+            action: action.as_str(),
+            old_value: old.map(json),
+            new_value: new.map(json)
+        }
+    }
+
+    fn created(book: &Book) -> Self {
+        AuditRecord::new(AuditAction::Create, None, Some(book))
+    }
+
+    fn updated(old: &Book, new: &Book) -> Self {
+        AuditRecord::new(AuditAction::Update, Some(old), Some(new))
+    }
+
+    fn deleted(book: &Book) -> Self {
+        AuditRecord::new(AuditAction::Delete, Some(book), None)
+    }
+}
+
+/// The entry for an `audit_log` row, as the backends read it back.
+fn audit_entry(book_id: Uuid, action: &str, old_value: Option<String>, new_value: Option<String>, created_at: DateTime<Utc>) -> Result<AuditEntry, RepositoryError> {
+    let decode = |value: Option<String>| -> Result<Option<Book>, RepositoryError> {
+        value.map(|value| serde_json::from_str(&value))
+            .transpose()
+            .map_err(|e| RepositoryError::Database(sqlx::Error::Decode(Box::new(e))))
+    };
+    let action = AuditAction::parse(action)
+        .ok_or_else(|| RepositoryError::Database(sqlx::Error::Decode(format!("unknown audit action {:?}", action).into())))?;
+    Ok(AuditEntry { book_id, action, old: decode(old_value)?, new: decode(new_value)?, created_at })
+}
+
+/// A subscription's `events`, as stored in its `events` column.
+fn events_column(events: &[String]) -> String {
+    events.join(",")
+}
+
+/// The subscription for a `webhook_subscription` row, as the backends
+/// read it back.
+fn webhook_subscription(id: Uuid, url: String, secret: String, events: &str, created_at: DateTime<Utc>) -> WebhookSubscription {
+    let events = events.split(',').filter(|event| !event.is_empty()).map(str::to_owned).collect();
+    WebhookSubscription { id, url, secret, events, created_at }
+}
+
+/// The name of the books table, `book` unless `Config::table_name` says
+/// otherwise.
+///
+/// It's spliced into SQL, so only plain identifiers are accepted: letters,
+/// digits and underscores, optionally qualified by a schema as in
+/// `tenant_a.book`. Reviews, idempotency keys and author links keep their
+/// own tables and point at a book by its id, so the table only needs the
+/// book's columns.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TableName(String);
+
+impl TableName {
+    pub fn new(name: &str) -> Result<Self, String> {
+        let valid_part = |part: &str| {
+            part.len() <= 63
+                && part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        };
+        let parts: Vec<&str> = name.split('.').collect();
+        if parts.len() > 2 || !parts.iter().all(|part| valid_part(part)) {
+            return Err(format!("invalid table name: {:?}", name));
+        }
+        Ok(TableName(name.to_owned()))
+    }
+}
+
+impl std::str::FromStr for TableName {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        TableName::new(name)
+    }
+}
+
+impl Default for TableName {
+    fn default() -> Self {
+        TableName(String::from("book"))
+    }
+}
+
+impl fmt::Display for TableName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A client-chosen `Idempotency-Key`, scoped to the operation it was sent
+/// with, and the hash of the request body it came with. Records created
+/// before `expires_before` no longer count.
+#[derive(Debug)]
+pub struct IdempotencyKey {
+    pub scope: &'static str,
+    pub key: String,
+    pub request_hash: String,
+    pub expires_before: DateTime<Utc>
+}
+
+/// The response body recorded for `key` when `book` is created under it.
+fn idempotent_response_body(book: &Book) -> String {
+    serde_json::to_string(book).expect("a Book always serializes")
+}
+
+/// The request hash and serialized response recorded for a key.
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct IdempotentResponse {
+    pub request_hash: String,
+    pub response_body: String
+}
+
+/// What `change_book` makes of a book as the store holds it: the book to
+/// write in its place, or the error to give up with.
+pub type BookChange<'a> = dyn Fn(Book) -> Result<Book, RepositoryError> + Send + Sync + 'a;
+
+#[derive(Debug)]
+pub enum RepositoryError {
+    /// A row with the same primary key already exists.
+    Conflict,
+    /// The book being created has the same name and author as this one.
+    Duplicate(Uuid),
+    /// The store can't do this at all, or not as configured.
+    Unsupported(&'static str),
+    /// A `change_book` change refused the book it was given, or an import's
+    /// books were refused as they were read.
+    Rejected(Box<AppError>),
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for RepositoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepositoryError::Conflict => write!(f, "a row with this id already exists"),
+            RepositoryError::Duplicate(_) => write!(f, "a book with this name and author already exists"),
+            RepositoryError::Unsupported(what) => write!(f, "{}", what),
+            RepositoryError::Rejected(err) => write!(f, "{}", err),
+            RepositoryError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RepositoryError {}
+
+/// A serialization failure (`40001`, which MySQL also uses for deadlocks),
+/// a Postgres deadlock (`40P01`) or SQLite's `SQLITE_BUSY` (`5`), which a
+/// transaction that has read gets instead of deadlocking when another
+/// holds the write lock: the transaction lost a race with another and was
+/// rolled back, so running it again can succeed.
+const TRANSIENT_CODES: &[&str] = &["40001", "40P01", "5"];
+
+/// Postgres's `query_canceled`, which a statement running past
+/// `statement_timeout` fails with.
+const QUERY_CANCELED_CODE: &str = "57014";
+
+/// Postgres's `admin_shutdown`, `crash_shutdown` and `cannot_connect_now`
+/// ("the database system is starting up"): the server went away, or isn't
+/// back yet. Class `08`, the connection exceptions, is matched as well.
+const UNREACHABLE_CODES: &[&str] = &["57P01", "57P02", "57P03"];
+
+impl RepositoryError {
+    /// Whether the database cancelled the query for running too long.
+    pub fn is_timed_out(&self) -> bool {
+        match self {
+            RepositoryError::Database(sqlx::Error::Database(db_err)) => {
+                db_err.code().is_some_and(|code| code == QUERY_CANCELED_CODE)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the store couldn't be reached at all, rather than failing
+    /// the query: the connection was refused or dropped, or the server is
+    /// shutting down or starting up. Waiting for a free connection is not
+    /// this; the store is up, only busy.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            RepositoryError::Database(sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolClosed) => true,
+            RepositoryError::Database(sqlx::Error::Database(db_err)) => db_err.code().is_some_and(|code| {
+                code.starts_with("08") || UNREACHABLE_CODES.contains(&code.as_ref())
+            }),
+            _ => false,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RepositoryError::Database(sqlx::Error::Database(db_err)) => {
+                db_err.code().is_some_and(|code| TRANSIENT_CODES.contains(&code.as_ref()))
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Unique violations: `23505` on Postgres, `1555`/`2067` (primary key and
+/// unique constraint) on SQLite.
+const UNIQUE_VIOLATION_CODES: &[&str] = &["23505", "1555", "2067"];
+
+/// MySQL reports every integrity violation as SQLSTATE `23000`, so
+/// duplicates are told apart by the `ER_DUP_ENTRY` error number instead.
+#[cfg(feature = "mysql")]
+const MYSQL_ER_DUP_ENTRY: u16 = 1062;
+
+fn is_unique_violation(db_err: &dyn sqlx::error::DatabaseError) -> bool {
+    #[cfg(feature = "mysql")]
+    if let Some(mysql_err) = db_err.try_downcast_ref::<sqlx::mysql::MySqlDatabaseError>() {
+        return mysql_err.number() == MYSQL_ER_DUP_ENTRY;
+    }
+    db_err.code().is_some_and(|code| UNIQUE_VIOLATION_CODES.contains(&code.as_ref()))
+}
+
+impl From<sqlx::Error> for RepositoryError {
+    fn from(err: sqlx::Error) -> Self {
+        match &err {
+            sqlx::Error::Database(db_err) if is_unique_violation(db_err.as_ref()) => RepositoryError::Conflict,
+            _ => RepositoryError::Database(err),
+        }
+    }
+}
+
+#[test]
+fn filters_are_anded_with_numbered_parameters() {
+    let dialect = Dialect {
+        placeholder: |n| format!("${}", n),
+        contains: |column, parameter| format!("{} ILIKE '%' || {} || '%' ESCAPE '!'", column, parameter),
+        at_most: |column, parameter| format!("{} <= {}", column, parameter)
+    };
+    assert_eq!("", filter_sql(&BookFilter::default(), &dialect));
+
+    let filter = BookFilter { year_min: Some(2015), ..BookFilter::default() };
+    assert_eq!("WHERE year >= $1", filter_sql(&filter, &dialect));
+
+    let filter = BookFilter {
+        language: Some(String::from("en")),
+        author: Some(String::from("Nichols")),
+        year_min: Some(2015),
+        ..BookFilter::default()
+    };
+    assert_eq!(
+        "WHERE language = $1 AND author ILIKE '%' || $2 || '%' ESCAPE '!' AND year >= $3",
+        filter_sql(&filter, &dialect));
+}
@@ -0,0 +1,989 @@
+use std::collections::HashMap;
+
+use async_std::channel::Sender;
+use async_std::stream::StreamExt;
+use sqlx::{MySqlPool, MySql, Transaction, query_as, query_scalar};
+use sqlx::mysql::MySqlArguments;
+use sqlx::query::QueryAs;
+use sqlx::types::chrono::{DateTime, NaiveDate, Utc};
+use rust_decimal::Decimal;
+use uuid::Uuid;
+use uuid::fmt::Hyphenated;
+
+use crate::{AuditEntry, Author, Book, NewAuthor, NewReview, RatedBook, Review, ScoredBook, WebhookSubscription};
+use super::{AuditRecord, BookChange, BookFilter, BookPatch, BookRepository, Dialect, IMPORT_BATCH, IdempotencyKey, IdempotentResponse, ImportBatches, ImportCounts, ImportMode, Page, PoolStats, RepositoryError, SharedCacheStats, SlowQueryStats, TableName, audit_entry, audit_sql, bulk_match_by_id_sql, bulk_match_sql, bulk_update_by_id_sql, bulk_update_sql, distinct_runs, duplicate_sql, events_column, filter_sql, idempotent_response_body, import_sql, like_escape, next_batch, page_sql, refuse_repeats, select_list, webhook_subscription};
+
+/// Ids are stored as CHAR(36), and since MySQL has no `RETURNING` every
+/// write is followed by a SELECT inside the same transaction.
+#[derive(sqlx::FromRow)]
+struct BookRow {
+    id: Hyphenated,
+    name: Option<String>,
+    author: Option<String>,
+    year: Option<i32>,
+    published_date: Option<NaiveDate>,
+    publisher: Option<String>,
+    language: Option<String>,
+    price: Option<Decimal>,
+    stock: Option<i32>,
+    #[sqlx(default)]
+    updated_at: Option<DateTime<Utc>>
+}
+
+impl From<BookRow> for Book {
+    fn from(row: BookRow) -> Self {
+        Book {
+            id: row.id.into_uuid(),
+            name: row.name,
+            author: row.author,
+            year: row.year,
+            published_date: row.published_date,
+            publisher: row.publisher,
+            language: row.language,
+            price: row.price,
+            stock: row.stock,
+            updated_at: row.updated_at
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct RatedBookRow {
+    #[sqlx(flatten)]
+    book: BookRow,
+    avg_rating: Option<f64>,
+    review_count: i64
+}
+
+#[derive(sqlx::FromRow)]
+struct ReviewRow {
+    id: Hyphenated,
+    book_id: Hyphenated,
+    rating: i32,
+    text: Option<String>,
+    created_at: DateTime<Utc>
+}
+
+impl From<ReviewRow> for Review {
+    fn from(row: ReviewRow) -> Self {
+        Review {
+            id: row.id.into_uuid(),
+            book_id: row.book_id.into_uuid(),
+            rating: row.rating,
+            text: row.text,
+            created_at: row.created_at
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct AuditRow {
+    book_id: Hyphenated,
+    action: String,
+    old_value: Option<String>,
+    new_value: Option<String>,
+    created_at: DateTime<Utc>
+}
+
+#[derive(sqlx::FromRow)]
+struct WebhookRow {
+    id: Hyphenated,
+    url: String,
+    secret: String,
+    events: String,
+    created_at: DateTime<Utc>
+}
+
+impl From<WebhookRow> for WebhookSubscription {
+    fn from(row: WebhookRow) -> Self {
+        webhook_subscription(row.id.into_uuid(), row.url, row.secret, &row.events, row.created_at)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MySqlBookRepository {
+    db_pool: MySqlPool,
+    table: TableName
+}
+
+impl MySqlBookRepository {
+    pub fn new(db_pool: MySqlPool) -> Self {
+        MySqlBookRepository { db_pool, table: TableName::default() }
+    }
+
+    /// Keeps books in `table` instead of `book`.
+    pub fn with_table(self, table: TableName) -> Self {
+        MySqlBookRepository { table, ..self }
+    }
+    /// Fails with `Duplicate` if `book`'s name and author are taken. The
+    /// lookup goes through `book_name_author_idx` on the stored lower-cased
+    /// columns, so at `REPEATABLE READ` the locking read only locks the
+    /// index entries for the pair and the gap around them, not the table.
+    /// Of two concurrent creates of the pair one waits for the other or
+    /// deadlocks and is retried; either way it then sees the other's book.
+    async fn refuse_duplicate(&self, tx: &mut Transaction<'_, MySql>, book: &Book) -> Result<(), RepositoryError> {
+        let existing = query_scalar::<_, Hyphenated>(&format!(
+            r#"
+            SELECT id FROM {book}
+            WHERE name_lower = LOWER(?) AND author_lower = LOWER(?)
+            LIMIT 1
+            FOR UPDATE
+            "#, book = self.table))
+            .bind(&book.name)
+            .bind(&book.author)
+            .fetch_optional(&mut *tx).await?;
+        match existing {
+            Some(id) => Err(RepositoryError::Duplicate(id.into_uuid())),
+            None => Ok(()),
+        }
+    }
+
+    /// The books with `ids` as `tx` sees them, for what a bulk write returns
+    /// in place of `RETURNING`, which MySQL lacks. They're read
+    /// `IMPORT_BATCH` at a time to bound the placeholders.
+    async fn read_books(&self, tx: &mut Transaction<'_, MySql>, ids: &[Uuid]) -> Result<Vec<Book>, RepositoryError> {
+        let mut books = Vec::with_capacity(ids.len());
+        for batch in ids.chunks(IMPORT_BATCH as usize) {
+            let sql = bulk_match_by_id_sql(&self.table, batch.len(), |_| String::from("?"), "");
+            let mut query = query_as::<_, BookRow>(&sql);
+            for id in batch {
+                query = query.bind(id.hyphenated());
+            }
+            books.extend(query.fetch_all(&mut *tx).await?.into_iter().map(Book::from));
+        }
+        Ok(books)
+    }
+
+    /// The book as it is before `tx` changes it, locked until `tx` ends.
+    async fn lock_book(&self, tx: &mut Transaction<'_, MySql>, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let row = query_as::<_, BookRow>(&format!(
+            r#"
+            SELECT * FROM {book}
+            WHERE id = ?
+            FOR UPDATE
+            "#, book = self.table))
+            .bind(id.hyphenated())
+            .fetch_optional(&mut *tx).await?;
+        Ok(row.map(Book::from))
+    }
+
+    /// The book as `tx` left it.
+    async fn fetch_book(&self, tx: &mut Transaction<'_, MySql>, id: Uuid) -> Result<Book, RepositoryError> {
+        let row = query_as::<_, BookRow>(&format!(
+            r#"
+            SELECT * FROM {book}
+            WHERE id = ?
+            "#, book = self.table))
+            .bind(id.hyphenated())
+            .fetch_one(&mut *tx).await?;
+        Ok(row.into())
+    }
+
+    /// `refuse_duplicate` for all of `books`, new books written in one
+    /// statement, looked up at once through `book_name_author_idx`.
+    async fn refuse_duplicates(&self, tx: &mut Transaction<'_, MySql>, books: &[&Book]) -> Result<(), RepositoryError> {
+        if books.is_empty() {
+            return Ok(());
+        }
+        refuse_repeats(books)?;
+        let sql = duplicate_sql(&self.table, books.len(), |_| String::from("?"), |name, author| format!("name_lower = LOWER({}) AND author_lower = LOWER({})", name, author), " FOR UPDATE");
+        let mut query = query_scalar::<_, Hyphenated>(&sql);
+        for book in books {
+            query = query.bind(&book.name).bind(&book.author);
+        }
+        match query.fetch_optional(&mut *tx).await? {
+            Some(id) => Err(RepositoryError::Duplicate(id.into_uuid())),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a run of an import's books, all with different ids, as part
+    /// of `tx`: locks the ones already stored, refuses new ones whose name
+    /// and author are taken, and upserts the rest in one statement and
+    /// audits them in another, adding them to `counts`.
+    async fn import_run(&self, tx: &mut Transaction<'_, MySql>, books: Vec<Book>, counts: &mut ImportCounts) -> Result<(), RepositoryError> {
+        let sql = bulk_match_by_id_sql(&self.table, books.len(), |_| String::from("?"), " FOR UPDATE");
+        let mut query = query_as::<_, BookRow>(&sql);
+        for book in &books {
+            query = query.bind(book.id.hyphenated());
+        }
+        let old: HashMap<Uuid, Book> = query.fetch_all(&mut *tx).await?.into_iter().map(|row| (row.id.into_uuid(), Book::from(row))).collect();
+        let (skipped, books): (Vec<Book>, Vec<Book>) = books.into_iter().partition(|book| old.get(&book.id) == Some(book));
+        counts.skipped += skipped.len() as u64;
+        let new: Vec<&Book> = books.iter().filter(|book| !old.contains_key(&book.id)).collect();
+        self.refuse_duplicates(tx, &new).await?;
+        if books.is_empty() {
+            return Ok(());
+        }
+
+        let ids: Vec<Uuid> = books.iter().map(|book| book.id).collect();
+        let sql = import_sql(&self.table, books.len(), |_| String::from("?"), "CURRENT_TIMESTAMP(6)",
+            r#"
+            ON DUPLICATE KEY UPDATE
+            name = VALUES(name), author = VALUES(author), year = VALUES(year),
+            published_date = VALUES(published_date), publisher = VALUES(publisher),
+            language = VALUES(language), price = VALUES(price), stock = VALUES(stock),
+            updated_at = VALUES(updated_at)
+            "#);
+        let mut query = sqlx::query(&sql);
+        for book in books {
+            query = query
+                .bind(book.id.hyphenated())
+                .bind(book.name)
+                .bind(book.author)
+                .bind(book.year)
+                .bind(book.published_date)
+                .bind(book.publisher)
+                .bind(book.language)
+                .bind(book.price)
+                .bind(book.stock);
+        }
+        query.execute(&mut *tx).await?;
+        let rows = self.read_books(tx, &ids).await?;
+        let mut records = Vec::with_capacity(rows.len());
+        for row in rows {
+            match old.get(&row.id) {
+                Some(old) => {
+                    counts.updated += 1;
+                    records.push(AuditRecord::updated(old, &row));
+                }
+                None => {
+                    counts.inserted += 1;
+                    records.push(AuditRecord::created(&row));
+                }
+            }
+        }
+        audit_batch(tx, records).await
+    }
+}
+
+/// Writes `record` to the audit log as part of `tx`.
+async fn audit(tx: &mut Transaction<'_, MySql>, record: AuditRecord) -> Result<(), RepositoryError> {
+    sqlx::query(
+        r#"
+        INSERT INTO audit_log (book_id, action, old_value, new_value, created_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#)
+        .bind(record.book_id.hyphenated())
+        .bind(record.action)
+        .bind(record.old_value)
+        .bind(record.new_value)
+        .bind(Utc::now())
+        .execute(&mut *tx).await?;
+    Ok(())
+}
+
+/// Deletes the reviews, idempotency keys and author links of the books
+/// `ids` as part of `tx`. They can't cascade from the books table, which
+/// `TABLE_NAME` may name.
+async fn delete_dependents(tx: &mut Transaction<'_, MySql>, ids: &[Uuid]) -> Result<(), RepositoryError> {
+    let placeholders = vec!["?"; ids.len()].join(", ");
+    for table in ["review", "idempotency_key", "book_author"] {
+        let sql = format!("DELETE FROM {} WHERE book_id IN ({})", table, placeholders);
+        let mut query = sqlx::query(&sql);
+        for id in ids {
+            query = query.bind(id.hyphenated());
+        }
+        query.execute(&mut *tx).await?;
+    }
+    Ok(())
+}
+
+/// `audit` for a batch of `records`, in one statement.
+async fn audit_batch(tx: &mut Transaction<'_, MySql>, records: Vec<AuditRecord>) -> Result<(), RepositoryError> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let sql = audit_sql(records.len(), |_| String::from("?"), "");
+    let now = Utc::now();
+    let mut query = sqlx::query(&sql);
+    for record in records {
+        query = query
+            .bind(record.book_id.hyphenated())
+            .bind(record.action)
+            .bind(record.old_value)
+            .bind(record.new_value)
+            .bind(now);
+    }
+    query.execute(&mut *tx).await?;
+    Ok(())
+}
+
+/// `filter_sql` in MySQL's dialect.
+fn where_clause(filter: &BookFilter) -> String {
+    filter_sql(filter, &Dialect {
+        placeholder: |_| String::from("?"),
+        contains: |column, term| format!("lower({}) LIKE CONCAT('%', lower({}), '%') ESCAPE '!'", column, term),
+        at_most: |column, value| format!("{} <= {}", column, value)
+    })
+}
+
+/// Binds `filter`'s values in the order `where_clause` numbers them.
+fn bind_filter<'q, O>(mut query: QueryAs<'q, MySql, O, MySqlArguments>, filter: &'q BookFilter) -> QueryAs<'q, MySql, O, MySqlArguments> {
+    if let Some(published_after) = filter.published_after {
+        query = query.bind(published_after);
+    }
+    if let Some(name_contains) = &filter.name_contains {
+        query = query.bind(like_escape(name_contains));
+    }
+    if let Some(publisher) = &filter.publisher {
+        query = query.bind(like_escape(publisher));
+    }
+    if let Some(language) = &filter.language {
+        query = query.bind(language);
+    }
+    if let Some(max_price) = filter.max_price {
+        query = query.bind(max_price);
+    }
+    if let Some(author) = &filter.author {
+        query = query.bind(like_escape(author));
+    }
+    if let Some(year_min) = filter.year_min {
+        query = query.bind(year_min);
+    }
+    query
+}
+
+/// Binds `patch`'s values in `BookPatch::columns` order. The bulk UPDATEs
+/// go through it too, as queries that fetch no rows.
+fn bind_patch<'q, O>(mut query: QueryAs<'q, MySql, O, MySqlArguments>, patch: &'q BookPatch) -> QueryAs<'q, MySql, O, MySqlArguments> {
+    if let Some(name) = &patch.name {
+        query = query.bind(name);
+    }
+    if let Some(author) = &patch.author {
+        query = query.bind(author);
+    }
+    if let Some(year) = patch.year {
+        query = query.bind(year);
+    }
+    if let Some(publisher) = &patch.publisher {
+        query = query.bind(publisher);
+    }
+    if let Some(language) = &patch.language {
+        query = query.bind(language);
+    }
+    if let Some(price) = patch.price {
+        query = query.bind(price);
+    }
+    if let Some(stock) = patch.stock {
+        query = query.bind(stock);
+    }
+    query
+}
+
+#[tide::utils::async_trait]
+impl BookRepository for MySqlBookRepository {
+    async fn create_book(&self, book: Book) -> Result<Book, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        self.refuse_duplicate(&mut tx, &book).await?;
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP(6))
+            "#, book = self.table))
+            .bind(book.id.hyphenated())
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .execute(&mut tx).await?;
+        let row = self.fetch_book(&mut tx, book.id).await?;
+        audit(&mut tx, AuditRecord::created(&row)).await?;
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    async fn find_idempotent_response(&self, key: &IdempotencyKey) -> Result<Option<IdempotentResponse>, RepositoryError> {
+        let row = query_as::<_, IdempotentResponse>(
+            r#"
+            SELECT request_hash, response_body FROM idempotency_key
+            WHERE scope = ? AND `key` = ? AND created_at >= ?
+            "#)
+            .bind(key.scope)
+            .bind(&key.key)
+            .bind(key.expires_before)
+            .fetch_optional(&self.db_pool).await?;
+        Ok(row)
+    }
+
+    async fn create_book_with_key(&self, book: Book, key: &IdempotencyKey) -> Result<Book, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM idempotency_key
+            WHERE created_at < ?
+            "#)
+            .bind(key.expires_before)
+            .execute(&mut tx).await?;
+        self.refuse_duplicate(&mut tx, &book).await?;
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP(6))
+            "#, book = self.table))
+            .bind(book.id.hyphenated())
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .execute(&mut tx).await?;
+        let row = self.fetch_book(&mut tx, book.id).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO idempotency_key (scope, `key`, book_id, request_hash, response_body, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#)
+            .bind(key.scope)
+            .bind(&key.key)
+            .bind(row.id.hyphenated())
+            .bind(&key.request_hash)
+            .bind(idempotent_response_body(&row))
+            .bind(Utc::now())
+            .execute(&mut tx).await?;
+        audit(&mut tx, AuditRecord::created(&row)).await?;
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    async fn create_book_with_author(&self, book: Book, author: NewAuthor) -> Result<(Book, Author), RepositoryError> {
+        let author = Author { id: Uuid::new_v4(), name: author.name };
+        let mut tx = self.db_pool.begin().await?;
+        self.refuse_duplicate(&mut tx, &book).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO author (id, name)
+            VALUES (?, ?)
+            "#)
+            .bind(author.id.hyphenated())
+            .bind(&author.name)
+            .execute(&mut tx).await?;
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP(6))
+            "#, book = self.table))
+            .bind(book.id.hyphenated())
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .execute(&mut tx).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO book_author (book_id, author_id)
+            VALUES (?, ?)
+            "#)
+            .bind(book.id.hyphenated())
+            .bind(author.id.hyphenated())
+            .execute(&mut tx).await?;
+        let row = self.fetch_book(&mut tx, book.id).await?;
+        audit(&mut tx, AuditRecord::created(&row)).await?;
+        tx.commit().await?;
+        Ok((row, author))
+    }
+
+    async fn list_books(&self, columns: &[&str], filter: &BookFilter, page: Option<Page>) -> Result<Vec<Book>, RepositoryError> {
+        let sql = format!(
+            r#"
+            SELECT {columns} FROM {book}
+            {filter}
+            ORDER BY id
+            {page}
+            "#, columns = select_list(columns), book = self.table, filter = where_clause(filter), page = page_sql(page));
+        let rows = bind_filter(query_as::<_, BookRow>(&sql), filter).fetch_all(&self.db_pool).await?;
+        Ok(rows.into_iter().map(Book::from).collect())
+    }
+
+    async fn count_books(&self, filter: &BookFilter) -> Result<u64, RepositoryError> {
+        let sql = format!(
+            r#"
+            SELECT COUNT(*) FROM {book}
+            {filter}
+            "#, book = self.table, filter = where_clause(filter));
+        let (count,) = bind_filter(query_as::<_, (i64,)>(&sql), filter).fetch_one(&self.db_pool).await?;
+        Ok(count as u64)
+    }
+
+    async fn get_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let row = query_as::<_, BookRow>(&format!(
+            r#"
+            SELECT * FROM {book}
+            WHERE id = ?
+            "#, book = self.table))
+            .bind(id.hyphenated())
+            .fetch_optional(&self.db_pool).await?;
+        Ok(row.map(Book::from))
+    }
+
+    async fn search_books(&self, _term: &str, _threshold: f32, _limit: u32) -> Result<Vec<ScoredBook>, RepositoryError> {
+        Err(RepositoryError::Unsupported("fuzzy search needs Postgres with the pg_trgm extension"))
+    }
+
+    async fn random_book(&self) -> Result<Option<Book>, RepositoryError> {
+        let row = query_as::<_, BookRow>(&format!(
+            r#"
+            SELECT * FROM {book}
+            ORDER BY RAND()
+            LIMIT 1
+            "#, book = self.table))
+            .fetch_optional(&self.db_pool).await?;
+        Ok(row.map(Book::from))
+    }
+
+    async fn similar_books(&self, book: &Book, limit: u32) -> Result<Vec<Book>, RepositoryError> {
+        let Some(author) = &book.author else {
+            return Ok(Vec::new());
+        };
+        let rows = query_as::<_, BookRow>(&format!(
+            r#"
+            SELECT * FROM {book}
+            WHERE lower(author) = lower(?) AND id <> ?
+            ORDER BY id
+            LIMIT {limit}
+            "#, book = self.table, limit = limit))
+            .bind(author)
+            .bind(book.id.hyphenated())
+            .fetch_all(&self.db_pool).await?;
+        Ok(rows.into_iter().map(Book::from).collect())
+    }
+
+    async fn book_exists(&self, id: Uuid) -> Result<bool, RepositoryError> {
+        let row = sqlx::query(&format!(
+            r#"
+            SELECT 1 FROM {book}
+            WHERE id = ?
+            "#, book = self.table))
+            .bind(id.hyphenated())
+            .fetch_optional(&self.db_pool).await?;
+        Ok(row.is_some())
+    }
+
+    async fn get_rated_book(&self, id: Uuid) -> Result<Option<RatedBook>, RepositoryError> {
+        let row = query_as::<_, RatedBookRow>(&format!(
+            r#"
+            SELECT b.id, b.name, b.author, b.year, b.published_date, b.publisher, b.language, b.price, b.stock,
+                CAST(AVG(r.rating) AS DOUBLE) AS avg_rating,
+                COUNT(r.id) AS review_count
+            FROM {book} b
+            LEFT JOIN review r ON r.book_id = b.id
+            WHERE b.id = ?
+            GROUP BY b.id
+            "#, book = self.table))
+            .bind(id.hyphenated())
+            .fetch_optional(&self.db_pool).await?;
+        Ok(row.map(|row| RatedBook {
+            book: row.book.into(),
+            avg_rating: row.avg_rating,
+            review_count: row.review_count
+        }))
+    }
+
+    async fn update_book(&self, id: Uuid, book: Book) -> Result<Option<Book>, RepositoryError> {
+        self.change_book(id, &|_| Ok(book.clone())).await
+    }
+
+    async fn change_book(&self, id: Uuid, change: &BookChange<'_>) -> Result<Option<Book>, RepositoryError> {
+        // `rows_affected` only counts rows that actually changed on MySQL,
+        // so whether the book exists is decided by the SELECT instead.
+        let mut tx = self.db_pool.begin().await?;
+        let old = match self.lock_book(&mut tx, id).await? {
+            Some(old) => old,
+            None => return Ok(None),
+        };
+        let book = change(old.clone())?;
+        sqlx::query(&format!(
+            r#"
+            UPDATE {book}
+            SET name = ?, author = ?, year = ?, published_date = ?, publisher = ?, language = ?, price = ?, stock = ?, updated_at = CURRENT_TIMESTAMP(6)
+            WHERE id = ?
+            "#, book = self.table))
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .bind(id.hyphenated())
+            .execute(&mut tx).await?;
+        let row = self.fetch_book(&mut tx, id).await?;
+        audit(&mut tx, AuditRecord::updated(&old, &row)).await?;
+        tx.commit().await?;
+        Ok(Some(row))
+    }
+
+    async fn upsert_book(&self, id: Uuid, book: Book) -> Result<(Book, bool), RepositoryError> {
+        // `ON DUPLICATE KEY UPDATE` reports one affected row for an insert,
+        // two for an update and zero for an update that changed nothing.
+        let mut tx = self.db_pool.begin().await?;
+        let old = self.lock_book(&mut tx, id).await?;
+        let affected = sqlx::query(&format!(
+            r#"
+            INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP(6))
+            ON DUPLICATE KEY UPDATE
+            name = VALUES(name), author = VALUES(author), year = VALUES(year),
+            published_date = VALUES(published_date), publisher = VALUES(publisher),
+            language = VALUES(language), price = VALUES(price), stock = VALUES(stock),
+            updated_at = VALUES(updated_at)
+            "#, book = self.table))
+            .bind(id.hyphenated())
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .execute(&mut tx).await?
+            .rows_affected();
+        let row = self.fetch_book(&mut tx, id).await?;
+        let record = match &old {
+            Some(old) => AuditRecord::updated(old, &row),
+            None => AuditRecord::created(&row),
+        };
+        audit(&mut tx, record).await?;
+        tx.commit().await?;
+        Ok((row, affected == 1))
+    }
+
+    async fn update_books(&self, filter: &BookPatch, set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        let sql = bulk_update_sql(&self.table, filter, set, |_| String::from("?"), "CURRENT_TIMESTAMP(6)", "");
+        let mut tx = self.db_pool.begin().await?;
+        let matched_sql = bulk_match_sql(&self.table, filter, |_| String::from("?"), " FOR UPDATE");
+        let matched: Vec<Book> = bind_patch(query_as::<_, BookRow>(&matched_sql), filter).fetch_all(&mut tx).await?
+            .into_iter().map(Book::from).collect();
+        bind_patch(bind_patch(query_as::<_, BookRow>(&sql), set), filter).fetch_all(&mut tx).await?;
+        for old in &matched {
+            let new = set.apply(old);
+            if new != *old {
+                audit(&mut tx, AuditRecord::updated(old, &new)).await?;
+            }
+        }
+        let ids: Vec<Uuid> = matched.iter().map(|book| book.id).collect();
+        let updated = self.read_books(&mut tx, &ids).await?;
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    async fn update_books_by_id(&self, ids: &[Uuid], set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sql = bulk_update_by_id_sql(&self.table, set, ids.len(), |_| String::from("?"), "CURRENT_TIMESTAMP(6)", "");
+        let mut query = bind_patch(query_as::<_, BookRow>(&sql), set);
+        for id in ids {
+            query = query.bind(id.hyphenated());
+        }
+        let mut tx = self.db_pool.begin().await?;
+        let matched_sql = bulk_match_by_id_sql(&self.table, ids.len(), |_| String::from("?"), " FOR UPDATE");
+        let mut matched = query_as::<_, BookRow>(&matched_sql);
+        for id in ids {
+            matched = matched.bind(id.hyphenated());
+        }
+        let matched: Vec<Book> = matched.fetch_all(&mut tx).await?.into_iter().map(Book::from).collect();
+        query.fetch_all(&mut tx).await?;
+        for old in &matched {
+            let new = set.apply(old);
+            if new != *old {
+                audit(&mut tx, AuditRecord::updated(old, &new)).await?;
+            }
+        }
+        let ids: Vec<Uuid> = matched.iter().map(|book| book.id).collect();
+        let updated = self.read_books(&mut tx, &ids).await?;
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    async fn checkout_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let affected = sqlx::query(&format!(
+            r#"
+            UPDATE {book}
+            SET stock = stock - 1, updated_at = CURRENT_TIMESTAMP(6)
+            WHERE id = ? AND stock > 0
+            "#, book = self.table))
+            .bind(id.hyphenated())
+            .execute(&mut tx).await?
+            .rows_affected();
+        if affected == 0 {
+            return Ok(None);
+        }
+        let row = self.fetch_book(&mut tx, id).await?;
+        let old = Book { stock: row.stock.map(|stock| stock + 1), ..row.clone() };
+        audit(&mut tx, AuditRecord::updated(&old, &row)).await?;
+        tx.commit().await?;
+        Ok(Some(row))
+    }
+
+    async fn delete_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        // The row is read first, under a lock, since there's no `RETURNING`.
+        let mut tx = self.db_pool.begin().await?;
+        let row = self.lock_book(&mut tx, id).await?;
+        if let Some(row) = &row {
+            sqlx::query(&format!(
+                r#"
+                DELETE FROM {book}
+                WHERE id = ?
+                "#, book = self.table))
+                .bind(id.hyphenated())
+                .execute(&mut tx).await?;
+            delete_dependents(&mut tx, &[row.id]).await?;
+            audit(&mut tx, AuditRecord::deleted(row)).await?;
+        }
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    async fn export_books(&self, filter: &BookFilter, counted: Sender<u64>, out: Sender<Book>) -> Result<u64, RepositoryError> {
+        // InnoDB reads from one snapshot from a transaction's first read on.
+        let mut tx = self.db_pool.begin().await?;
+        let sql = format!("SELECT COUNT(*) FROM {} {}", self.table, where_clause(filter));
+        let (count,) = bind_filter(query_as::<_, (i64,)>(&sql), filter).fetch_one(&mut tx).await?;
+        // Not wanted by every export.
+        let _ = counted.send(count as u64).await;
+        let sql = format!("SELECT * FROM {} {} ORDER BY id", self.table, where_clause(filter));
+        let mut rows = bind_filter(query_as::<_, BookRow>(&sql), filter).fetch(&mut tx);
+        let mut exported = 0;
+        while let Some(row) = rows.next().await {
+            if out.send(row?.into()).await.is_err() {
+                break;
+            }
+            exported += 1;
+        }
+        Ok(exported)
+    }
+
+    async fn restore_books(&self, books: Vec<Book>) -> Result<Vec<Book>, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let mut restored = Vec::with_capacity(books.len());
+        for book in books {
+            self.refuse_duplicate(&mut tx, &book).await?;
+            sqlx::query(&format!(
+                r#"
+                INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP(6))
+                "#, book = self.table))
+                .bind(book.id.hyphenated())
+                .bind(book.name)
+                .bind(book.author)
+                .bind(book.year)
+                .bind(book.published_date)
+                .bind(book.publisher)
+                .bind(book.language)
+                .bind(book.price)
+                .bind(book.stock)
+                .execute(&mut tx).await?;
+            let row = self.fetch_book(&mut tx, book.id).await?;
+            audit(&mut tx, AuditRecord::created(&row)).await?;
+            restored.push(row);
+        }
+        tx.commit().await?;
+        Ok(restored)
+    }
+
+    async fn import_books(&self, books: ImportBatches, mode: ImportMode) -> Result<ImportCounts, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let mut counts = ImportCounts::default();
+        if mode == ImportMode::Replace {
+            // Each batch is read first, under a lock, since there's no `RETURNING`.
+            loop {
+                let deleted: Vec<Book> = query_as::<_, BookRow>(&format!(
+                    "SELECT * FROM {} LIMIT {} FOR UPDATE", self.table, IMPORT_BATCH))
+                    .fetch_all(&mut tx).await?
+                    .into_iter()
+                    .map(Book::from)
+                    .collect();
+                if deleted.is_empty() {
+                    break;
+                }
+                let placeholders = vec!["?"; deleted.len()].join(", ");
+                let sql = format!("DELETE FROM {} WHERE id IN ({})", self.table, placeholders);
+                let mut delete = sqlx::query(&sql);
+                for row in &deleted {
+                    delete = delete.bind(row.id.hyphenated());
+                }
+                delete.execute(&mut tx).await?;
+                let ids: Vec<Uuid> = deleted.iter().map(|row| row.id).collect();
+                delete_dependents(&mut tx, &ids).await?;
+                audit_batch(&mut tx, deleted.iter().map(AuditRecord::deleted).collect()).await?;
+                counts.deleted += deleted.len() as u64;
+            }
+        }
+        while let Some(batch) = next_batch(&books).await? {
+            for run in distinct_runs(batch) {
+                self.import_run(&mut tx, run, &mut counts).await?;
+            }
+        }
+        tx.commit().await?;
+        Ok(counts)
+    }
+
+    async fn book_history(&self, id: Uuid, limit: u32) -> Result<Vec<AuditEntry>, RepositoryError> {
+        let rows = query_as::<_, AuditRow>(&format!(
+            r#"
+            SELECT book_id, action, old_value, new_value, created_at FROM audit_log
+            WHERE book_id = ?
+            ORDER BY id
+            LIMIT {}
+            "#, limit))
+            .bind(id.hyphenated())
+            .fetch_all(&self.db_pool).await?;
+        rows.into_iter()
+            .map(|row| audit_entry(row.book_id.into_uuid(), &row.action, row.old_value, row.new_value, row.created_at))
+            .collect()
+    }
+
+    async fn create_review(&self, book_id: Uuid, review: NewReview) -> Result<Option<Review>, RepositoryError> {
+        let id = Uuid::new_v4();
+        let mut tx = self.db_pool.begin().await?;
+        let result = sqlx::query(&format!(
+            r#"
+            INSERT INTO review (id, book_id, rating, text, created_at)
+            SELECT ?, ?, ?, ?, ? FROM DUAL
+            WHERE EXISTS (SELECT 1 FROM {book} WHERE id = ?)
+            "#, book = self.table))
+            .bind(id.hyphenated())
+            .bind(book_id.hyphenated())
+            .bind(review.rating)
+            .bind(review.text)
+            .bind(Utc::now())
+            .bind(book_id.hyphenated())
+            .execute(&mut tx).await?;
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        let row = query_as::<_, ReviewRow>(
+            r#"
+            SELECT * FROM review
+            WHERE id = ?
+            "#)
+            .bind(id.hyphenated())
+            .fetch_one(&mut tx).await?;
+        tx.commit().await?;
+        Ok(Some(row.into()))
+    }
+
+    async fn list_reviews(&self, book_id: Uuid, limit: u32) -> Result<Vec<Review>, RepositoryError> {
+        let rows = query_as::<_, ReviewRow>(&format!(
+            r#"
+            SELECT * FROM review
+            WHERE book_id = ?
+            ORDER BY created_at
+            LIMIT {}
+            "#, limit))
+            .bind(book_id.hyphenated())
+            .fetch_all(&self.db_pool).await?;
+        Ok(rows.into_iter().map(Review::from).collect())
+    }
+
+    async fn list_reviews_for_books(&self, book_ids: &[Uuid]) -> Result<Vec<Review>, RepositoryError> {
+        if book_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sql = format!(
+            r#"
+            SELECT * FROM review
+            WHERE book_id IN ({})
+            ORDER BY created_at
+            "#, vec!["?"; book_ids.len()].join(", "));
+        let mut query = query_as::<_, ReviewRow>(&sql);
+        for book_id in book_ids {
+            query = query.bind(book_id.hyphenated());
+        }
+        let rows = query.fetch_all(&self.db_pool).await?;
+        Ok(rows.into_iter().map(Review::from).collect())
+    }
+
+    async fn create_webhook(&self, subscription: WebhookSubscription) -> Result<WebhookSubscription, RepositoryError> {
+        sqlx::query(
+            r#"
+            INSERT INTO webhook_subscription (id, url, secret, events, created_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#)
+            .bind(subscription.id.hyphenated())
+            .bind(&subscription.url)
+            .bind(&subscription.secret)
+            .bind(events_column(&subscription.events))
+            .bind(subscription.created_at)
+            .execute(&self.db_pool).await?;
+        Ok(subscription)
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<WebhookSubscription>, RepositoryError> {
+        let rows = query_as::<_, WebhookRow>(
+            r#"
+            SELECT id, url, secret, events, created_at FROM webhook_subscription
+            ORDER BY created_at, id
+            "#)
+            .fetch_all(&self.db_pool).await?;
+        Ok(rows.into_iter().map(WebhookSubscription::from).collect())
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<Option<WebhookSubscription>, RepositoryError> {
+        // The row is read first, under a lock, since there's no `RETURNING`.
+        let mut tx = self.db_pool.begin().await?;
+        let row = query_as::<_, WebhookRow>(
+            r#"
+            SELECT id, url, secret, events, created_at FROM webhook_subscription
+            WHERE id = ?
+            FOR UPDATE
+            "#)
+            .bind(id.hyphenated())
+            .fetch_optional(&mut tx).await?;
+        if row.is_some() {
+            sqlx::query(
+                r#"
+                DELETE FROM webhook_subscription
+                WHERE id = ?
+                "#)
+                .bind(id.hyphenated())
+                .execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(row.map(WebhookSubscription::from))
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        sqlx::query("SELECT 1").execute(&self.db_pool).await?;
+        Ok(())
+    }
+
+    async fn migration_version(&self) -> Result<Option<i64>, RepositoryError> {
+        let tables: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = '_sqlx_migrations'"
+        ).fetch_one(&self.db_pool).await?;
+        if tables == 0 {
+            return Ok(None);
+        }
+        let version = sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&self.db_pool).await?;
+        Ok(version)
+    }
+
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        vec![PoolStats::of("primary", &self.db_pool)]
+    }
+
+    fn shared_cache_stats(&self) -> Option<SharedCacheStats> {
+        None
+    }
+
+    fn slow_query_stats(&self) -> Option<SlowQueryStats> {
+        None
+    }
+}
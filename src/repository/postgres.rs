@@ -0,0 +1,941 @@
+use std::collections::HashMap;
+
+use async_std::channel::Sender;
+use async_std::stream::StreamExt;
+use sqlx::{PgPool, Postgres, Transaction, query_as, query_scalar};
+use sqlx::postgres::PgArguments;
+use sqlx::query::QueryAs;
+use sqlx::types::chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::{AuditAction, AuditEntry, Author, Book, NewAuthor, NewReview, RatedBook, Review, ScoredBook, WebhookSubscription, changes};
+use super::{AuditRecord, BookChange, BookFilter, BookPatch, BookRepository, Dialect, IMPORT_BATCH, IdempotencyKey, IdempotentResponse, ImportBatches, ImportCounts, ImportMode, Page, PoolStats, RETURNING_BOOK, RepositoryError, SharedCacheStats, SlowQueryStats, TableName, audit_entry, audit_sql, bulk_match_by_id_sql, bulk_match_sql, bulk_update_by_id_sql, bulk_update_sql, distinct_runs, duplicate_sql, events_column, filter_sql, idempotent_response_body, import_sql, like_escape, next_batch, page_sql, refuse_repeats, select_list, webhook_subscription};
+
+/// What Postgres reports for `similarity()` and `%` when `pg_trgm` isn't
+/// installed.
+const UNDEFINED_FUNCTION: &str = "42883";
+
+#[derive(sqlx::FromRow)]
+struct UpsertedBook {
+    #[sqlx(flatten)]
+    book: Book,
+    inserted: bool
+}
+
+#[derive(sqlx::FromRow)]
+struct AuditRow {
+    book_id: Uuid,
+    action: String,
+    old_value: Option<String>,
+    new_value: Option<String>,
+    created_at: DateTime<Utc>
+}
+
+#[derive(sqlx::FromRow)]
+struct WebhookRow {
+    id: Uuid,
+    url: String,
+    secret: String,
+    events: String,
+    created_at: DateTime<Utc>
+}
+
+impl From<WebhookRow> for WebhookSubscription {
+    fn from(row: WebhookRow) -> Self {
+        webhook_subscription(row.id, row.url, row.secret, &row.events, row.created_at)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PgBookRepository {
+    db_pool: PgPool,
+    replica_pool: Option<PgPool>,
+    table: TableName,
+    /// Tags this instance's notifications on `changes::CHANNEL`.
+    origin: Uuid
+}
+
+impl PgBookRepository {
+    pub fn new(db_pool: PgPool) -> Self {
+        PgBookRepository { db_pool, replica_pool: None, table: TableName::default(), origin: Uuid::new_v4() }
+    }
+
+    /// Sends reads to `replica_pool` and writes to `db_pool`.
+    pub fn with_replica(db_pool: PgPool, replica_pool: PgPool) -> Self {
+        PgBookRepository { db_pool, replica_pool: Some(replica_pool), table: TableName::default(), origin: Uuid::new_v4() }
+    }
+
+    /// Keeps books in `table` instead of `book`.
+    pub fn with_table(self, table: TableName) -> Self {
+        PgBookRepository { table, ..self }
+    }
+
+    /// What `changes::relay` for this instance is given, to tell the
+    /// changes it made itself from the other instances'.
+    pub fn origin(&self) -> Uuid {
+        self.origin
+    }
+
+    fn read_pool(&self) -> &PgPool {
+        self.replica_pool.as_ref().unwrap_or(&self.db_pool)
+    }
+
+    /// Fails with `Duplicate` if `book`'s name and author are taken. The
+    /// pair is locked until `tx` ends first, so a concurrent create of it
+    /// waits for this one and then sees its book: at `READ COMMITTED` each
+    /// statement reads what committed before it started.
+    async fn refuse_duplicate(&self, tx: &mut Transaction<'_, Postgres>, book: &Book) -> Result<(), RepositoryError> {
+        sqlx::query(
+            r#"
+            SELECT pg_advisory_xact_lock(hashtextextended($1 || E'\n' || lower($2) || E'\n' || lower($3), 0))
+            "#)
+            .bind(self.table.to_string())
+            .bind(&book.name)
+            .bind(&book.author)
+            .execute(&mut *tx).await?;
+        let existing = query_scalar::<_, Uuid>(&format!(
+            r#"
+            SELECT id FROM {book}
+            WHERE lower(name) = lower($1) AND lower(author) = lower($2)
+            LIMIT 1
+            "#, book = self.table))
+            .bind(&book.name)
+            .bind(&book.author)
+            .fetch_optional(&mut *tx).await?;
+        match existing {
+            Some(id) => Err(RepositoryError::Duplicate(id)),
+            None => Ok(()),
+        }
+    }
+
+    /// The book as it is before `tx` changes it, locked until `tx` ends.
+    async fn lock_book(&self, tx: &mut Transaction<'_, Postgres>, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            SELECT * FROM {book}
+            WHERE id = $1
+            FOR UPDATE
+            "#, book = self.table))
+            .bind(id)
+            .fetch_optional(&mut *tx).await?;
+        Ok(row)
+    }
+
+    /// Writes `record` to the audit log as part of `tx`, and tells the
+    /// other instances about the change on `changes::CHANNEL` once `tx`
+    /// commits.
+    async fn audit(&self, tx: &mut Transaction<'_, Postgres>, record: AuditRecord) -> Result<(), RepositoryError> {
+        let event = AuditAction::parse(record.action).expect("audit records have a known action").event();
+        let book = record.new_value.as_deref().or(record.old_value.as_deref()).expect("a change has a book before or after it");
+        let payload = changes::payload(self.origin, event, record.book_id, book);
+        sqlx::query(
+            r#"
+            INSERT INTO audit_log (book_id, action, old_value, new_value, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            "#)
+            .bind(record.book_id)
+            .bind(record.action)
+            .bind(record.old_value)
+            .bind(record.new_value)
+            .bind(Utc::now())
+            .execute(&mut *tx).await?;
+        self.notify(tx, payload).await
+    }
+
+    /// `audit` for a bulk write's `records` in one statement, telling the
+    /// other instances about them in one notification, which names their
+    /// rows in `audit_log`, rather than one each.
+    async fn audit_batch(&self, tx: &mut Transaction<'_, Postgres>, records: Vec<AuditRecord>) -> Result<(), RepositoryError> {
+        let ids = audit_rows(tx, records).await?;
+        for payload in changes::batch_payloads(self.origin, &ids) {
+            self.notify(tx, payload).await?;
+        }
+        Ok(())
+    }
+
+    /// Notifies `changes::CHANNEL` of `payload` once `tx` commits.
+    async fn notify(&self, tx: &mut Transaction<'_, Postgres>, payload: String) -> Result<(), RepositoryError> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(changes::CHANNEL)
+            .bind(payload)
+            .execute(&mut *tx).await?;
+        Ok(())
+    }
+
+    /// `refuse_duplicate` for all of `books`, new books written in one
+    /// statement: their pairs' advisory locks are taken in one go, in
+    /// order so that two imports can't deadlock on them, and then looked
+    /// up at once.
+    async fn refuse_duplicates(&self, tx: &mut Transaction<'_, Postgres>, books: &[&Book]) -> Result<(), RepositoryError> {
+        if books.is_empty() {
+            return Ok(());
+        }
+        refuse_repeats(books)?;
+        let names: Vec<Option<String>> = books.iter().map(|book| book.name.clone()).collect();
+        let authors: Vec<Option<String>> = books.iter().map(|book| book.author.clone()).collect();
+        sqlx::query(
+            r#"
+            SELECT pg_advisory_xact_lock(key) FROM (
+                SELECT DISTINCT hashtextextended($1 || E'\n' || lower(name) || E'\n' || lower(author), 0) AS key
+                FROM UNNEST($2::text[], $3::text[]) AS pair (name, author)
+            ) AS keys
+            ORDER BY key
+            "#)
+            .bind(self.table.to_string())
+            .bind(&names)
+            .bind(&authors)
+            .execute(&mut *tx).await?;
+        let sql = duplicate_sql(&self.table, books.len(), |n| format!("${}", n), |name, author| format!("lower(name) = lower({}) AND lower(author) = lower({})", name, author), "");
+        let mut query = query_scalar::<_, Uuid>(&sql);
+        for book in books {
+            query = query.bind(&book.name).bind(&book.author);
+        }
+        match query.fetch_optional(&mut *tx).await? {
+            Some(id) => Err(RepositoryError::Duplicate(id)),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes a run of an import's books, all with different ids, as part
+    /// of `tx`: locks the ones already stored, refuses new ones whose name
+    /// and author are taken, and upserts the rest in one statement and
+    /// audits them in another, adding them to `counts`.
+    async fn import_run(&self, tx: &mut Transaction<'_, Postgres>, books: Vec<Book>, counts: &mut ImportCounts) -> Result<(), RepositoryError> {
+        let sql = bulk_match_by_id_sql(&self.table, books.len(), |n| format!("${}", n), " FOR UPDATE");
+        let mut query = query_as::<_, Book>(&sql);
+        for book in &books {
+            query = query.bind(book.id);
+        }
+        let old: HashMap<Uuid, Book> = query.fetch_all(&mut *tx).await?.into_iter().map(|book| (book.id, book)).collect();
+        let (skipped, books): (Vec<Book>, Vec<Book>) = books.into_iter().partition(|book| old.get(&book.id) == Some(book));
+        counts.skipped += skipped.len() as u64;
+        let new: Vec<&Book> = books.iter().filter(|book| !old.contains_key(&book.id)).collect();
+        self.refuse_duplicates(tx, &new).await?;
+        if books.is_empty() {
+            return Ok(());
+        }
+
+        let sql = import_sql(&self.table, books.len(), |n| format!("${}", n), "now()", &format!(
+            r#"
+            ON CONFLICT (id) DO UPDATE
+            SET name = EXCLUDED.name, author = EXCLUDED.author, year = EXCLUDED.year,
+                published_date = EXCLUDED.published_date, publisher = EXCLUDED.publisher,
+                language = EXCLUDED.language, price = EXCLUDED.price, stock = EXCLUDED.stock,
+                updated_at = EXCLUDED.updated_at
+            {}
+            "#, RETURNING_BOOK));
+        let mut query = query_as::<_, Book>(&sql);
+        for book in books {
+            query = query
+                .bind(book.id)
+                .bind(book.name)
+                .bind(book.author)
+                .bind(book.year)
+                .bind(book.published_date)
+                .bind(book.publisher)
+                .bind(book.language)
+                .bind(book.price)
+                .bind(book.stock);
+        }
+        let rows = query.fetch_all(&mut *tx).await?;
+        let mut records = Vec::with_capacity(rows.len());
+        for row in rows {
+            match old.get(&row.id) {
+                Some(old) => {
+                    counts.updated += 1;
+                    records.push(AuditRecord::updated(old, &row));
+                }
+                None => {
+                    counts.inserted += 1;
+                    records.push(AuditRecord::created(&row));
+                }
+            }
+        }
+        audit_rows(tx, records).await?;
+        Ok(())
+    }
+}
+
+/// Writes `records` to the audit log in one statement as part of `tx`,
+/// returning their rows' ids.
+async fn audit_rows(tx: &mut Transaction<'_, Postgres>, records: Vec<AuditRecord>) -> Result<Vec<i64>, RepositoryError> {
+    if records.is_empty() {
+        return Ok(Vec::new());
+    }
+    let sql = audit_sql(records.len(), |n| format!("${}", n), " RETURNING id");
+    let now = Utc::now();
+    let mut query = query_scalar::<_, i64>(&sql);
+    for record in records {
+        query = query
+            .bind(record.book_id)
+            .bind(record.action)
+            .bind(record.old_value)
+            .bind(record.new_value)
+            .bind(now);
+    }
+    Ok(query.fetch_all(&mut *tx).await?)
+}
+
+/// Deletes the reviews, idempotency keys and author links of the books
+/// `ids` as part of `tx`. They can't cascade from the books table, which
+/// `TABLE_NAME` may name.
+async fn delete_dependents(tx: &mut Transaction<'_, Postgres>, ids: &[Uuid]) -> Result<(), RepositoryError> {
+    for table in ["review", "idempotency_key", "book_author"] {
+        sqlx::query(&format!("DELETE FROM {} WHERE book_id = ANY($1)", table))
+            .bind(ids)
+            .execute(&mut *tx).await?;
+    }
+    Ok(())
+}
+
+/// `filter_sql` in Postgres's dialect.
+fn where_clause(filter: &BookFilter) -> String {
+    filter_sql(filter, &Dialect {
+        placeholder: |n| format!("${}", n),
+        contains: |column, term| format!("{} ILIKE '%' || {} || '%' ESCAPE '!'", column, term),
+        at_most: |column, value| format!("{} <= {}", column, value)
+    })
+}
+
+/// Binds `filter`'s values in the order `where_clause` numbers them.
+fn bind_filter<'q, O>(mut query: QueryAs<'q, Postgres, O, PgArguments>, filter: &'q BookFilter) -> QueryAs<'q, Postgres, O, PgArguments> {
+    if let Some(published_after) = filter.published_after {
+        query = query.bind(published_after);
+    }
+    if let Some(name_contains) = &filter.name_contains {
+        query = query.bind(like_escape(name_contains));
+    }
+    if let Some(publisher) = &filter.publisher {
+        query = query.bind(like_escape(publisher));
+    }
+    if let Some(language) = &filter.language {
+        query = query.bind(language);
+    }
+    if let Some(max_price) = filter.max_price {
+        query = query.bind(max_price);
+    }
+    if let Some(author) = &filter.author {
+        query = query.bind(like_escape(author));
+    }
+    if let Some(year_min) = filter.year_min {
+        query = query.bind(year_min);
+    }
+    query
+}
+
+/// Binds `patch`'s values in `BookPatch::columns` order. The bulk UPDATEs
+/// go through it too, as queries that fetch no rows.
+fn bind_patch<'q, O>(mut query: QueryAs<'q, Postgres, O, PgArguments>, patch: &'q BookPatch) -> QueryAs<'q, Postgres, O, PgArguments> {
+    if let Some(name) = &patch.name {
+        query = query.bind(name);
+    }
+    if let Some(author) = &patch.author {
+        query = query.bind(author);
+    }
+    if let Some(year) = patch.year {
+        query = query.bind(year);
+    }
+    if let Some(publisher) = &patch.publisher {
+        query = query.bind(publisher);
+    }
+    if let Some(language) = &patch.language {
+        query = query.bind(language);
+    }
+    if let Some(price) = patch.price {
+        query = query.bind(price);
+    }
+    if let Some(stock) = patch.stock {
+        query = query.bind(stock);
+    }
+    query
+}
+
+#[tide::utils::async_trait]
+impl BookRepository for PgBookRepository {
+    async fn create_book(&self, book: Book) -> Result<Book, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        self.refuse_duplicate(&mut tx, &book).await?;
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now())
+            RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at
+            "#, book = self.table))
+            .bind(book.id)
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .fetch_one(&mut tx).await?;
+        self.audit(&mut tx, AuditRecord::created(&row)).await?;
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    async fn find_idempotent_response(&self, key: &IdempotencyKey) -> Result<Option<IdempotentResponse>, RepositoryError> {
+        let row = query_as::<_, IdempotentResponse>(
+            r#"
+            SELECT request_hash, response_body FROM idempotency_key
+            WHERE scope = $1 AND key = $2 AND created_at >= $3
+            "#)
+            .bind(key.scope)
+            .bind(&key.key)
+            .bind(key.expires_before)
+            .fetch_optional(&self.db_pool).await?;
+        Ok(row)
+    }
+
+    async fn create_book_with_key(&self, book: Book, key: &IdempotencyKey) -> Result<Book, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        sqlx::query(
+            r#"
+            DELETE FROM idempotency_key
+            WHERE created_at < $1
+            "#)
+            .bind(key.expires_before)
+            .execute(&mut tx).await?;
+        self.refuse_duplicate(&mut tx, &book).await?;
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now())
+            RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at
+            "#, book = self.table))
+            .bind(book.id)
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .fetch_one(&mut tx).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO idempotency_key (scope, key, book_id, request_hash, response_body, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            "#)
+            .bind(key.scope)
+            .bind(&key.key)
+            .bind(row.id)
+            .bind(&key.request_hash)
+            .bind(idempotent_response_body(&row))
+            .bind(Utc::now())
+            .execute(&mut tx).await?;
+        self.audit(&mut tx, AuditRecord::created(&row)).await?;
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    async fn create_book_with_author(&self, book: Book, author: NewAuthor) -> Result<(Book, Author), RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        self.refuse_duplicate(&mut tx, &book).await?;
+        let author = query_as::<_, Author>(
+            r#"
+            INSERT INTO author (id, name)
+            VALUES ($1, $2)
+            RETURNING id, name
+            "#)
+            .bind(Uuid::new_v4())
+            .bind(author.name)
+            .fetch_one(&mut tx).await?;
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now())
+            RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at
+            "#, book = self.table))
+            .bind(book.id)
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .fetch_one(&mut tx).await?;
+        sqlx::query(
+            r#"
+            INSERT INTO book_author (book_id, author_id)
+            VALUES ($1, $2)
+            "#)
+            .bind(row.id)
+            .bind(author.id)
+            .execute(&mut tx).await?;
+        self.audit(&mut tx, AuditRecord::created(&row)).await?;
+        tx.commit().await?;
+        Ok((row, author))
+    }
+
+    async fn list_books(&self, columns: &[&str], filter: &BookFilter, page: Option<Page>) -> Result<Vec<Book>, RepositoryError> {
+        let sql = format!(
+            r#"
+            SELECT {columns} FROM {book}
+            {filter}
+            ORDER BY id
+            {page}
+            "#, columns = select_list(columns), book = self.table, filter = where_clause(filter), page = page_sql(page));
+        let rows = bind_filter(query_as::<_, Book>(&sql), filter).fetch_all(self.read_pool()).await?;
+        Ok(rows)
+    }
+
+    async fn count_books(&self, filter: &BookFilter) -> Result<u64, RepositoryError> {
+        let sql = format!(
+            r#"
+            SELECT COUNT(*) FROM {book}
+            {filter}
+            "#, book = self.table, filter = where_clause(filter));
+        let (count,) = bind_filter(query_as::<_, (i64,)>(&sql), filter).fetch_one(self.read_pool()).await?;
+        Ok(count as u64)
+    }
+
+    async fn get_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            SELECT * FROM {book}
+            WHERE id = $1
+            "#, book = self.table))
+            .bind(id)
+            .fetch_optional(self.read_pool()).await?;
+        Ok(row)
+    }
+
+    async fn search_books(&self, term: &str, threshold: f32, limit: u32) -> Result<Vec<ScoredBook>, RepositoryError> {
+        // `%` is what the trigram indexes can answer, and it compares
+        // against this setting; `set_config(..., true)` keeps it to the
+        // transaction.
+        let mut tx = self.read_pool().begin().await?;
+        sqlx::query("SELECT set_config('pg_trgm.similarity_threshold', $1, true)")
+            .bind(threshold.to_string())
+            .execute(&mut tx).await?;
+        let rows = query_as::<_, ScoredBook>(&format!(
+            r#"
+            SELECT id, name, author, year, published_date, publisher, language, price, stock,
+                GREATEST(similarity(name, $1), similarity(author, $1)) AS score
+            FROM {book}
+            WHERE name % $1 OR author % $1
+            ORDER BY score DESC, id
+            LIMIT {limit}
+            "#, book = self.table, limit = limit))
+            .bind(term)
+            .fetch_all(&mut tx).await
+            .map_err(|err| match &err {
+                sqlx::Error::Database(db_err) if db_err.code().as_deref() == Some(UNDEFINED_FUNCTION) => {
+                    RepositoryError::Unsupported("fuzzy search needs the pg_trgm extension")
+                }
+                _ => RepositoryError::from(err),
+            })?;
+        tx.commit().await?;
+        Ok(rows)
+    }
+
+    async fn random_book(&self) -> Result<Option<Book>, RepositoryError> {
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            SELECT * FROM {book}
+            ORDER BY random()
+            LIMIT 1
+            "#, book = self.table))
+            .fetch_optional(self.read_pool()).await?;
+        Ok(row)
+    }
+
+    async fn similar_books(&self, book: &Book, limit: u32) -> Result<Vec<Book>, RepositoryError> {
+        let Some(author) = &book.author else {
+            return Ok(Vec::new());
+        };
+        let rows = query_as::<_, Book>(&format!(
+            r#"
+            SELECT * FROM {book}
+            WHERE lower(author) = lower($1) AND id <> $2
+            ORDER BY id
+            LIMIT {limit}
+            "#, book = self.table, limit = limit))
+            .bind(author)
+            .bind(book.id)
+            .fetch_all(self.read_pool()).await?;
+        Ok(rows)
+    }
+
+    async fn book_exists(&self, id: Uuid) -> Result<bool, RepositoryError> {
+        let row = sqlx::query(&format!(
+            r#"
+            SELECT 1 FROM {book}
+            WHERE id = $1
+            "#, book = self.table))
+            .bind(id)
+            .fetch_optional(self.read_pool()).await?;
+        Ok(row.is_some())
+    }
+
+    async fn get_rated_book(&self, id: Uuid) -> Result<Option<RatedBook>, RepositoryError> {
+        let row = query_as::<_, RatedBook>(&format!(
+            r#"
+            SELECT b.id, b.name, b.author, b.year, b.published_date, b.publisher, b.language, b.price, b.stock,
+                AVG(r.rating)::FLOAT8 AS avg_rating,
+                COUNT(r.id) AS review_count
+            FROM {book} b
+            LEFT JOIN review r ON r.book_id = b.id
+            WHERE b.id = $1
+            GROUP BY b.id
+            "#, book = self.table))
+            .bind(id)
+            .fetch_optional(self.read_pool()).await?;
+        Ok(row)
+    }
+
+    async fn update_book(&self, id: Uuid, book: Book) -> Result<Option<Book>, RepositoryError> {
+        self.change_book(id, &|_| Ok(book.clone())).await
+    }
+
+    async fn change_book(&self, id: Uuid, change: &BookChange<'_>) -> Result<Option<Book>, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let old = match self.lock_book(&mut tx, id).await? {
+            Some(old) => old,
+            None => return Ok(None),
+        };
+        let book = change(old.clone())?;
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            UPDATE {book}
+            SET name = $2, author = $3, year = $4, published_date = $5, publisher = $6, language = $7, price = $8, stock = $9, updated_at = now()
+            WHERE id = $1
+            RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at
+            "#, book = self.table))
+            .bind(id)
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .fetch_one(&mut tx).await?;
+        self.audit(&mut tx, AuditRecord::updated(&old, &row)).await?;
+        tx.commit().await?;
+        Ok(Some(row))
+    }
+
+    async fn upsert_book(&self, id: Uuid, book: Book) -> Result<(Book, bool), RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let old = self.lock_book(&mut tx, id).await?;
+        // `xmax` is only zero on a freshly inserted row version.
+        let row = query_as::<_, UpsertedBook>(&format!(
+            r#"
+            INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now())
+            ON CONFLICT (id) DO UPDATE
+            SET name = EXCLUDED.name, author = EXCLUDED.author, year = EXCLUDED.year,
+                published_date = EXCLUDED.published_date, publisher = EXCLUDED.publisher,
+                language = EXCLUDED.language, price = EXCLUDED.price, stock = EXCLUDED.stock,
+                updated_at = EXCLUDED.updated_at
+            RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at, (xmax = 0) AS inserted
+            "#, book = self.table))
+            .bind(id)
+            .bind(book.name)
+            .bind(book.author)
+            .bind(book.year)
+            .bind(book.published_date)
+            .bind(book.publisher)
+            .bind(book.language)
+            .bind(book.price)
+            .bind(book.stock)
+            .fetch_one(&mut tx).await?;
+        let record = match &old {
+            Some(old) if !row.inserted => AuditRecord::updated(old, &row.book),
+            _ => AuditRecord::created(&row.book),
+        };
+        self.audit(&mut tx, record).await?;
+        tx.commit().await?;
+        Ok((row.book, row.inserted))
+    }
+
+    async fn update_books(&self, filter: &BookPatch, set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        let sql = bulk_update_sql(&self.table, filter, set, |n| format!("${}", n), "now()", RETURNING_BOOK);
+        let mut tx = self.db_pool.begin().await?;
+        let matched_sql = bulk_match_sql(&self.table, filter, |n| format!("${}", n), " FOR UPDATE");
+        let matched = bind_patch(query_as::<_, Book>(&matched_sql), filter).fetch_all(&mut tx).await?;
+        let updated = bind_patch(bind_patch(query_as::<_, Book>(&sql), set), filter).fetch_all(&mut tx).await?;
+        let records = matched.iter()
+            .filter_map(|old| Some(set.apply(old)).filter(|new| new != old).map(|new| AuditRecord::updated(old, &new)))
+            .collect();
+        self.audit_batch(&mut tx, records).await?;
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    async fn update_books_by_id(&self, ids: &[Uuid], set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let sql = bulk_update_by_id_sql(&self.table, set, ids.len(), |n| format!("${}", n), "now()", RETURNING_BOOK);
+        let mut query = bind_patch(query_as::<_, Book>(&sql), set);
+        for id in ids {
+            query = query.bind(id);
+        }
+        let mut tx = self.db_pool.begin().await?;
+        let matched_sql = bulk_match_by_id_sql(&self.table, ids.len(), |n| format!("${}", n), " FOR UPDATE");
+        let mut matched = query_as::<_, Book>(&matched_sql);
+        for id in ids {
+            matched = matched.bind(id);
+        }
+        let matched = matched.fetch_all(&mut tx).await?;
+        let updated = query.fetch_all(&mut tx).await?;
+        let records = matched.iter()
+            .filter_map(|old| Some(set.apply(old)).filter(|new| new != old).map(|new| AuditRecord::updated(old, &new)))
+            .collect();
+        self.audit_batch(&mut tx, records).await?;
+        tx.commit().await?;
+        Ok(updated)
+    }
+
+    async fn checkout_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            UPDATE {book}
+            SET stock = stock - 1, updated_at = now()
+            WHERE id = $1 AND stock > 0
+            RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at
+            "#, book = self.table))
+            .bind(id)
+            .fetch_optional(&mut tx).await?;
+        if let Some(row) = &row {
+            let old = Book { stock: row.stock.map(|stock| stock + 1), ..row.clone() };
+            self.audit(&mut tx, AuditRecord::updated(&old, row)).await?;
+        }
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    async fn delete_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let row = query_as::<_, Book>(&format!(
+            r#"
+            DELETE FROM {book}
+            WHERE id = $1
+            RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at
+            "#, book = self.table))
+            .bind(id)
+            .fetch_optional(&mut tx).await?;
+        if let Some(row) = &row {
+            delete_dependents(&mut tx, &[row.id]).await?;
+            self.audit(&mut tx, AuditRecord::deleted(row)).await?;
+        }
+        tx.commit().await?;
+        Ok(row)
+    }
+
+    async fn export_books(&self, filter: &BookFilter, counted: Sender<u64>, out: Sender<Book>) -> Result<u64, RepositoryError> {
+        let mut tx = self.read_pool().begin().await?;
+        sqlx::query("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ, READ ONLY").execute(&mut tx).await?;
+        let sql = format!("SELECT COUNT(*) FROM {} {}", self.table, where_clause(filter));
+        let (count,) = bind_filter(query_as::<_, (i64,)>(&sql), filter).fetch_one(&mut tx).await?;
+        // Not wanted by every export.
+        let _ = counted.send(count as u64).await;
+        let sql = format!("SELECT * FROM {} {} ORDER BY id", self.table, where_clause(filter));
+        let mut rows = bind_filter(query_as::<_, Book>(&sql), filter).fetch(&mut tx);
+        let mut exported = 0;
+        while let Some(row) = rows.next().await {
+            if out.send(row?).await.is_err() {
+                break;
+            }
+            exported += 1;
+        }
+        Ok(exported)
+    }
+
+    async fn restore_books(&self, books: Vec<Book>) -> Result<Vec<Book>, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let mut restored = Vec::with_capacity(books.len());
+        for book in books {
+            self.refuse_duplicate(&mut tx, &book).await?;
+            let row = query_as::<_, Book>(&format!(
+                r#"
+                INSERT INTO {book} (id, name, author, year, published_date, publisher, language, price, stock, updated_at)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, now())
+                RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at
+                "#, book = self.table))
+                .bind(book.id)
+                .bind(book.name)
+                .bind(book.author)
+                .bind(book.year)
+                .bind(book.published_date)
+                .bind(book.publisher)
+                .bind(book.language)
+                .bind(book.price)
+                .bind(book.stock)
+                .fetch_one(&mut tx).await?;
+            restored.push(row);
+        }
+        self.audit_batch(&mut tx, restored.iter().map(AuditRecord::created).collect()).await?;
+        tx.commit().await?;
+        Ok(restored)
+    }
+
+    async fn import_books(&self, books: ImportBatches, mode: ImportMode) -> Result<ImportCounts, RepositoryError> {
+        let mut tx = self.db_pool.begin().await?;
+        let mut counts = ImportCounts::default();
+        if mode == ImportMode::Replace {
+            loop {
+                let deleted = query_as::<_, Book>(&format!(
+                    r#"
+                    DELETE FROM {book}
+                    WHERE id IN (SELECT id FROM {book} LIMIT {batch})
+                    RETURNING id, name, author, year, published_date, publisher, language, price, stock, updated_at
+                    "#, book = self.table, batch = IMPORT_BATCH))
+                    .fetch_all(&mut tx).await?;
+                if deleted.is_empty() {
+                    break;
+                }
+                let ids: Vec<Uuid> = deleted.iter().map(|row| row.id).collect();
+                delete_dependents(&mut tx, &ids).await?;
+                audit_rows(&mut tx, deleted.iter().map(AuditRecord::deleted).collect()).await?;
+                counts.deleted += deleted.len() as u64;
+            }
+        }
+        while let Some(batch) = next_batch(&books).await? {
+            for run in distinct_runs(batch) {
+                self.import_run(&mut tx, run, &mut counts).await?;
+            }
+        }
+        // One summary, not the changes, however many books there were.
+        self.notify(&mut tx, changes::import_payload(self.origin, &counts)).await?;
+        tx.commit().await?;
+        Ok(counts)
+    }
+
+    async fn book_history(&self, id: Uuid, limit: u32) -> Result<Vec<AuditEntry>, RepositoryError> {
+        let rows = query_as::<_, AuditRow>(&format!(
+            r#"
+            SELECT book_id, action, old_value, new_value, created_at FROM audit_log
+            WHERE book_id = $1
+            ORDER BY id
+            LIMIT {}
+            "#, limit))
+            .bind(id)
+            .fetch_all(self.read_pool()).await?;
+        rows.into_iter()
+            .map(|row| audit_entry(row.book_id, &row.action, row.old_value, row.new_value, row.created_at))
+            .collect()
+    }
+
+    async fn create_review(&self, book_id: Uuid, review: NewReview) -> Result<Option<Review>, RepositoryError> {
+        // The share lock holds off a delete of the book until the review is
+        // in, which it then deletes too.
+        let row = query_as::<_, Review>(&format!(
+            r#"
+            INSERT INTO review (id, book_id, rating, text)
+            SELECT $1, $2, $3, $4
+            WHERE EXISTS (SELECT 1 FROM {book} WHERE id = $2 FOR SHARE)
+            RETURNING id, book_id, rating, text, created_at
+            "#, book = self.table))
+            .bind(Uuid::new_v4())
+            .bind(book_id)
+            .bind(review.rating)
+            .bind(review.text)
+            .fetch_optional(&self.db_pool).await?;
+        Ok(row)
+    }
+
+    async fn list_reviews(&self, book_id: Uuid, limit: u32) -> Result<Vec<Review>, RepositoryError> {
+        let rows = query_as::<_, Review>(&format!(
+            r#"
+            SELECT * FROM review
+            WHERE book_id = $1
+            ORDER BY created_at
+            LIMIT {}
+            "#, limit))
+            .bind(book_id)
+            .fetch_all(self.read_pool()).await?;
+        Ok(rows)
+    }
+
+    async fn list_reviews_for_books(&self, book_ids: &[Uuid]) -> Result<Vec<Review>, RepositoryError> {
+        let rows = query_as::<_, Review>(
+            r#"
+            SELECT * FROM review
+            WHERE book_id = ANY($1)
+            ORDER BY created_at
+            "#)
+            .bind(book_ids)
+            .fetch_all(self.read_pool()).await?;
+        Ok(rows)
+    }
+
+    async fn create_webhook(&self, subscription: WebhookSubscription) -> Result<WebhookSubscription, RepositoryError> {
+        let row = query_as::<_, WebhookRow>(
+            r#"
+            INSERT INTO webhook_subscription (id, url, secret, events, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, url, secret, events, created_at
+            "#)
+            .bind(subscription.id)
+            .bind(&subscription.url)
+            .bind(&subscription.secret)
+            .bind(events_column(&subscription.events))
+            .bind(subscription.created_at)
+            .fetch_one(&self.db_pool).await?;
+        Ok(row.into())
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<WebhookSubscription>, RepositoryError> {
+        let rows = query_as::<_, WebhookRow>(
+            r#"
+            SELECT id, url, secret, events, created_at FROM webhook_subscription
+            ORDER BY created_at, id
+            "#)
+            .fetch_all(self.read_pool()).await?;
+        Ok(rows.into_iter().map(WebhookSubscription::from).collect())
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<Option<WebhookSubscription>, RepositoryError> {
+        let row = query_as::<_, WebhookRow>(
+            r#"
+            DELETE FROM webhook_subscription
+            WHERE id = $1
+            RETURNING id, url, secret, events, created_at
+            "#)
+            .bind(id)
+            .fetch_optional(&self.db_pool).await?;
+        Ok(row.map(WebhookSubscription::from))
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        sqlx::query("SELECT 1").execute(&self.db_pool).await?;
+        Ok(())
+    }
+
+    async fn migration_version(&self) -> Result<Option<i64>, RepositoryError> {
+        let migrated: bool = sqlx::query_scalar(
+            "SELECT to_regclass('_sqlx_migrations') IS NOT NULL"
+        ).fetch_one(&self.db_pool).await?;
+        if !migrated {
+            return Ok(None);
+        }
+        let version = sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+            .fetch_one(&self.db_pool).await?;
+        Ok(version)
+    }
+
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        let mut stats = vec![PoolStats::of("primary", &self.db_pool)];
+        stats.extend(self.replica_pool.iter().map(|replica_pool| PoolStats::of("replica", replica_pool)));
+        stats
+    }
+
+    fn shared_cache_stats(&self) -> Option<SharedCacheStats> {
+        None
+    }
+
+    fn slow_query_stats(&self) -> Option<SlowQueryStats> {
+        None
+    }
+}
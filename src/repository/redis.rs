@@ -0,0 +1,428 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use async_std::channel::Sender;
+use redis::aio::MultiplexedConnection;
+use redis::{AsyncConnectionConfig, Cmd, ErrorKind, FromRedisValue, RedisError, RedisResult};
+use serde::{Deserialize, Serialize};
+use sqlx::types::chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::{AuditEntry, Author, Book, NewAuthor, NewReview, RatedBook, Review, ScoredBook, WebhookSubscription};
+use super::{BookChange, BookFilter, BookPatch, BookRepository, IdempotencyKey, IdempotentResponse, ImportBatches, ImportCounts, ImportMode, Page, PoolStats, RepositoryError, SharedCacheStats, SlowQueryStats};
+
+/// How long connecting, and then each command, may take before Redis
+/// counts as down.
+const TIMEOUT: Duration = Duration::from_millis(500);
+/// How long Redis is left alone after it failed, so requests don't each
+/// wait out `TIMEOUT` while it's down.
+const RETRY_AFTER: Duration = Duration::from_secs(1);
+/// Books are stored under this followed by their id.
+const KEY_PREFIX: &str = "book:";
+
+/// A client for one Redis server, over a single multiplexed connection
+/// that's opened on the first command and again on the next one after it
+/// failed.
+#[derive(Debug)]
+pub struct RedisClient {
+    client: redis::Client,
+    conn: async_std::sync::Mutex<Option<MultiplexedConnection>>,
+    down_until: Mutex<Option<Instant>>
+}
+
+impl RedisClient {
+    /// The server at `redis_url`, `redis://[[user]:password@]host[:port][/db]`.
+    /// Nothing is connected to until the first command.
+    pub fn new(redis_url: &str) -> RedisResult<Self> {
+        Ok(RedisClient {
+            client: redis::Client::open(redis_url)?,
+            conn: async_std::sync::Mutex::new(None),
+            down_until: Mutex::new(None)
+        })
+    }
+
+    /// The server at `redis_url`, when it's set. An invalid URL is logged
+    /// and leaves the cache off.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let redis_url = config.redis_url.as_deref()?;
+        match RedisClient::new(redis_url) {
+            Ok(client) => Some(client),
+            Err(e) => {
+                tracing::error!("ignoring REDIS_URL {}: {}", redis_url, e);
+                None
+            }
+        }
+    }
+
+    /// Runs one command. A failure to reach Redis is logged and keeps it
+    /// from being tried again for `RETRY_AFTER`; an error Redis replied
+    /// with is only returned.
+    async fn call<T: FromRedisValue>(&self, cmd: &Cmd) -> RedisResult<T> {
+        if self.down_until.lock().unwrap().is_some_and(|until| Instant::now() < until) {
+            return Err(RedisError::from((ErrorKind::IoError, "Redis failed moments ago")));
+        }
+        let mut conn = self.conn.lock().await;
+        let result = match conn.as_mut() {
+            Some(conn) => cmd.query_async(conn).await,
+            None => match self.connect().await {
+                Ok(mut connected) => {
+                    let result = cmd.query_async(&mut connected).await;
+                    *conn = Some(connected);
+                    result
+                }
+                Err(e) => Err(e),
+            },
+        };
+        match result {
+            Err(e) if e.is_io_error() || e.is_timeout() || e.is_connection_dropped() || e.is_connection_refusal() => {
+                *conn = None;
+                *self.down_until.lock().unwrap() = Some(Instant::now() + RETRY_AFTER);
+                tracing::warn!("Redis failed, serving books from the database for {:?}: {}", RETRY_AFTER, e);
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    async fn connect(&self) -> RedisResult<MultiplexedConnection> {
+        let config = AsyncConnectionConfig::new().set_connection_timeout(TIMEOUT).set_response_timeout(TIMEOUT);
+        self.client.get_multiplexed_async_connection_with_config(&config).await
+    }
+}
+
+fn key(id: Uuid) -> String {
+    format!("{}{}", KEY_PREFIX, id)
+}
+
+/// A book as it's stored in Redis: its JSON, and beside it `updated_at`,
+/// which that leaves out.
+#[derive(Deserialize, Serialize)]
+struct CachedBook {
+    #[serde(flatten)]
+    book: Book,
+    updated_at: Option<DateTime<Utc>>
+}
+
+/// Wraps a repository and keeps the books `get_book` reads in Redis, for
+/// `ttl`, where every instance sees them. A write deletes the books it
+/// touched once it's done. A read that fetched a book before a write
+/// committed can still put the old book back after the write's delete;
+/// `ttl` bounds how long that's served, which is why it's short. When
+/// Redis can't be reached the books come from the database, as if nothing
+/// were cached.
+#[derive(Debug)]
+pub struct RedisCached<R> {
+    inner: R,
+    redis: RedisClient,
+    ttl: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    errors: AtomicU64
+}
+
+impl<R: BookRepository> RedisCached<R> {
+    pub fn new(inner: R, redis: RedisClient, ttl: Duration) -> Self {
+        RedisCached { inner, redis, ttl, hits: AtomicU64::new(0), misses: AtomicU64::new(0), errors: AtomicU64::new(0) }
+    }
+
+    /// Caches for `redis_book_ttl_ms`.
+    pub fn from_config(inner: R, redis: RedisClient, config: &Config) -> Self {
+        RedisCached::new(inner, redis, Duration::from_millis(config.redis_book_ttl_ms))
+    }
+
+    async fn cached(&self, id: Uuid) -> Option<Book> {
+        match self.redis.call::<Option<Vec<u8>>>(redis::cmd("GET").arg(key(id))).await {
+            Ok(Some(json)) => match serde_json::from_slice::<CachedBook>(&json) {
+                Ok(cached) => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Some(Book { updated_at: cached.updated_at, ..cached.book });
+                }
+                Err(e) => {
+                    tracing::warn!("ignoring cached book {} that doesn't parse: {}", id, e);
+                    self.errors.fetch_add(1, Ordering::Relaxed);
+                }
+            },
+            Ok(None) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        None
+    }
+
+    async fn backfill(&self, book: &Book) {
+        let cached = CachedBook { book: book.clone(), updated_at: book.updated_at };
+        let Ok(json) = serde_json::to_vec(&cached) else { return };
+        let set = redis::cmd("SET").arg(key(book.id)).arg(json).arg("PX").arg(self.ttl.as_millis() as u64).clone();
+        if self.redis.call::<()>(&set).await.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Deletes the cached `ids`, after a write to them whether or not it
+    /// reported success, since it may have committed anyway.
+    async fn forget(&self, ids: &[Uuid]) {
+        if ids.is_empty() {
+            return;
+        }
+        let keys: Vec<String> = ids.iter().map(|id| key(*id)).collect();
+        if self.redis.call::<()>(redis::cmd("DEL").arg(keys)).await.is_err() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Deletes every cached book, for writes that don't say which books
+    /// they touched.
+    async fn forget_all(&self) {
+        let pattern = format!("{}*", KEY_PREFIX);
+        let mut cursor = 0;
+        loop {
+            let scan = redis::cmd("SCAN").arg(cursor).arg("MATCH").arg(&pattern).arg("COUNT").arg(1000).clone();
+            let Ok((next, keys)) = self.redis.call::<(u64, Vec<String>)>(&scan).await else {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                return;
+            };
+            if !keys.is_empty() && self.redis.call::<()>(redis::cmd("DEL").arg(keys)).await.is_err() {
+                self.errors.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            if next == 0 {
+                return;
+            }
+            cursor = next;
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<R: BookRepository> BookRepository for RedisCached<R> {
+    async fn create_book(&self, book: Book) -> Result<Book, RepositoryError> {
+        self.inner.create_book(book).await
+    }
+
+    async fn find_idempotent_response(&self, key: &IdempotencyKey) -> Result<Option<IdempotentResponse>, RepositoryError> {
+        self.inner.find_idempotent_response(key).await
+    }
+
+    async fn create_book_with_key(&self, book: Book, key: &IdempotencyKey) -> Result<Book, RepositoryError> {
+        self.inner.create_book_with_key(book, key).await
+    }
+
+    async fn create_book_with_author(&self, book: Book, author: NewAuthor) -> Result<(Book, Author), RepositoryError> {
+        self.inner.create_book_with_author(book, author).await
+    }
+
+    async fn list_books(&self, columns: &[&str], filter: &BookFilter, page: Option<Page>) -> Result<Vec<Book>, RepositoryError> {
+        self.inner.list_books(columns, filter, page).await
+    }
+
+    async fn count_books(&self, filter: &BookFilter) -> Result<u64, RepositoryError> {
+        self.inner.count_books(filter).await
+    }
+
+    async fn search_books(&self, term: &str, threshold: f32, limit: u32) -> Result<Vec<ScoredBook>, RepositoryError> {
+        self.inner.search_books(term, threshold, limit).await
+    }
+
+    async fn get_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        if let Some(book) = self.cached(id).await {
+            return Ok(Some(book));
+        }
+        let book = self.inner.get_book(id).await?;
+        if let Some(book) = &book {
+            self.backfill(book).await;
+        }
+        Ok(book)
+    }
+
+    async fn random_book(&self) -> Result<Option<Book>, RepositoryError> {
+        self.inner.random_book().await
+    }
+
+    async fn similar_books(&self, book: &Book, limit: u32) -> Result<Vec<Book>, RepositoryError> {
+        self.inner.similar_books(book, limit).await
+    }
+
+    async fn book_exists(&self, id: Uuid) -> Result<bool, RepositoryError> {
+        self.inner.book_exists(id).await
+    }
+
+    async fn get_rated_book(&self, id: Uuid) -> Result<Option<RatedBook>, RepositoryError> {
+        self.inner.get_rated_book(id).await
+    }
+
+    async fn update_book(&self, id: Uuid, book: Book) -> Result<Option<Book>, RepositoryError> {
+        let updated = self.inner.update_book(id, book).await;
+        self.forget(&[id]).await;
+        updated
+    }
+
+    async fn change_book(&self, id: Uuid, change: &BookChange<'_>) -> Result<Option<Book>, RepositoryError> {
+        let changed = self.inner.change_book(id, change).await;
+        self.forget(&[id]).await;
+        changed
+    }
+
+    async fn upsert_book(&self, id: Uuid, book: Book) -> Result<(Book, bool), RepositoryError> {
+        let upserted = self.inner.upsert_book(id, book).await;
+        self.forget(&[id]).await;
+        upserted
+    }
+
+    async fn update_books(&self, filter: &BookPatch, set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        let updated = self.inner.update_books(filter, set).await;
+        self.forget_all().await;
+        updated
+    }
+
+    async fn update_books_by_id(&self, ids: &[Uuid], set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        let updated = self.inner.update_books_by_id(ids, set).await;
+        self.forget(ids).await;
+        updated
+    }
+
+    async fn checkout_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let checked_out = self.inner.checkout_book(id).await;
+        self.forget(&[id]).await;
+        checked_out
+    }
+
+    async fn delete_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        let deleted = self.inner.delete_book(id).await;
+        self.forget(&[id]).await;
+        deleted
+    }
+
+    async fn export_books(&self, filter: &BookFilter, counted: Sender<u64>, out: Sender<Book>) -> Result<u64, RepositoryError> {
+        self.inner.export_books(filter, counted, out).await
+    }
+
+    async fn restore_books(&self, books: Vec<Book>) -> Result<Vec<Book>, RepositoryError> {
+        self.inner.restore_books(books).await
+    }
+
+    async fn import_books(&self, books: ImportBatches, mode: ImportMode) -> Result<ImportCounts, RepositoryError> {
+        let imported = self.inner.import_books(books, mode).await;
+        self.forget_all().await;
+        imported
+    }
+
+    async fn book_history(&self, id: Uuid, limit: u32) -> Result<Vec<AuditEntry>, RepositoryError> {
+        self.inner.book_history(id, limit).await
+    }
+
+    async fn create_review(&self, book_id: Uuid, review: NewReview) -> Result<Option<Review>, RepositoryError> {
+        self.inner.create_review(book_id, review).await
+    }
+
+    async fn list_reviews(&self, book_id: Uuid, limit: u32) -> Result<Vec<Review>, RepositoryError> {
+        self.inner.list_reviews(book_id, limit).await
+    }
+
+    async fn list_reviews_for_books(&self, book_ids: &[Uuid]) -> Result<Vec<Review>, RepositoryError> {
+        self.inner.list_reviews_for_books(book_ids).await
+    }
+
+    async fn create_webhook(&self, subscription: WebhookSubscription) -> Result<WebhookSubscription, RepositoryError> {
+        self.inner.create_webhook(subscription).await
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<WebhookSubscription>, RepositoryError> {
+        self.inner.list_webhooks().await
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<Option<WebhookSubscription>, RepositoryError> {
+        self.inner.delete_webhook(id).await
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        self.inner.ping().await
+    }
+
+    async fn migration_version(&self) -> Result<Option<i64>, RepositoryError> {
+        self.inner.migration_version().await
+    }
+
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        self.inner.pool_stats()
+    }
+
+    fn shared_cache_stats(&self) -> Option<SharedCacheStats> {
+        Some(SharedCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed)
+        })
+    }
+
+    fn slow_query_stats(&self) -> Option<SlowQueryStats> {
+        self.inner.slow_query_stats()
+    }
+}
+
+/// A connection of the tests' own to the Redis at `REDIS_URL`, to look at
+/// what the cache left there, or `None` when that isn't set.
+#[cfg(test)]
+async fn test_redis() -> Option<(RedisClient, MultiplexedConnection)> {
+    let redis_url = std::env::var("REDIS_URL").ok()?;
+    let conn = redis::Client::open(redis_url.as_str()).unwrap().get_multiplexed_async_connection().await.unwrap();
+    Some((RedisClient::new(&redis_url).unwrap(), conn))
+}
+
+#[async_std::test]
+async fn books_are_back_filled_and_forgotten_on_write() {
+    use redis::AsyncCommands;
+    use super::InMemoryBookRepository;
+
+    let Some((client, mut conn)) = test_redis().await else {
+        eprintln!("skipping books_are_back_filled_and_forgotten_on_write: REDIS_URL isn't set");
+        return;
+    };
+    let repo = RedisCached::new(InMemoryBookRepository::new(), client, Duration::from_millis(crate::config::DEFAULT_REDIS_BOOK_TTL_MS));
+    let book = repo.create_book(crate::fixtures::BookFixture::new("Dune").build()).await.unwrap();
+
+    assert_eq!(Some(&book), repo.get_book(book.id).await.unwrap().as_ref());
+    assert!(conn.exists::<_, bool>(key(book.id)).await.unwrap());
+    let ttl: i64 = conn.pttl(key(book.id)).await.unwrap();
+    assert!(ttl > 0 && ttl <= crate::config::DEFAULT_REDIS_BOOK_TTL_MS as i64, "{}", ttl);
+    let hit = repo.get_book(book.id).await.unwrap().unwrap();
+    assert_eq!((&book, book.updated_at), (&hit, hit.updated_at));
+    assert_eq!(Some(SharedCacheStats { hits: 1, misses: 1, errors: 0 }), repo.shared_cache_stats());
+
+    // What Redis holds is served as is, until a write deletes it.
+    let stale = Book { name: Some(String::from("Stale")), ..book.clone() };
+    let _: () = conn.set(key(book.id), serde_json::to_vec(&stale).unwrap()).await.unwrap();
+    assert_eq!(Some(&stale), repo.get_book(book.id).await.unwrap().as_ref());
+    let renamed = Book { name: Some(String::from("Dune Messiah")), ..book.clone() };
+    repo.update_book(book.id, renamed.clone()).await.unwrap();
+    assert!(!conn.exists::<_, bool>(key(book.id)).await.unwrap());
+    assert_eq!(Some(&renamed), repo.get_book(book.id).await.unwrap().as_ref());
+    assert!(conn.exists::<_, bool>(key(book.id)).await.unwrap());
+
+    // A bulk update doesn't say which books it hit, so all of them go.
+    repo.update_books(&BookPatch::default(), &BookPatch { stock: Some(3), ..BookPatch::default() }).await.unwrap();
+    assert!(!conn.exists::<_, bool>(key(book.id)).await.unwrap());
+}
+
+#[async_std::test]
+async fn books_come_from_the_store_while_redis_is_down() {
+    use super::InMemoryBookRepository;
+
+    // A port with nothing listening on it.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let client = RedisClient::new(&format!("redis://{}", addr)).unwrap();
+    let repo = RedisCached::new(InMemoryBookRepository::new(), client, Duration::from_millis(crate::config::DEFAULT_REDIS_BOOK_TTL_MS));
+    let book = repo.create_book(crate::fixtures::BookFixture::new("Dune").build()).await.unwrap();
+
+    assert_eq!(Some(&book), repo.get_book(book.id).await.unwrap().as_ref());
+    assert_eq!(Some(&book), repo.get_book(book.id).await.unwrap().as_ref());
+    assert!(repo.delete_book(book.id).await.unwrap().is_some());
+    let stats = repo.shared_cache_stats().unwrap();
+    assert_eq!((0, 0), (stats.hits, stats.misses));
+    assert!(stats.errors >= 3);
+    assert!(RedisClient::new("http://cache.internal").is_err());
+}
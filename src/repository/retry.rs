@@ -0,0 +1,276 @@
+use std::future::Future;
+use std::time::Duration;
+
+use async_std::channel::Sender;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::{AuditEntry, Author, Book, NewAuthor, NewReview, RatedBook, Review, ScoredBook, WebhookSubscription};
+use super::{BookChange, BookFilter, BookPatch, BookRepository, IdempotencyKey, IdempotentResponse, ImportBatches, ImportCounts, ImportMode, Page, PoolStats, RepositoryError, SharedCacheStats, SlowQueryStats};
+
+/// Used when `db_retry_attempts` isn't set.
+pub const DEFAULT_ATTEMPTS: u32 = 3;
+/// The wait before the first retry; each later one waits twice as long.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Wraps a repository and runs an operation again when its transaction
+/// lost a race, as a serialization failure or a deadlock, making up to
+/// `attempts` attempts in all. Every operation is one transaction, so
+/// running it again is safe; once the attempts are spent the last error
+/// is returned and becomes a `500`.
+#[derive(Debug)]
+pub struct RetryTransient<R> {
+    inner: R,
+    attempts: u32,
+    backoff: Duration
+}
+
+impl<R: BookRepository> RetryTransient<R> {
+    pub fn new(inner: R, attempts: u32, backoff: Duration) -> Self {
+        RetryTransient { inner, attempts: attempts.max(1), backoff }
+    }
+
+    /// Makes `db_retry_attempts` attempts.
+    pub fn from_config(inner: R, config: &Config) -> Self {
+        RetryTransient::new(inner, config.db_retry_attempts, DEFAULT_BACKOFF)
+    }
+
+    pub async fn retry<T, F, Fut>(&self, operation: &str, query: F) -> Result<T, RepositoryError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, RepositoryError>>,
+    {
+        retry(operation, self.attempts, self.backoff, query).await
+    }
+}
+
+/// Runs `query` until it succeeds, fails for a reason other than a lost
+/// race, or has been tried `attempts` times.
+async fn retry<T, F, Fut>(operation: &str, attempts: u32, backoff: Duration, mut query: F) -> Result<T, RepositoryError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RepositoryError>>,
+{
+    let mut backoff = backoff;
+    let mut attempt = 1;
+    loop {
+        match query().await {
+            Err(e) if e.is_transient() && attempt < attempts => {
+                tracing::warn!("{} failed, retrying in {:?}: {}", operation, backoff, e);
+                async_std::task::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<R: BookRepository> BookRepository for RetryTransient<R> {
+    async fn create_book(&self, book: Book) -> Result<Book, RepositoryError> {
+        self.retry("create_book", || self.inner.create_book(book.clone())).await
+    }
+
+    async fn find_idempotent_response(&self, key: &IdempotencyKey) -> Result<Option<IdempotentResponse>, RepositoryError> {
+        self.retry("find_idempotent_response", || self.inner.find_idempotent_response(key)).await
+    }
+
+    async fn create_book_with_key(&self, book: Book, key: &IdempotencyKey) -> Result<Book, RepositoryError> {
+        self.retry("create_book_with_key", || self.inner.create_book_with_key(book.clone(), key)).await
+    }
+
+    async fn create_book_with_author(&self, book: Book, author: NewAuthor) -> Result<(Book, Author), RepositoryError> {
+        self.retry("create_book_with_author", || self.inner.create_book_with_author(book.clone(), author.clone())).await
+    }
+
+    async fn list_books(&self, columns: &[&str], filter: &BookFilter, page: Option<Page>) -> Result<Vec<Book>, RepositoryError> {
+        self.retry("list_books", || self.inner.list_books(columns, filter, page)).await
+    }
+
+    async fn count_books(&self, filter: &BookFilter) -> Result<u64, RepositoryError> {
+        self.retry("count_books", || self.inner.count_books(filter)).await
+    }
+
+    async fn get_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        self.retry("get_book", || self.inner.get_book(id)).await
+    }
+
+    async fn search_books(&self, term: &str, threshold: f32, limit: u32) -> Result<Vec<ScoredBook>, RepositoryError> {
+        self.retry("search_books", || self.inner.search_books(term, threshold, limit)).await
+    }
+
+    async fn random_book(&self) -> Result<Option<Book>, RepositoryError> {
+        self.retry("random_book", || self.inner.random_book()).await
+    }
+
+    async fn similar_books(&self, book: &Book, limit: u32) -> Result<Vec<Book>, RepositoryError> {
+        self.retry("similar_books", || self.inner.similar_books(book, limit)).await
+    }
+
+    async fn book_exists(&self, id: Uuid) -> Result<bool, RepositoryError> {
+        self.retry("book_exists", || self.inner.book_exists(id)).await
+    }
+
+    async fn get_rated_book(&self, id: Uuid) -> Result<Option<RatedBook>, RepositoryError> {
+        self.retry("get_rated_book", || self.inner.get_rated_book(id)).await
+    }
+
+    async fn update_book(&self, id: Uuid, book: Book) -> Result<Option<Book>, RepositoryError> {
+        self.retry("update_book", || self.inner.update_book(id, book.clone())).await
+    }
+
+    async fn change_book(&self, id: Uuid, change: &BookChange<'_>) -> Result<Option<Book>, RepositoryError> {
+        self.retry("change_book", || self.inner.change_book(id, change)).await
+    }
+
+    async fn upsert_book(&self, id: Uuid, book: Book) -> Result<(Book, bool), RepositoryError> {
+        self.retry("upsert_book", || self.inner.upsert_book(id, book.clone())).await
+    }
+
+    async fn update_books(&self, filter: &BookPatch, set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        self.retry("update_books", || self.inner.update_books(filter, set)).await
+    }
+
+    async fn update_books_by_id(&self, ids: &[Uuid], set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        self.retry("update_books_by_id", || self.inner.update_books_by_id(ids, set)).await
+    }
+
+    async fn checkout_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        self.retry("checkout_book", || self.inner.checkout_book(id)).await
+    }
+
+    async fn delete_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        self.retry("delete_book", || self.inner.delete_book(id)).await
+    }
+
+    async fn export_books(&self, filter: &BookFilter, counted: Sender<u64>, out: Sender<Book>) -> Result<u64, RepositoryError> {
+        // Books already sent can't be taken back, so a failed export isn't
+        // run again.
+        self.inner.export_books(filter, counted, out).await
+    }
+
+    async fn restore_books(&self, books: Vec<Book>) -> Result<Vec<Book>, RepositoryError> {
+        self.retry("restore_books", || self.inner.restore_books(books.clone())).await
+    }
+
+    async fn import_books(&self, books: ImportBatches, mode: ImportMode) -> Result<ImportCounts, RepositoryError> {
+        // The books are taken as they're read, so a failed import can't be
+        // run again.
+        self.inner.import_books(books, mode).await
+    }
+
+    async fn book_history(&self, id: Uuid, limit: u32) -> Result<Vec<AuditEntry>, RepositoryError> {
+        self.retry("book_history", || self.inner.book_history(id, limit)).await
+    }
+
+    async fn create_review(&self, book_id: Uuid, review: NewReview) -> Result<Option<Review>, RepositoryError> {
+        self.retry("create_review", || self.inner.create_review(book_id, review.clone())).await
+    }
+
+    async fn list_reviews(&self, book_id: Uuid, limit: u32) -> Result<Vec<Review>, RepositoryError> {
+        self.retry("list_reviews", || self.inner.list_reviews(book_id, limit)).await
+    }
+
+    async fn list_reviews_for_books(&self, book_ids: &[Uuid]) -> Result<Vec<Review>, RepositoryError> {
+        self.retry("list_reviews_for_books", || self.inner.list_reviews_for_books(book_ids)).await
+    }
+
+    async fn create_webhook(&self, subscription: WebhookSubscription) -> Result<WebhookSubscription, RepositoryError> {
+        self.retry("create_webhook", || self.inner.create_webhook(subscription.clone())).await
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<WebhookSubscription>, RepositoryError> {
+        self.retry("list_webhooks", || self.inner.list_webhooks()).await
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<Option<WebhookSubscription>, RepositoryError> {
+        self.retry("delete_webhook", || self.inner.delete_webhook(id)).await
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        self.inner.ping().await
+    }
+
+    async fn migration_version(&self) -> Result<Option<i64>, RepositoryError> {
+        self.inner.migration_version().await
+    }
+
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        self.inner.pool_stats()
+    }
+
+    fn shared_cache_stats(&self) -> Option<SharedCacheStats> {
+        self.inner.shared_cache_stats()
+    }
+
+    fn slow_query_stats(&self) -> Option<SlowQueryStats> {
+        self.inner.slow_query_stats()
+    }
+}
+
+/// A database error with just a SQLSTATE, standing in for the driver's.
+#[cfg(test)]
+#[derive(Debug)]
+struct SqlState(&'static str);
+
+#[cfg(test)]
+impl std::fmt::Display for SqlState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SQLSTATE {}", self.0)
+    }
+}
+
+#[cfg(test)]
+impl std::error::Error for SqlState {}
+
+#[cfg(test)]
+impl sqlx::error::DatabaseError for SqlState {
+    fn message(&self) -> &str {
+        "simulated failure"
+    }
+
+    fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+        Some(self.0.into())
+    }
+
+    fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+        self
+    }
+}
+
+#[test]
+fn lost_races_are_retried_until_the_attempts_run_out() {
+    use std::cell::Cell;
+
+    let fail = |code| RepositoryError::Database(sqlx::Error::Database(Box::new(SqlState(code))));
+    let calls = Cell::new(0);
+    let flaky = |failures: u32, code: &'static str| {
+        calls.set(0);
+        let calls = &calls;
+        move || {
+            calls.set(calls.get() + 1);
+            async move { if calls.get() <= failures { Err(fail(code)) } else { Ok(calls.get()) } }
+        }
+    };
+    let run = |query| async_std::task::block_on(retry("test", 3, Duration::from_millis(1), query));
+
+    // A serialization failure and then a deadlock, then success.
+    assert_eq!(3, run(flaky(2, "40001")).unwrap());
+    assert_eq!(2, run(flaky(1, "40P01")).unwrap());
+
+    assert!(run(flaky(3, "40001")).is_err());
+    assert_eq!(3, calls.get());
+
+    // Anything else fails straight away.
+    assert!(run(flaky(1, "23502")).is_err());
+    assert_eq!(1, calls.get());
+}
@@ -0,0 +1,220 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use async_std::channel::Sender;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::{AuditEntry, Author, Book, NewAuthor, NewReview, RatedBook, Review, ScoredBook, WebhookSubscription, timing};
+use super::{BookChange, BookFilter, BookPatch, BookRepository, IdempotencyKey, IdempotentResponse, ImportBatches, ImportCounts, ImportMode, Page, PoolStats, RepositoryError, SharedCacheStats, SlowQueryStats};
+
+/// Used when `slow_query_ms` isn't set: 250 ms.
+pub const DEFAULT_SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(250);
+/// Used when `slow_query_error_ms` isn't set: 2 seconds.
+pub const DEFAULT_SLOW_QUERY_ERROR_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// Wraps a repository, running every operation in a `db` span and timing
+/// it for `Server-Timing`, and logs every one that takes longer than
+/// `threshold`: a warning, or an error past `error_threshold`. The event
+/// has the operation as `query`, the book or other row it was for as `id`
+/// when there is one, and `elapsed_ms`; a handler's operations log inside
+/// its request span, which names the handler.
+#[derive(Debug)]
+pub struct SlowQueryLog<R> {
+    inner: R,
+    threshold: Duration,
+    error_threshold: Duration,
+    slow: AtomicU64,
+    very_slow: AtomicU64
+}
+
+impl<R: BookRepository> SlowQueryLog<R> {
+    pub fn new(inner: R, threshold: Duration) -> Self {
+        SlowQueryLog {
+            inner,
+            threshold,
+            error_threshold: DEFAULT_SLOW_QUERY_ERROR_THRESHOLD,
+            slow: AtomicU64::new(0),
+            very_slow: AtomicU64::new(0)
+        }
+    }
+
+    pub fn with_error_threshold(self, error_threshold: Duration) -> Self {
+        SlowQueryLog { error_threshold, ..self }
+    }
+
+    /// The thresholds from `slow_query_ms` and `slow_query_error_ms`.
+    pub fn from_config(inner: R, config: &Config) -> Self {
+        SlowQueryLog::new(inner, Duration::from_millis(config.slow_query_ms))
+            .with_error_threshold(Duration::from_millis(config.slow_query_error_ms))
+    }
+
+    pub async fn time<T>(&self, operation: &str, query: impl Future<Output = T>) -> T {
+        self.time_for(operation, None, query).await
+    }
+
+    /// As `time`, for an operation on the row `id`.
+    pub async fn time_for<T>(&self, operation: &str, id: Option<Uuid>, query: impl Future<Output = T>) -> T {
+        let span = tracing::info_span!("db", query = operation, id = id.map(tracing::field::display));
+        let started = Instant::now();
+        let result = query.instrument(span).await;
+        let elapsed = started.elapsed();
+        timing::record_db_time(elapsed);
+        if elapsed > self.threshold {
+            self.slow.fetch_add(1, Ordering::Relaxed);
+            let id = id.map(|id| id.to_string());
+            let elapsed_ms = elapsed.as_millis() as u64;
+            if elapsed > self.error_threshold {
+                self.very_slow.fetch_add(1, Ordering::Relaxed);
+                tracing::error!(query = operation, id = id.as_deref(), elapsed_ms, "slow query: {} took {} ms", operation, elapsed_ms);
+            } else {
+                tracing::warn!(query = operation, id = id.as_deref(), elapsed_ms, "slow query: {} took {} ms", operation, elapsed_ms);
+            }
+        }
+        result
+    }
+}
+
+#[tide::utils::async_trait]
+impl<R: BookRepository> BookRepository for SlowQueryLog<R> {
+    async fn create_book(&self, book: Book) -> Result<Book, RepositoryError> {
+        self.time_for("create_book", Some(book.id), self.inner.create_book(book)).await
+    }
+
+    async fn find_idempotent_response(&self, key: &IdempotencyKey) -> Result<Option<IdempotentResponse>, RepositoryError> {
+        self.time("find_idempotent_response", self.inner.find_idempotent_response(key)).await
+    }
+
+    async fn create_book_with_key(&self, book: Book, key: &IdempotencyKey) -> Result<Book, RepositoryError> {
+        self.time_for("create_book_with_key", Some(book.id), self.inner.create_book_with_key(book, key)).await
+    }
+
+    async fn create_book_with_author(&self, book: Book, author: NewAuthor) -> Result<(Book, Author), RepositoryError> {
+        self.time_for("create_book_with_author", Some(book.id), self.inner.create_book_with_author(book, author)).await
+    }
+
+    async fn list_books(&self, columns: &[&str], filter: &BookFilter, page: Option<Page>) -> Result<Vec<Book>, RepositoryError> {
+        self.time("list_books", self.inner.list_books(columns, filter, page)).await
+    }
+
+    async fn count_books(&self, filter: &BookFilter) -> Result<u64, RepositoryError> {
+        self.time("count_books", self.inner.count_books(filter)).await
+    }
+
+    async fn get_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        self.time_for("get_book", Some(id), self.inner.get_book(id)).await
+    }
+
+    async fn search_books(&self, term: &str, threshold: f32, limit: u32) -> Result<Vec<ScoredBook>, RepositoryError> {
+        self.time("search_books", self.inner.search_books(term, threshold, limit)).await
+    }
+
+    async fn random_book(&self) -> Result<Option<Book>, RepositoryError> {
+        self.time("random_book", self.inner.random_book()).await
+    }
+
+    async fn similar_books(&self, book: &Book, limit: u32) -> Result<Vec<Book>, RepositoryError> {
+        self.time_for("similar_books", Some(book.id), self.inner.similar_books(book, limit)).await
+    }
+
+    async fn book_exists(&self, id: Uuid) -> Result<bool, RepositoryError> {
+        self.time_for("book_exists", Some(id), self.inner.book_exists(id)).await
+    }
+
+    async fn get_rated_book(&self, id: Uuid) -> Result<Option<RatedBook>, RepositoryError> {
+        self.time_for("get_rated_book", Some(id), self.inner.get_rated_book(id)).await
+    }
+
+    async fn update_book(&self, id: Uuid, book: Book) -> Result<Option<Book>, RepositoryError> {
+        self.time_for("update_book", Some(id), self.inner.update_book(id, book)).await
+    }
+
+    async fn change_book(&self, id: Uuid, change: &BookChange<'_>) -> Result<Option<Book>, RepositoryError> {
+        self.time_for("change_book", Some(id), self.inner.change_book(id, change)).await
+    }
+
+    async fn upsert_book(&self, id: Uuid, book: Book) -> Result<(Book, bool), RepositoryError> {
+        self.time_for("upsert_book", Some(id), self.inner.upsert_book(id, book)).await
+    }
+
+    async fn update_books(&self, filter: &BookPatch, set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        self.time("update_books", self.inner.update_books(filter, set)).await
+    }
+
+    async fn update_books_by_id(&self, ids: &[Uuid], set: &BookPatch) -> Result<Vec<Book>, RepositoryError> {
+        self.time("update_books_by_id", self.inner.update_books_by_id(ids, set)).await
+    }
+
+    async fn checkout_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        self.time_for("checkout_book", Some(id), self.inner.checkout_book(id)).await
+    }
+
+    async fn delete_book(&self, id: Uuid) -> Result<Option<Book>, RepositoryError> {
+        self.time_for("delete_book", Some(id), self.inner.delete_book(id)).await
+    }
+
+    async fn export_books(&self, filter: &BookFilter, counted: Sender<u64>, out: Sender<Book>) -> Result<u64, RepositoryError> {
+        self.time("export_books", self.inner.export_books(filter, counted, out)).await
+    }
+
+    async fn restore_books(&self, books: Vec<Book>) -> Result<Vec<Book>, RepositoryError> {
+        self.time("restore_books", self.inner.restore_books(books)).await
+    }
+
+    async fn import_books(&self, books: ImportBatches, mode: ImportMode) -> Result<ImportCounts, RepositoryError> {
+        self.time("import_books", self.inner.import_books(books, mode)).await
+    }
+
+    async fn book_history(&self, id: Uuid, limit: u32) -> Result<Vec<AuditEntry>, RepositoryError> {
+        self.time_for("book_history", Some(id), self.inner.book_history(id, limit)).await
+    }
+
+    async fn create_review(&self, book_id: Uuid, review: NewReview) -> Result<Option<Review>, RepositoryError> {
+        self.time_for("create_review", Some(book_id), self.inner.create_review(book_id, review)).await
+    }
+
+    async fn list_reviews(&self, book_id: Uuid, limit: u32) -> Result<Vec<Review>, RepositoryError> {
+        self.time_for("list_reviews", Some(book_id), self.inner.list_reviews(book_id, limit)).await
+    }
+
+    async fn list_reviews_for_books(&self, book_ids: &[Uuid]) -> Result<Vec<Review>, RepositoryError> {
+        self.time("list_reviews_for_books", self.inner.list_reviews_for_books(book_ids)).await
+    }
+
+    async fn create_webhook(&self, subscription: WebhookSubscription) -> Result<WebhookSubscription, RepositoryError> {
+        self.time("create_webhook", self.inner.create_webhook(subscription)).await
+    }
+
+    async fn list_webhooks(&self) -> Result<Vec<WebhookSubscription>, RepositoryError> {
+        self.time("list_webhooks", self.inner.list_webhooks()).await
+    }
+
+    async fn delete_webhook(&self, id: Uuid) -> Result<Option<WebhookSubscription>, RepositoryError> {
+        self.time_for("delete_webhook", Some(id), self.inner.delete_webhook(id)).await
+    }
+
+    async fn ping(&self) -> Result<(), RepositoryError> {
+        self.time("ping", self.inner.ping()).await
+    }
+
+    async fn migration_version(&self) -> Result<Option<i64>, RepositoryError> {
+        self.time("migration_version", self.inner.migration_version()).await
+    }
+
+    fn pool_stats(&self) -> Vec<PoolStats> {
+        self.inner.pool_stats()
+    }
+
+    fn shared_cache_stats(&self) -> Option<SharedCacheStats> {
+        self.inner.shared_cache_stats()
+    }
+
+    fn slow_query_stats(&self) -> Option<SlowQueryStats> {
+        Some(SlowQueryStats {
+            slow: self.slow.load(Ordering::Relaxed),
+            very_slow: self.very_slow.load(Ordering::Relaxed)
+        })
+    }
+}
@@ -0,0 +1,115 @@
+//! Sample data for `crud_test seed`.
+
+use rust_decimal::Decimal;
+use uuid::Uuid;
+
+use crate::{Book, validate_book};
+use crate::error::AppError;
+use crate::repository::{BookFilter, BookRepository, RepositoryError};
+
+/// How many books `seed` inserts unless told otherwise.
+pub const DEFAULT_COUNT: usize = 50;
+
+/// `(name, author, year)` of the real books seeded first.
+pub const SAMPLE_BOOKS: &[(&str, &str, i32)] = &[
+    ("The Rust Programming Language", "Steve Klabnik, Carol Nichols", 2018),
+    ("Programming Rust", "Jim Blandy, Jason Orendorff", 2017),
+    ("Rust for Rustaceans", "Jon Gjengset", 2021),
+    ("Zero To Production In Rust", "Luca Palmieri", 2022),
+    ("Rust in Action", "Tim McNamara", 2021),
+];
+
+/// What the made-up books after `SAMPLE_BOOKS` are made of.
+const ADJECTIVES: &[&str] = &[
+    "Silent", "Crimson", "Hidden", "Last", "Burning", "Distant", "Broken", "Golden",
+    "Forgotten", "Winter", "Glass", "Northern", "Endless", "Quiet", "Salt", "Iron",
+];
+const NOUNS: &[&str] = &[
+    "River", "Garden", "Harbor", "Orchard", "Lighthouse", "Archive", "Kingdom", "Letter",
+    "Mountain", "Station", "Compass", "Library", "Island", "Bridge", "Season", "Machine",
+];
+const FIRST_NAMES: &[&str] = &["Ada", "Miguel", "Hannah", "Kenji", "Laura", "Omar", "Ingrid", "Tomás", "Priya", "Felix"];
+const LAST_NAMES: &[&str] = &["Moreau", "Okafor", "Lindqvist", "Tanaka", "Rossi", "Haddad", "García", "Novak", "Byrne", "Weber"];
+const PUBLISHERS: &[&str] = &["Harbor House", "Northwind Press", "Lantern Books", "Blue Fern", "Meridian"];
+const LANGUAGES: &[&str] = &["en", "en", "en", "es", "fr", "de"];
+/// The made-up books' years spread over these.
+const FIRST_YEAR: i32 = 1950;
+const YEARS: usize = 75;
+
+/// What `seed` did.
+#[derive(Debug, PartialEq)]
+pub enum Seeded {
+    Inserted(usize),
+    /// Nothing was inserted, the store already holding this many books.
+    Skipped(u64),
+}
+
+/// Inserts `count` sample books: `SAMPLE_BOOKS`, then made-up ones, each
+/// checked as `create_book` checks them. A store that already has books is
+/// left alone unless `force` is set; then only the books that aren't there
+/// yet are inserted, so seeding twice doesn't duplicate them.
+pub async fn seed(repo: &dyn BookRepository, count: usize, force: bool) -> Result<Seeded, AppError> {
+    if !force {
+        let existing = repo.count_books(&BookFilter::default()).await?;
+        if existing > 0 {
+            return Ok(Seeded::Skipped(existing));
+        }
+    }
+    let mut inserted = 0;
+    for n in 0..count {
+        match repo.create_book(validate_book("", sample_book(n))?).await {
+            Ok(_) => inserted += 1,
+            Err(RepositoryError::Duplicate(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Seeded::Inserted(inserted))
+}
+
+/// The `n`th sample book, the same every time but for its id.
+pub fn sample_book(n: usize) -> Book {
+    let mut book = Book {
+        id: Uuid::new_v4(),
+        name: None,
+        author: None,
+        year: None,
+        published_date: None,
+        publisher: None,
+        language: None,
+        price: None,
+        stock: None,
+        updated_at: None
+    };
+    if let Some((name, author, year)) = SAMPLE_BOOKS.get(n) {
+        book.name = Some(name.to_string());
+        book.author = Some(author.to_string());
+        book.year = Some(*year);
+        return book;
+    }
+    let made_up = n - SAMPLE_BOOKS.len();
+    let titles = ADJECTIVES.len() * NOUNS.len();
+    let title = format!("The {} {}", ADJECTIVES[made_up % ADJECTIVES.len()], NOUNS[made_up / ADJECTIVES.len() % NOUNS.len()]);
+    book.name = Some(match made_up / titles {
+        0 => title,
+        volume => format!("{}, Volume {}", title, volume + 1),
+    });
+    book.author = Some(format!("{} {}", FIRST_NAMES[made_up * 7 % FIRST_NAMES.len()], LAST_NAMES[made_up * 3 % LAST_NAMES.len()]));
+    book.year = Some(FIRST_YEAR + (made_up * 17 % YEARS) as i32);
+    book.publisher = Some(PUBLISHERS[made_up % PUBLISHERS.len()].to_owned());
+    book.language = Some(LANGUAGES[made_up % LANGUAGES.len()].to_owned());
+    book.price = Some(Decimal::new(799 + (made_up % 24) as i64 * 100, 2));
+    book.stock = Some((made_up * 7 % 20) as i32);
+    book
+}
+
+#[test]
+fn sample_books_are_all_different() {
+    let count = SAMPLE_BOOKS.len() + 2 * ADJECTIVES.len() * NOUNS.len();
+    let mut names: Vec<String> = (0..count).map(|n| sample_book(n).name.unwrap()).collect();
+    names.sort();
+    names.dedup();
+    assert_eq!(count, names.len());
+    assert!((0..count).all(|n| validate_book("", sample_book(n)).is_ok()));
+    let years: Vec<i32> = (0..count).filter_map(|n| sample_book(n).year).collect();
+    assert!(years.iter().all(|year| (FIRST_YEAR..FIRST_YEAR + YEARS as i32).contains(year)));
+}
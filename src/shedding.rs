@@ -0,0 +1,106 @@
+//! Load shedding: `DbPermits` bounds how many requests use the database at
+//! once. Under a spike the requests past the limit are answered `503` after
+//! a short wait, with `Retry-After`, rather than queueing on the connection
+//! pool until their clients have given up on them.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use async_lock::{Semaphore, SemaphoreGuardArc};
+use tide::{Middleware, Next, Request};
+
+use crate::State;
+use crate::config::Config;
+use crate::error::AppError;
+
+/// Used when `db_permit_wait_ms` isn't set: enough to ride out a burst,
+/// well short of any client's timeout.
+pub const DEFAULT_PERMIT_WAIT: Duration = Duration::from_millis(100);
+/// Used when `db_concurrency` isn't set: the permits per pooled connection,
+/// so a connection freeing up finds the next request already waiting.
+pub const PERMITS_PER_CONNECTION: u32 = 2;
+
+/// The permits to use the database. A request waits up to `wait` for one
+/// and is shed, and counted, when none comes free in time.
+#[derive(Debug)]
+pub struct DbPermits {
+    semaphore: Arc<Semaphore>,
+    permits: u32,
+    wait: Duration,
+    shed: AtomicU64
+}
+
+impl DbPermits {
+    pub fn new(permits: u32, wait: Duration) -> Self {
+        let permits = permits.max(1);
+        DbPermits { semaphore: Arc::new(Semaphore::new(permits as usize)), permits, wait, shed: AtomicU64::new(0) }
+    }
+
+    /// `db_concurrency` permits, or `PERMITS_PER_CONNECTION` for each of
+    /// the `pool_size` connections, waited for up to `db_permit_wait_ms`.
+    pub fn from_config(config: &Config) -> Self {
+        let permits = config.db_concurrency.unwrap_or(config.pool_size.saturating_mul(PERMITS_PER_CONNECTION));
+        DbPermits::new(permits, Duration::from_millis(config.db_permit_wait_ms))
+    }
+
+    pub fn permits(&self) -> u32 {
+        self.permits
+    }
+
+    /// How many requests have been shed since startup.
+    pub fn shed(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// A permit, given back when it's dropped, or `Unavailable` once `wait`
+    /// has passed without one.
+    pub async fn acquire(&self) -> Result<SemaphoreGuardArc, AppError> {
+        match async_std::future::timeout(self.wait, self.semaphore.acquire_arc()).await {
+            Ok(permit) => Ok(permit),
+            Err(_) => {
+                let shed = self.shed.fetch_add(1, Ordering::Relaxed) + 1;
+                tracing::warn!(shed, "shedding a request: all {} database permits were taken for {:?}", self.permits, self.wait);
+                Err(AppError::Unavailable(String::from("the server is too busy; try again shortly")))
+            }
+        }
+    }
+
+    /// A permit for work done in the background after a request, which
+    /// waits its turn however long that takes rather than being shed.
+    pub async fn acquire_queued(&self) -> SemaphoreGuardArc {
+        self.semaphore.acquire_arc().await
+    }
+}
+
+/// Runs the routes it's on holding one of `State`'s `DbPermits`. What a
+/// handler streams after returning, as an export or `/books/events` does,
+/// no longer holds it.
+#[derive(Clone, Copy, Debug)]
+pub struct ShedLoad;
+
+#[tide::utils::async_trait]
+impl Middleware<State> for ShedLoad {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let permits = req.state().db_permits.clone();
+        let _permit = match permits.acquire().await {
+            Ok(permit) => permit,
+            Err(e) => return Ok(e.into_response()),
+        };
+        Ok(next.run(req).await)
+    }
+}
+
+#[async_std::test]
+async fn requests_past_the_permits_are_shed() {
+    let permits = DbPermits::new(2, Duration::from_millis(10));
+    let first = permits.acquire().await.unwrap();
+    let _second = permits.acquire().await.unwrap();
+    assert!(matches!(permits.acquire().await, Err(AppError::Unavailable(_))));
+    assert_eq!(1, permits.shed());
+
+    drop(first);
+    assert!(permits.acquire().await.is_ok());
+    assert_eq!(1, permits.shed());
+    assert_eq!(1, DbPermits::new(0, DEFAULT_PERMIT_WAIT).permits());
+}
@@ -0,0 +1,43 @@
+//! Response bodies written a chunk at a time by a task of their own, for
+//! responses too long to build in memory first.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use async_std::channel::Receiver;
+use async_std::io::{BufReader, Read};
+use async_std::stream::Stream;
+use tide::Body;
+
+/// A body of the chunks sent on `receiver`, in order, which ends when
+/// every sender is dropped. Once the client goes away the body is dropped
+/// too and sending fails, which tells the writer to stop.
+pub fn body(receiver: Receiver<Vec<u8>>) -> Body {
+    Body::from_reader(BufReader::new(Chunks { receiver, chunk: Vec::new(), read: 0 }), None)
+}
+
+struct Chunks {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    read: usize
+}
+
+impl Read for Chunks {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+        while self.read == self.chunk.len() {
+            match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(chunk)) => {
+                    self.chunk = chunk;
+                    self.read = 0;
+                }
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let unread = &self.chunk[self.read..];
+        let n = unread.len().min(buf.len());
+        buf[..n].copy_from_slice(&unread[..n]);
+        self.read += n;
+        Poll::Ready(Ok(n))
+    }
+}
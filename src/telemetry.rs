@@ -0,0 +1,208 @@
+//! Structured logging through `tracing`, with a span around every handler
+//! and, inside it, one around every repository operation. With the `otel`
+//! feature the spans are exported too; see `otel`.
+
+use tide::{Middleware, Next, Request};
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use uuid::Uuid;
+
+use crate::error::AppError;
+
+/// Keeps the exporter, if there is one, until it's dropped, which flushes
+/// the spans not yet sent.
+#[derive(Debug)]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            let _ = provider.shutdown();
+        }
+    }
+}
+
+/// Installs the global subscriber. `RUST_LOG` takes precedence over
+/// `default_level` when it's set. Records logged through the `log` crate,
+/// by tide and sqlx among others, are forwarded to it.
+pub fn init(default_level: LevelFilter) -> Telemetry {
+    let filter = EnvFilter::builder()
+        .with_default_directive(default_level.into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::registry().with(filter).with(tracing_subscriber::fmt::layer());
+    #[cfg(feature = "otel")]
+    {
+        let (layer, provider) = crate::otel::layer().unzip();
+        subscriber.with(layer).init();
+        Telemetry { provider }
+    }
+    #[cfg(not(feature = "otel"))]
+    {
+        subscriber.init();
+        Telemetry {}
+    }
+}
+
+/// The id a request is logged under: the client's `X-Request-Id` when it
+/// sent one, otherwise a fresh UUID. It's echoed back in the response.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    pub fn of<State>(req: &Request<State>) -> Option<&str> {
+        req.ext::<RequestId>().map(|id| id.0.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct RequestIds;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequestIds {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let id = match req.header("X-Request-Id") {
+            Some(values) if values.last().as_str().len() <= 255 => values.last().as_str().to_owned(),
+            _ => Uuid::new_v4().to_string(),
+        };
+        req.set_ext(RequestId(id.clone()));
+        let mut res = next.run(req).await;
+        res.insert_header("X-Request-Id", id);
+        Ok(res)
+    }
+}
+
+/// The span a handler runs in. `handler` is the handler function's name,
+/// `trace_id` the trace of the request's `traceparent` when it has one, and
+/// `status` is recorded once the handler has answered.
+pub fn handler_span<State>(req: &Request<State>, handler: &str) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        request_id = RequestId::of(req).unwrap_or(""),
+        method = %req.method(),
+        route = req.url().path(),
+        handler,
+        trace_id = trace_id(req),
+        status = tracing::field::Empty
+    );
+    #[cfg(feature = "otel")]
+    crate::otel::continue_trace(req, &span);
+    span
+}
+
+/// The trace id of a valid W3C `traceparent`: `00-`, 32 lowercase hex
+/// digits of trace id, 16 of parent id and 2 of flags, with neither id all
+/// zeros.
+fn trace_id<State>(req: &Request<State>) -> Option<&str> {
+    let traceparent = req.header("traceparent")?.last().as_str();
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    let hex = |part: &str, len: usize| {
+        part.len() == len && part.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    let nonzero = |part: &str| part.bytes().any(|b| b != b'0');
+    match parts[..] {
+        ["00", trace_id, parent_id, flags] if hex(trace_id, 32) && nonzero(trace_id) && hex(parent_id, 16) && nonzero(parent_id) && hex(flags, 2) => {
+            Some(trace_id)
+        }
+        _ => None,
+    }
+}
+
+/// Records how the handler answered on its span, with an event when it
+/// failed: an error for a server error, otherwise at debug level, the
+/// client being the one at fault.
+pub fn record_outcome(status: u16, error: Option<&AppError>) {
+    Span::current().record("status", status);
+    match error {
+        Some(error) if error.status().is_server_error() => tracing::error!(status, code = error.code(), "request failed: {}", error.message()),
+        Some(error) => tracing::debug!(status, code = error.code(), "request refused: {}", error.message()),
+        None => {}
+    }
+}
+
+/// Captures span and event fields for tests to assert on, installed with
+/// `tracing::subscriber::with_default`.
+#[cfg(test)]
+pub mod capture {
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record as Values};
+    use tracing::{Event, Level, Subscriber};
+    use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    use tracing_subscriber::Registry;
+
+    /// A span's or event's name and level, and its fields rendered as
+    /// `key=value`.
+    #[derive(Clone, Debug)]
+    pub struct Record {
+        pub name: String,
+        pub level: Level,
+        pub fields: Vec<String>
+    }
+
+    impl Record {
+        pub fn field(&self, key: &str) -> Option<&str> {
+            let prefix = format!("{}=", key);
+            self.fields.iter().find_map(|field| field.strip_prefix(prefix.as_str()))
+        }
+    }
+
+    #[derive(Clone, Default)]
+    pub struct Captured {
+        pub spans: Arc<Mutex<Vec<Record>>>,
+        pub events: Arc<Mutex<Vec<Record>>>,
+        /// Where each span is in `spans`, to add the fields recorded later.
+        indices: Arc<Mutex<HashMap<u64, usize>>>
+    }
+
+    impl Captured {
+        pub fn subscriber(&self) -> impl Subscriber + Send + Sync {
+            Registry::default().with(self.clone())
+        }
+    }
+
+    struct Fields(Vec<String>);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.push(format!("{}={}", field.name(), value));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for Captured {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _ctx: Context<'_, S>) {
+            let mut fields = Fields(Vec::new());
+            attrs.record(&mut fields);
+            let mut spans = self.spans.lock().unwrap();
+            self.indices.lock().unwrap().insert(id.into_u64(), spans.len());
+            spans.push(Record { name: attrs.metadata().name().to_owned(), level: *attrs.metadata().level(), fields: fields.0 });
+        }
+
+        fn on_record(&self, id: &Id, values: &Values<'_>, _ctx: Context<'_, S>) {
+            let mut fields = Fields(Vec::new());
+            values.record(&mut fields);
+            if let Some(index) = self.indices.lock().unwrap().get(&id.into_u64()) {
+                self.spans.lock().unwrap()[*index].fields.extend(fields.0);
+            }
+        }
+
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            let mut fields = Fields(Vec::new());
+            event.record(&mut fields);
+            self.events.lock().unwrap().push(Record { name: event.metadata().name().to_owned(), level: *event.metadata().level(), fields: fields.0 });
+        }
+    }
+}
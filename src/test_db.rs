@@ -0,0 +1,122 @@
+//! Isolated databases for tests, so they neither see each other's rows nor
+//! leave any behind. On Postgres each `TestDb` gets its own schema (the
+//! pool's `search_path` starts with it); on SQLite and MySQL its own database.
+//!
+//! Tests get one through `with_test_db(|app| async move { ... })`, which
+//! tears it down even when they panic.
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+
+use futures_lite::FutureExt;
+
+use sqlx::{Connection, Executor, PgConnection};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use tide::Server;
+use uuid::Uuid;
+
+use crate::{State, server, test_config};
+
+pub struct TestDb {
+    name: String,
+    app: Server<State>,
+    /// Closed on teardown: sqlx keeps a pool's connections open until it's
+    /// closed, even once nothing uses it, and the tests would otherwise run
+    /// Postgres out of connections.
+    pg_pool: Option<sqlx::PgPool>
+}
+
+impl TestDb {
+    /// Creates the throwaway database, migrates it and builds the app on it.
+    pub async fn new() -> TestDb {
+        let name = format!("test_{}", Uuid::new_v4().simple());
+        let db_url = test_config().database_url;
+
+        #[cfg(feature = "sqlite")]
+        if db_url.starts_with("sqlite:") {
+            return TestDb::sqlite(name).await;
+        }
+
+        #[cfg(feature = "mysql")]
+        if db_url.starts_with("mysql:") || db_url.starts_with("mariadb:") {
+            return TestDb::mysql(name, &db_url).await;
+        }
+
+        let mut conn = PgConnection::connect(&db_url).await.unwrap();
+        conn.execute(format!("CREATE SCHEMA {}", name).as_str()).await.unwrap();
+        // `public` stays on the path, after the schema, for the extensions
+        // installed there.
+        let search_path = format!("{},public", name);
+        let options = PgConnectOptions::from_str(&db_url).unwrap().options([("search_path", &search_path)]);
+        let db_pool = PgPoolOptions::new().connect_with(options).await.unwrap();
+        sqlx::migrate!().run(&db_pool).await.unwrap();
+        TestDb { name, app: server(db_pool.clone()).await, pg_pool: Some(db_pool) }
+    }
+
+    #[cfg(feature = "sqlite")]
+    async fn sqlite(name: String) -> TestDb {
+        use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+        use crate::{SqliteBookRepository, server_with_repo};
+
+        let path = std::env::temp_dir().join(format!("{}.sqlite", name));
+        let options = SqliteConnectOptions::new().filename(&path).create_if_missing(true);
+        let db_pool = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::migrate!("./migrations/sqlite").run(&db_pool).await.unwrap();
+        TestDb { name, app: server_with_repo(SqliteBookRepository::new(db_pool)).await, pg_pool: None }
+    }
+
+    #[cfg(feature = "mysql")]
+    async fn mysql(name: String, db_url: &str) -> TestDb {
+        use sqlx::mysql::{MySqlConnectOptions, MySqlConnection, MySqlPool};
+        use crate::{MySqlBookRepository, server_with_repo};
+
+        let mut conn = MySqlConnection::connect(db_url).await.unwrap();
+        conn.execute(format!("CREATE DATABASE {}", name).as_str()).await.unwrap();
+        let options = MySqlConnectOptions::from_str(db_url).unwrap().database(&name);
+        let db_pool = MySqlPool::connect_with(options).await.unwrap();
+        sqlx::migrate!("./migrations/mysql").run(&db_pool).await.unwrap();
+        TestDb { name, app: server_with_repo(MySqlBookRepository::new(db_pool)).await, pg_pool: None }
+    }
+
+    /// Drops the database.
+    pub async fn teardown(self) {
+        let db_url = test_config().database_url;
+        drop(self.app);
+        if let Some(pg_pool) = self.pg_pool {
+            pg_pool.close().await;
+        }
+
+        #[cfg(feature = "sqlite")]
+        if db_url.starts_with("sqlite:") {
+            for suffix in ["", "-wal", "-shm"] {
+                let path = std::env::temp_dir().join(format!("{}.sqlite{}", self.name, suffix));
+                let _ = std::fs::remove_file(path);
+            }
+            return;
+        }
+
+        #[cfg(feature = "mysql")]
+        if db_url.starts_with("mysql:") || db_url.starts_with("mariadb:") {
+            let mut conn = sqlx::mysql::MySqlConnection::connect(&db_url).await.unwrap();
+            conn.execute(format!("DROP DATABASE {}", self.name).as_str()).await.unwrap();
+            return;
+        }
+
+        let mut conn = PgConnection::connect(&db_url).await.unwrap();
+        conn.execute(format!("DROP SCHEMA {} CASCADE", self.name).as_str()).await.unwrap();
+    }
+}
+
+/// Runs `test` on the app of a fresh `TestDb`, then drops the database,
+/// whether `test` succeeded, failed or panicked, and gives back what it did.
+pub async fn with_test_db<F, Fut, T>(test: F) -> T
+where
+    F: FnOnce(Server<State>) -> Fut,
+    Fut: Future<Output = T>
+{
+    let db = TestDb::new().await;
+    let outcome = AssertUnwindSafe(test(db.app.clone())).catch_unwind().await;
+    db.teardown().await;
+    outcome.unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
@@ -0,0 +1,62 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tide::{Middleware, Next, Request, Response};
+
+use crate::config::Config;
+use crate::error::AppError;
+
+/// Used when nothing configures a deadline: 30 seconds.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bounds how long a handler wrapped in `endpoint` may run before the
+/// request is answered with `503`.
+///
+/// Like `BodyLimit`, registered on the app it sets the default and on a
+/// route it overrides it, so a slow route such as an export can be given
+/// a longer budget with `.with(RequestTimeout::new(...))`.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestTimeout {
+    duration: Duration
+}
+
+impl RequestTimeout {
+    pub fn new(duration: Duration) -> Self {
+        RequestTimeout { duration }
+    }
+
+    /// The deadline from `request_timeout_secs`.
+    pub fn from_config(config: &Config) -> Self {
+        RequestTimeout::new(Duration::from_secs(config.request_timeout_secs))
+    }
+
+    /// The deadline set on the request, or `DEFAULT_REQUEST_TIMEOUT`.
+    pub fn of<State>(req: &Request<State>) -> Self {
+        req.ext::<RequestTimeout>().copied().unwrap_or(RequestTimeout::new(DEFAULT_REQUEST_TIMEOUT))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequestTimeout {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        req.set_ext(*self);
+        Ok(next.run(req).await)
+    }
+}
+
+/// Runs a handler future against `timeout`. A handler that misses the
+/// deadline is dropped before anything is written, so the client only
+/// ever sees the complete `503` problem response. `route` names the
+/// request in the log line.
+pub async fn with_deadline<Fut>(timeout: RequestTimeout, route: String, handler: Fut) -> Result<Response, AppError>
+where
+    Fut: Future<Output = Result<Response, AppError>>,
+{
+    match async_std::future::timeout(timeout.duration, handler).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!("request timed out after {:?}: {}", timeout.duration, route);
+            Err(AppError::Timeout(format!("request did not complete within {:?}", timeout.duration)))
+        }
+    }
+}
@@ -0,0 +1,59 @@
+//! The `Server-Timing` header: how long the server spent on a request, and
+//! how much of that was spent waiting on the database, for the browser's
+//! developer tools to show.
+
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use tide::{Middleware, Next, Request};
+
+async_std::task_local! {
+    /// The time the current task has spent in repository operations since
+    /// `ServerTiming` last took it. Each connection is served on its own
+    /// task, one request at a time, so it's the current request's.
+    static DB_TIME: Cell<Duration> = Cell::new(Duration::ZERO);
+}
+
+/// Adds `elapsed` to the current request's database time. Outside a task
+/// there's no request to add it to, so it's dropped.
+pub fn record_db_time(elapsed: Duration) {
+    let _ = DB_TIME.try_with(|db_time| db_time.set(db_time.get() + elapsed));
+}
+
+fn take_db_time() -> Duration {
+    DB_TIME.try_with(|db_time| db_time.replace(Duration::ZERO)).unwrap_or_default()
+}
+
+/// Times every request, reporting `db`, the time spent in repository
+/// operations, `app`, the rest, and `total` as `Server-Timing`.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerTiming;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for ServerTiming {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        take_db_time();
+        let started = Instant::now();
+        let mut res = next.run(req).await;
+        let total = started.elapsed();
+        let db = take_db_time().min(total);
+        res.insert_header("Server-Timing", header(db, total));
+        Ok(res)
+    }
+}
+
+fn header(db: Duration, total: Duration) -> String {
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    format!("db;dur={:.1}, app;dur={:.1}, total;dur={:.1}", ms(db), ms(total - db), ms(total))
+}
+
+#[test]
+fn durations_are_in_milliseconds() {
+    assert_eq!("db;dur=12.3, app;dur=4.1, total;dur=16.4", header(Duration::from_micros(12_300), Duration::from_micros(16_400)));
+    async_std::task::block_on(async {
+        record_db_time(Duration::from_millis(2));
+        record_db_time(Duration::from_millis(3));
+        assert_eq!(Duration::from_millis(5), take_db_time());
+        assert_eq!(Duration::ZERO, take_db_time());
+    });
+}
@@ -0,0 +1,308 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_std::net::{TcpStream, ToSocketAddrs};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::types::chrono::{DateTime, Utc};
+use tide::http::url::Host;
+use tide::http::{Method, StatusCode, Url, mime};
+
+use crate::Book;
+use crate::config::Config;
+use crate::repository::{BookRepository, ImportCounts};
+use crate::shedding::DbPermits;
+
+/// Used when `webhook_attempts` isn't set.
+pub const DEFAULT_ATTEMPTS: u32 = 3;
+/// The wait before the first retry; each later one waits twice as long.
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+/// How long one delivery may take before it counts as failed.
+pub const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The events a subscription can ask for.
+pub const EVENTS: &[&str] = &["book.created", "book.updated", "book.deleted", "books.imported"];
+/// Carries `sha256=` and the hex HMAC-SHA256 of a delivery's body, keyed
+/// with the subscription's secret, so the subscriber can tell it came
+/// from us.
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+
+/// Tells a downstream service about every book `create_book` inserts, by
+/// POSTing the book's JSON to `url`.
+///
+/// Deliveries run in the background so the client creating the book never
+/// waits on them or sees them fail. A delivery that errors or gets a
+/// non-2xx answer is retried up to `attempts` times in all, then logged
+/// and dropped. `url` is the operator's own, so unlike a subscription's it
+/// may be inside our network.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    url: Url,
+    attempts: u32,
+    backoff: Duration
+}
+
+impl Webhook {
+    pub fn new(url: Url, attempts: u32, backoff: Duration) -> Self {
+        Webhook { url, attempts: attempts.max(1), backoff }
+    }
+
+    /// The webhook at `webhook_url`, if it's set, making up to
+    /// `webhook_attempts` attempts per book.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let url = config.webhook_url.clone()?;
+        Some(Webhook::new(url, config.webhook_attempts, DEFAULT_BACKOFF))
+    }
+
+    /// Starts delivering `book` and returns straight away.
+    pub fn book_created(&self, book: &Book) {
+        let webhook = self.clone();
+        let body = match serde_json::to_string(book) {
+            Ok(body) => body,
+            Err(e) => return tracing::error!("could not serialize book {} for the webhook: {}", book.id, e),
+        };
+        let what = format!("book {}", book.id);
+        async_std::task::spawn(async move {
+            send(&webhook.url, &body, None, webhook.attempts, webhook.backoff, true, &what).await;
+        });
+    }
+}
+
+/// What a subscriber is POSTed when a book changes, with the book, as it
+/// was before a delete, or when an import ran, with what it `imported`
+/// in place of its books.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Event {
+    pub event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub book: Option<Book>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub imported: Option<ImportCounts>,
+    pub occurred_at: DateTime<Utc>
+}
+
+/// Announces book changes to the `webhook_subscription`s asking for them,
+/// each delivery signed in `SIGNATURE_HEADER`.
+///
+/// Like `Webhook`, it works in the background: the subscriptions are read
+/// once per write, holding one of the `DbPermits` like any other use of
+/// the database, and each one is delivered to in a task of its own, so
+/// one slow or failing subscriber holds up neither the request nor the
+/// others. A subscriber gets a write's events one after the other, in
+/// order.
+///
+/// A subscriber may not be at an `is_internal` address, so a subscription
+/// can't be used to reach services that trust anything on our network.
+#[derive(Clone, Copy, Debug)]
+pub struct Deliveries {
+    attempts: u32,
+    backoff: Duration,
+    internal_targets: bool
+}
+
+impl Deliveries {
+    pub fn new(attempts: u32, backoff: Duration) -> Self {
+        Deliveries { attempts: attempts.max(1), backoff, internal_targets: false }
+    }
+
+    /// Lets subscribers be at internal addresses too, as the tests' are.
+    #[cfg(test)]
+    pub fn allowing_internal_targets(self) -> Self {
+        Deliveries { internal_targets: true, ..self }
+    }
+
+    /// Whether a subscriber may be at `url`: any whose host isn't an
+    /// `is_internal` address or a name for loopback. A name is only
+    /// resolved when it's delivered to, which checks again.
+    pub fn allows(&self, url: &Url) -> bool {
+        self.internal_targets || match url.host() {
+            Some(Host::Ipv4(ip)) => !is_internal(ip.into()),
+            Some(Host::Ipv6(ip)) => !is_internal(ip.into()),
+            Some(Host::Domain(domain)) => {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                domain != "localhost" && !domain.ends_with(".localhost")
+            }
+            None => false,
+        }
+    }
+
+    /// Makes up to `webhook_attempts` attempts per delivery.
+    pub fn from_config(config: &Config) -> Self {
+        Deliveries::new(config.webhook_attempts, DEFAULT_BACKOFF)
+    }
+
+    /// Starts announcing every change a write made, each an event of
+    /// `EVENTS` about a book, and returns straight away.
+    pub fn books_changed<'a>(self, repo: Arc<dyn BookRepository>, permits: Arc<DbPermits>, changes: impl IntoIterator<Item = (&'static str, &'a Book)>) {
+        let occurred_at = Utc::now();
+        let mut deliveries = Vec::new();
+        for (event, book) in changes {
+            match serde_json::to_string(&Event { event: event.to_owned(), book: Some(book.clone()), imported: None, occurred_at }) {
+                Ok(body) => deliveries.push((event, format!("{} of book {}", event, book.id), body)),
+                Err(e) => tracing::error!("could not serialize {} of book {}: {}", event, book.id, e),
+            }
+        }
+        self.announce(repo, permits, deliveries);
+    }
+
+    /// Starts announcing a `books.imported` event with what an import did,
+    /// and returns straight away.
+    pub fn books_imported(self, repo: Arc<dyn BookRepository>, permits: Arc<DbPermits>, counts: ImportCounts) {
+        let event = "books.imported";
+        match serde_json::to_string(&Event { event: event.to_owned(), book: None, imported: Some(counts), occurred_at: Utc::now() }) {
+            Ok(body) => self.announce(repo, permits, vec![(event, String::from("an import"), body)]),
+            Err(e) => tracing::error!("could not serialize an import's {}: {}", event, e),
+        }
+    }
+
+    /// Looks the subscriptions up once, holding a database permit while
+    /// it does, and delivers each of `deliveries`, an event, what it's
+    /// about for the log and its body, in order to the subscriptions that
+    /// asked for its event.
+    fn announce(self, repo: Arc<dyn BookRepository>, permits: Arc<DbPermits>, deliveries: Vec<(&'static str, String, String)>) {
+        if deliveries.is_empty() {
+            return;
+        }
+        async_std::task::spawn(async move {
+            let permit = permits.acquire_queued().await;
+            let subscriptions = repo.list_webhooks().await;
+            drop(permit);
+            let subscriptions = match subscriptions {
+                Ok(subscriptions) => subscriptions,
+                Err(e) => return tracing::error!("could not read the webhook subscriptions for {}: {}", deliveries[0].1, e),
+            };
+            let deliveries = Arc::new(deliveries);
+            for subscription in subscriptions {
+                if !deliveries.iter().any(|(event, _, _)| subscription.events.iter().any(|wanted| wanted == event)) {
+                    continue;
+                }
+                let url = match Url::parse(&subscription.url) {
+                    Ok(url) => url,
+                    Err(e) => {
+                        tracing::error!("skipping webhook {} with URL {:?}: {}", subscription.id, subscription.url, e);
+                        continue;
+                    }
+                };
+                let deliveries = deliveries.clone();
+                async_std::task::spawn(async move {
+                    for (event, what, body) in deliveries.iter() {
+                        if subscription.events.iter().any(|wanted| wanted == event) {
+                            let signature = sign(&subscription.secret, body);
+                            send(&url, body, Some(&signature), self.attempts, self.backoff, self.internal_targets, what).await;
+                        }
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// Whether `ip` is inside our own network: loopback, private (RFC 1918 or
+/// IPv6 unique local), link-local, which takes in the cloud metadata
+/// service at 169.254.169.254, or unspecified.
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.octets()[0] == 0,
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(ip.into()),
+            None => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local() || ip.is_unspecified(),
+        },
+    }
+}
+
+/// The `SIGNATURE_HEADER` value for `body` sent with `secret`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+/// Delivers `body` to `url`, trying up to `attempts` times with a doubling
+/// `backoff` in between, and logs the outcome of every failed attempt.
+/// `internal_targets` is whether `url` may be inside our network. `what`
+/// names the delivery in the log.
+async fn send(url: &Url, body: &str, signature: Option<&str>, attempts: u32, backoff: Duration, internal_targets: bool, what: &str) {
+    let mut backoff = backoff;
+    for attempt in 1..=attempts {
+        let failure = match async_std::future::timeout(DELIVERY_TIMEOUT, deliver(url, body, signature, internal_targets)).await {
+            Ok(Ok(status)) if status.is_success() => return,
+            Ok(Ok(status)) => format!("answered {}", status),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => format!("timed out after {:?}", DELIVERY_TIMEOUT),
+        };
+        if attempt == attempts {
+            tracing::error!("gave up notifying {} of {} after {} attempts: {}", url, what, attempt, failure);
+        } else {
+            tracing::warn!("notifying {} of {} failed, retrying in {:?}: {}", url, what, backoff, failure);
+            async_std::task::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+}
+
+/// POSTs `body` to `url` over a fresh connection. Unless
+/// `internal_targets`, a host that resolves to any `is_internal` address
+/// is refused, and the connection goes to the addresses checked rather
+/// than ones looked up again, which could have changed in between.
+async fn deliver(url: &Url, body: &str, signature: Option<&str>, internal_targets: bool) -> tide::http::Result<StatusCode> {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match url.host() {
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Domain(domain)) => (domain, port).to_socket_addrs().await?.collect(),
+        None => Vec::new(),
+    };
+    if let Some(addr) = addrs.iter().find(|addr| !internal_targets && is_internal(addr.ip())) {
+        return Err(tide::http::Error::from_str(StatusCode::Forbidden, format!("{} is at internal address {}", host, addr.ip())));
+    }
+    let stream = TcpStream::connect(&addrs[..]).await?;
+    let mut req = tide::http::Request::new(Method::Post, url.clone());
+    req.set_body(body);
+    req.set_content_type(mime::JSON);
+    if let Some(signature) = signature {
+        req.insert_header(SIGNATURE_HEADER, signature);
+    }
+    let res = match url.scheme() {
+        "https" => async_h1::connect(async_native_tls::connect(host, stream).await?, req).await?,
+        _ => async_h1::connect(stream, req).await?,
+    };
+    Ok(res.status())
+}
+
+#[test]
+fn signatures_are_hmac_sha256_of_the_body() {
+    // RFC 4231, test case 2.
+    assert_eq!(
+        "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        sign("Jefe", "what do ya want for nothing?"));
+}
+
+#[test]
+fn internal_addresses_are_told_apart() {
+    for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "::ffff:10.0.0.1"] {
+        assert!(is_internal(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in ["93.184.216.34", "172.32.0.1", "8.8.8.8", "2606:2800:220:1:248:1893:25c8:1946", "::ffff:8.8.8.8"] {
+        assert!(!is_internal(ip.parse().unwrap()), "{}", ip);
+    }
+}
+
+#[async_std::test]
+async fn subscribers_at_internal_addresses_are_refused() {
+    let deliveries = Deliveries::new(1, Duration::ZERO);
+    for url in ["http://127.0.0.1:8080/", "http://10.0.0.1/", "http://169.254.169.254/latest/meta-data/", "http://[::1]/", "http://localhost/", "http://api.LOCALHOST./"] {
+        let url = Url::parse(url).unwrap();
+        assert!(!deliveries.allows(&url), "{}", url);
+        assert!(deliveries.allowing_internal_targets().allows(&url), "{}", url);
+    }
+    // Checked again once resolved, so a name can't lead anywhere its URL
+    // couldn't.
+    for url in ["http://127.0.0.1:8080/", "http://169.254.169.254/latest/meta-data/", "http://[::1]/", "http://localhost/"] {
+        let e = deliver(&Url::parse(url).unwrap(), "{}", None, false).await.unwrap_err();
+        assert_eq!(StatusCode::Forbidden, e.status(), "{}", url);
+    }
+    assert!(deliveries.allows(&Url::parse("https://hooks.example.com/books").unwrap()));
+}
@@ -0,0 +1,149 @@
+//! `/ws/books`: the book changes of `BookEvents` over a WebSocket, for
+//! clients behind proxies that break Server-Sent Events.
+//!
+//! `tide-websockets` speaks the protocol. Each change is sent as
+//! `{"id": ..., "event": "book.updated", "book": {...}}`, and an import as
+//! `{"id": ..., "event": "books.imported", "imported": {...}}`. A client
+//! can narrow the feed to one author's books, and the imports, by sending
+//! `{"subscribe": {"author": "..."}}`, or widen it again with
+//! `{"subscribe": {}}`.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use async_std::channel::{self, Receiver};
+use async_std::stream::StreamExt;
+use futures_lite::future;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use tide::{Request, Response};
+use tide_websockets::tungstenite::protocol::CloseFrame;
+use tide_websockets::tungstenite::protocol::frame::coding::CloseCode;
+use tide_websockets::{Error, Message, WebSocketConnection};
+
+use crate::State;
+use crate::error::AppError;
+use crate::events::{Batch, Event, Subject};
+
+/// What `{"subscribe": ...}` narrows the feed to.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Filter {
+    author: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Subscribe {
+    subscribe: Filter
+}
+
+impl Filter {
+    /// An import may have changed any author's books, so every client is
+    /// told of it.
+    fn matches(&self, event: &Event) -> bool {
+        self.author.as_ref().is_none_or(|author| event.book().is_none_or(|book| book.author.as_ref() == Some(author)))
+    }
+}
+
+pub fn book_socket_doc() -> Value {
+    json!({
+        "operationId": "book_socket",
+        "description": "A WebSocket upgrade. Each book change arrives as a text frame `{\"id\", \"event\", \"book\"}`, and each import as `{\"id\", \"event\": \"books.imported\", \"imported\"}` with its counts; send `{\"subscribe\": {\"author\": \"...\"}}` to only get one author's books. The server pings every `EVENTS_KEEP_ALIVE_SECS` and drops clients that stop answering.",
+        "responses": {
+            "101": {"description": "Switched to the WebSocket protocol"},
+            "400": {
+                "description": "Not a WebSocket upgrade",
+                "content": {"application/problem+json": {"schema": {"$ref": "#/components/schemas/Problem"}}}
+            }
+        }
+    })
+}
+
+/// Answers the requests to `/ws/books` that aren't upgrades; the
+/// `WebSocket` in front of it takes the others.
+pub async fn not_an_upgrade(_: Request<State>) -> Result<Response, AppError> {
+    Err(AppError::BadRequest(String::from("expected a version 13 WebSocket upgrade")))
+}
+
+/// Follows `BookEvents` over an upgraded connection until the client
+/// leaves, goes quiet for two keep-alives or falls too far behind.
+pub async fn book_socket(req: Request<State>, connection: WebSocketConnection) -> tide::Result<()> {
+    let events = req.state().events.clone();
+    let (_, subscription) = events.subscribe(None);
+    if let Err(e) = follow(connection, subscription, events.keep_alive()).await {
+        tracing::debug!("book socket closed: {}", e);
+    }
+    Ok(())
+}
+
+/// What woke `follow` up.
+enum Wake {
+    Events(Result<Batch, channel::RecvError>),
+    Message(Option<Result<Message, Error>>),
+    KeepAlive
+}
+
+/// Writes the events that pass the client's filter, answers its messages
+/// and pings it when it's been quiet for `keep_alive`. Pings and closes
+/// from the client are answered by the connection itself.
+async fn follow(mut connection: WebSocketConnection, events: Receiver<Batch>, keep_alive: Duration) -> Result<(), Error> {
+    let mut filter = Filter::default();
+    let mut last_heard = Instant::now();
+    let mut last_ping = Instant::now();
+    loop {
+        let wait = keep_alive.saturating_sub(last_ping.elapsed());
+        let wake = future::or(
+            async { Wake::Events(events.recv().await) },
+            future::or(
+                async { Wake::Message(connection.next().await) },
+                async {
+                    async_std::task::sleep(wait).await;
+                    Wake::KeepAlive
+                }));
+        match wake.await {
+            Wake::Events(Ok(batch)) => {
+                for event in batch.iter().filter(|event| filter.matches(event)) {
+                    let message = match &event.subject {
+                        Subject::Book(book) => json!({"id": event.id, "event": event.event, "book": book}),
+                        Subject::Import(counts) => json!({"id": event.id, "event": event.event, "imported": counts}),
+                    };
+                    connection.send_string(message.to_string()).await?;
+                }
+            }
+            Wake::Events(Err(_)) => return Err(Error::Io(io::Error::other("fell too far behind"))),
+            Wake::Message(Some(Ok(message))) => {
+                last_heard = Instant::now();
+                match message {
+                    Message::Text(text) => {
+                        let reply = match serde_json::from_str::<Subscribe>(&text) {
+                            Ok(Subscribe { subscribe }) => {
+                                let reply = json!({"subscribed": {"author": subscribe.author}});
+                                filter = subscribe;
+                                reply
+                            }
+                            Err(e) => json!({"error": format!("expected {{\"subscribe\": {{\"author\": ...}}}}: {}", e)}),
+                        };
+                        connection.send_string(reply.to_string()).await?;
+                    }
+                    Message::Binary(_) => {
+                        let close = CloseFrame { code: CloseCode::Unsupported, reason: "the feed only reads text".into() };
+                        let _ = connection.send(Message::Close(Some(close))).await;
+                        return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidData, "sent a binary message")));
+                    }
+                    // The connection sends the reply, and ends once it's out.
+                    Message::Close(_) | Message::Ping(_) | Message::Pong(_) => {}
+                }
+            }
+            Wake::Message(Some(Err(Error::ConnectionClosed))) | Wake::Message(None) => return Ok(()),
+            Wake::Message(Some(Err(e))) => return Err(e),
+            Wake::KeepAlive if last_heard.elapsed() > keep_alive * 2 => {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::TimedOut, "stopped answering pings")));
+            }
+            Wake::KeepAlive => {
+                last_ping = Instant::now();
+                connection.send(Message::Ping(Vec::new())).await?;
+            }
+        }
+    }
+}